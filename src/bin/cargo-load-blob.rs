@@ -1,68 +1,2440 @@
-use blob_loader::blob_info::BlobInfoFile;
-use probe_rs::{flashing::DownloadOptions, Permissions, Session};
+use blob_loader::blob_image::{self, BlobImage};
+use blob_loader::blob_info::{
+    self, BlobInfo, BlobInfoFile, Checksum, LayoutInfo, ModifiedFile, ProbeInfo, SectorMap,
+    VERIFY_FAIL_MARKER, VERIFY_OK_MARKER,
+};
+use blob_loader::compress::Codec;
+use blob_loader::dfu::DfuDevice;
+use blob_loader::embed_config;
+use blob_loader::flash::{
+    self, erase_sectors, flash_sectors, format_ranges, kib_per_sec, sector_maps, EraseRefused,
+    FlashOptions, FlashTimings, Flasher, MissingFile, Progress, ReadKind, Verified,
+};
+use blob_loader::gdb_remote::GdbRemote;
+use blob_loader::serial_boot::SerialBootloader;
+use blob_loader::signing;
+use clap::{ArgAction, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use ed25519_dalek::VerifyingKey;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use object::{Object, ObjectSection, ObjectSymbol};
+use probe_rs::config::Registry;
+use probe_rs::flashing::{ProgressEvent, ProgressOperation};
+use probe_rs::probe::list::Lister;
+use probe_rs::probe::{DebugProbeSelector, Probe, WireProtocol};
+use probe_rs::rtt::Rtt;
+use probe_rs::{Permissions, Session};
+use probe_rs_target::{ApAddress, CoreAccessOptions};
+use std::fmt;
 use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{IsTerminal, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, error, info, warn, Level};
+use tracing_subscriber::EnvFilter;
 
 type DynResult<T> = Result<T, Box<dyn std::error::Error>>;
 
-pub fn load_blob(blob_info: &BlobInfoFile) -> DynResult<()> {
-    let mut session = Session::auto_attach(&blob_info.probe.chip, Permissions::default())?;
-    let mut loader = session.target().flash_loader();
+/// Environment variable selecting the probe environment, like --env
+const ENVIRONMENT_VAR: &str = "BLOB_LOADER_ENV";
+
+/// Exit codes, listed by --print-exit-codes. Clap uses 2 for command line
+/// errors.
+#[derive(Debug, Clone, Copy)]
+enum Failure {
+    Other = 1,
+    ProbeNotFound = 3,
+    ChipMismatch = 4,
+    FileMissing = 5,
+    Verify = 6,
+    Flash = 7,
+    BlobInfo = 8,
+    ProbeBusy = 9,
+    Signature = 10,
+    Stale = 11,
+}
+
+const EXIT_CODES: [(u8, &str); 11] = [
+    (Failure::Other as u8, "Other errors"),
+    (2, "Invalid command line"),
+    (
+        Failure::ProbeNotFound as u8,
+        "No debug probe found, or several and none selected",
+    ),
+    (
+        Failure::ChipMismatch as u8,
+        "Unknown chip, or it has no such core",
+    ),
+    (
+        Failure::FileMissing as u8,
+        "Blob info file, blob file or other input file missing",
+    ),
+    (
+        Failure::Verify as u8,
+        "Blobs in flash don't match their checksums",
+    ),
+    (Failure::Flash as u8, "Erasing or programming flash failed"),
+    (Failure::BlobInfo as u8, "Blob info file can't be parsed"),
+    (
+        Failure::ProbeBusy as u8,
+        "Debug probe in use by another instance",
+    ),
+    (
+        Failure::Signature as u8,
+        "Blob info file not signed, or the signature doesn't match",
+    ),
+    (
+        Failure::Stale as u8,
+        "Blob info file from another build than the ELF file or generated files, or blob files changed since the build",
+    ),
+];
+
+impl From<Failure> for ExitCode {
+    fn from(failure: Failure) -> ExitCode {
+        ExitCode::from(failure as u8)
+    }
+}
+
+/// Error tagged with the exit code to use for it
+#[derive(Debug)]
+struct Failed(Failure, Box<dyn std::error::Error>);
+
+impl fmt::Display for Failed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.1.fmt(f)
+    }
+}
+
+impl std::error::Error for Failed {}
+
+fn failed<E: Into<Box<dyn std::error::Error>>>(
+    failure: Failure,
+    e: E,
+) -> Box<dyn std::error::Error> {
+    Box::new(Failed(failure, e.into()))
+}
+
+/// Failure class of an error, default unless it has been tagged with one
+fn failure_of(e: &(dyn std::error::Error + 'static), default: Failure) -> Failure {
+    if e.is::<MissingFile>() {
+        return Failure::FileMissing;
+    }
+    if e.is::<EraseRefused>() {
+        return Failure::Other;
+    }
+    // Image building reports it inside an io::Error
+    let inner = e
+        .downcast_ref::<std::io::Error>()
+        .and_then(|e| e.get_ref())
+        .map(|e| e as &(dyn std::error::Error + 'static));
+    if e.is::<ModifiedFile>() || inner.is_some_and(|e| e.is::<ModifiedFile>()) {
+        return Failure::Stale;
+    }
+    e.downcast_ref::<Failed>().map(|f| f.0).unwrap_or(default)
+}
+
+/// Description of a command, its arguments and its subcommands
+fn command_json(cmd: &clap::Command) -> serde_json::Value {
+    let arguments: Vec<_> = cmd
+        .get_arguments()
+        .map(|arg| {
+            serde_json::json!({
+                "id": arg.get_id().as_str(),
+                "long": arg.get_long(),
+                "short": arg.get_short().map(String::from),
+                "help": arg.get_help().map(|h| h.to_string()),
+                "positional": arg.is_positional(),
+                "required": arg.is_required_set(),
+                "global": arg.is_global_set(),
+                "takes_value": arg.get_action().takes_values(),
+                "repeatable": matches!(arg.get_action(), ArgAction::Append | ArgAction::Count),
+                "default": arg
+                    .get_default_values()
+                    .iter()
+                    .map(|v| v.to_string_lossy())
+                    .collect::<Vec<_>>(),
+                "possible_values": arg
+                    .get_possible_values()
+                    .iter()
+                    .map(|v| v.get_name().to_string())
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "name": cmd.get_name(),
+        "about": cmd.get_about().map(|a| a.to_string()),
+        "arguments": arguments,
+        "subcommands": cmd.get_subcommands().map(command_json).collect::<Vec<_>>(),
+    })
+}
+
+/// Prints the commands, their arguments and the exit codes as JSON
+fn print_help_json() {
+    let mut cmd = Cli::command();
+    // Makes the global arguments show up in the subcommands
+    cmd.build();
+    let mut json = command_json(&cmd);
+    json["version"] = serde_json::json!(cmd.get_version());
+    json["exit_codes"] = EXIT_CODES
+        .iter()
+        .map(|(code, meaning)| serde_json::json!({"code": code, "meaning": meaning}))
+        .collect();
+    println!("{}", serde_json::to_string_pretty(&json).unwrap());
+}
+
+/// Summary of a run, written by --report
+#[derive(Default)]
+struct Report {
+    blob_info: Vec<String>,
+    /// Timings and written blobs of flashing
+    flash: Option<serde_json::Value>,
+    /// Result of the verification by the firmware, reported over RTT
+    rtt_verified: Option<bool>,
+    /// Verified blob info files and whether their blobs matched
+    verified: Vec<(String, bool)>,
+    /// Tags and notes of the blobs that have any
+    notes: Vec<serde_json::Value>,
+}
+
+impl Report {
+    fn write(&self, path: &Path, result: Result<(), Failure>) -> DynResult<()> {
+        let (code, meaning) = match result {
+            Ok(()) => (0, "Success"),
+            Err(failure) => EXIT_CODES
+                .iter()
+                .find(|(code, _)| *code == failure as u8)
+                .copied()
+                .unwrap_or((failure as u8, "")),
+        };
+        let json = serde_json::json!({
+            "blob_info": self.blob_info,
+            "exit_code": code,
+            "result": meaning,
+            "flash": self.flash,
+            "rtt_verified": self.rtt_verified,
+            "verified": self
+                .verified
+                .iter()
+                .map(|(path, matches)| serde_json::json!({"blob_info": path, "matches": matches}))
+                .collect::<Vec<_>>(),
+            "notes": self.notes,
+        });
+        std::fs::write(path, serde_json::to_string_pretty(&json)?)?;
+        Ok(())
+    }
+}
+
+fn print_exit_codes() {
+    println!("{:>4}  Meaning", "Code");
+    println!("{:>4}  Success", 0);
+    for (code, meaning) in EXIT_CODES {
+        println!("{:>4}  {}", code, meaning);
+    }
+}
+
+/// Flash the blobs described by a blob info file
+#[derive(Parser)]
+#[command(name = "cargo-load-blob", version)]
+struct Cli {
+    /// Target chip, overrides the chip in the blob info file
+    #[arg(long, global = true)]
+    chip: Option<String>,
+    /// Target description file for chips not known to probe-rs
+    #[arg(long, global = true)]
+    chip_description_path: Option<PathBuf>,
+    /// Probe to use, as VID:PID or VID:PID:SERIAL. Overrides the selector in
+    /// the blob info file.
+    #[arg(long, global = true)]
+    probe: Option<DebugProbeSelector>,
+    /// Protocol clock speed in kHz
+    #[arg(long, global = true, alias = "speed")]
+    speed_khz: Option<u32>,
+    /// Protocol used to connect to the target, swd or jtag
+    #[arg(long, global = true)]
+    protocol: Option<WireProtocol>,
+    /// Hold nRESET asserted while attaching, for firmware that disables the
+    /// debug port
+    #[arg(long, global = true)]
+    connect_under_reset: bool,
+    /// Pulse nRESET before attaching
+    #[arg(long, global = true)]
+    reset_before_attach: bool,
+    /// Don't ask for confirmation before erasing more than the blob region
+    #[arg(short, long, global = true)]
+    yes: bool,
+    /// Core to access the flash through, as an index in the target
+    /// description. Overrides the core in the blob info file.
+    #[arg(long, global = true)]
+    core: Option<usize>,
+    /// Access port of the core, for parts where the target description
+    /// doesn't match. Overrides the access port in the blob info file.
+    #[arg(long, global = true)]
+    ap: Option<u8>,
+    /// Blob info file written by prepare_blob, by default BlobInfo.toml or
+    /// BlobInfo.json in the target directory reported by cargo metadata.
+    /// Read from stdin if "-". Give it several times to flash or verify the
+    /// blobs of several packages in one session.
+    #[arg(long, global = true)]
+    blob_info: Vec<PathBuf>,
+    /// Directory holding the blob files, instead of the one they were
+    /// built in. The file names are relative to it.
+    #[arg(long, global = true)]
+    asset_root: Option<PathBuf>,
+    /// Profile of the Embed.toml files of cargo-embed to take probe
+    /// settings from. These override the blob info file.
+    #[arg(long, global = true, default_value = "default")]
+    embed_profile: String,
+    /// Don't read probe settings from Embed.toml files
+    #[arg(long, global = true)]
+    no_embed_toml: bool,
+    /// Environment in the blob info file whose probe settings to use, by
+    /// default the one named by BLOB_LOADER_ENV if set
+    #[arg(long, global = true)]
+    env: Option<String>,
+    /// Public key to check the signatures of the blob info files with, as
+    /// 64 hex digits or a file holding them
+    #[arg(long, global = true)]
+    public_key: Option<String>,
+    /// Fail unless the blob info files are signed with the public key
+    #[arg(long, global = true, requires = "public_key")]
+    require_signature: bool,
+    /// Package whose blobs to load
+    #[arg(short, long, global = true)]
+    package: Option<String>,
+    /// Profile the package was built with
+    #[arg(long, global = true, conflicts_with = "release")]
+    profile: Option<String>,
+    /// Same as --profile release
+    #[arg(long, global = true)]
+    release: bool,
+    /// More output, repeat for even more
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+    /// Less output, repeat for only errors
+    #[arg(short, long, global = true, action = ArgAction::Count, conflicts_with = "verbose")]
+    quiet: u8,
+    /// Fail at once if another instance is using the probe, instead of
+    /// waiting for it to finish
+    #[arg(long, global = true, conflicts_with = "wait_timeout")]
+    no_wait: bool,
+    /// Seconds to wait for another instance using the probe, by default
+    /// without limit
+    #[arg(long, global = true)]
+    wait_timeout: Option<u64>,
+    /// Write a JSON summary of the result to this file
+    #[arg(long, global = true)]
+    report: Option<PathBuf>,
+    /// Print the exit codes and their meaning
+    #[arg(long)]
+    print_exit_codes: bool,
+    /// Print the commands and their arguments as JSON, for tools wrapping
+    /// this one
+    #[arg(long)]
+    help_json: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Write the blobs to flash (default)
+    Flash(FlashArgs),
+    /// Compare the blobs in flash with the checksums in the blob info file
+    Verify,
+    /// Read the blobs from flash and write them to files
+    Dump {
+        /// Directory for the files, which are named after the blobs
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+    /// Erase the sectors of the blob area, leaving the application
+    Erase {
+        /// Only erase the sectors of these blobs
+        #[arg(long)]
+        only: Vec<String>,
+        /// Erase the sectors of all blobs and the blob table
+        #[arg(long, conflicts_with = "only")]
+        all: bool,
+    },
+    /// Watch the blob files and flash those that change
+    Watch {
+        /// Time between checks of the files, in milliseconds
+        #[arg(long, default_value_t = 500)]
+        interval: u64,
+        /// Reset the target and let it run after each flashing
+        #[arg(long)]
+        reset: bool,
+    },
+    /// Show the blobs in the blob info file
+    Info,
+    /// Write the blobs to a single image file
+    Export(ExportArgs),
+    /// List connected debug probes
+    ListProbes,
+    /// List chips known to probe-rs
+    ListChips {
+        /// Only show chips starting with this name, where x matches any
+        /// character
+        name: Option<String>,
+    },
+    /// Print a completion script for the shell
+    Completions {
+        /// Shell to complete in
+        shell: Shell,
+    },
+}
+
+#[derive(Args)]
+struct FlashArgs {
+    /// Flash all blobs, also those already present in flash
+    #[arg(long)]
+    force: bool,
+    /// Print what would be flashed without attaching to the target
+    #[arg(long)]
+    dry_run: bool,
+    /// Reset the target and let it run after flashing
+    #[arg(long, conflicts_with = "halt")]
+    reset: bool,
+    /// Reset the target and halt it after flashing
+    #[arg(long)]
+    halt: bool,
+    /// Reset the target after flashing and wait for the firmware to report
+    /// the result of verify_all over RTT
+    #[arg(long, conflicts_with = "halt")]
+    rtt: bool,
+    /// Seconds to wait for the report over RTT
+    #[arg(long, default_value_t = 10)]
+    rtt_timeout: u64,
+    /// ELF file with the defmt table, for firmware logging with defmt
+    #[arg(long, requires = "rtt")]
+    defmt: Option<PathBuf>,
+    /// Erase the whole chip before flashing. This also erases everything
+    /// that isn't a blob, and implies --force.
+    #[arg(long)]
+    chip_erase: bool,
+    /// Don't erase sectors before writing, the flash has to be erased already
+    #[arg(long, overrides_with = "no_skip_erase", conflicts_with = "chip_erase")]
+    skip_erase: bool,
+    /// Erase sectors before writing (default)
+    #[arg(long, overrides_with = "skip_erase")]
+    no_skip_erase: bool,
+    /// Restore the parts of erased sectors that aren't written, e.g. a
+    /// settings area next to the blobs
+    #[arg(long, alias = "keep-unwritten-bytes")]
+    restore_unwritten: bool,
+    /// Program the parts of erased sectors that aren't written to this
+    /// value, so that no data from earlier images remains
+    #[arg(
+        long,
+        value_parser = parse_int::<u8>,
+        conflicts_with_all = ["restore_unwritten", "skip_erase"]
+    )]
+    fill: Option<u8>,
+    /// Flash algorithm to use where the target has several for a region,
+    /// e.g. for external flash. Algorithms not known to probe-rs, such as
+    /// ones converted from an FLM file with target-gen, can be added with
+    /// --chip-description-path.
+    #[arg(long)]
+    flash_algorithm: Vec<String>,
+    /// Read back and compare the written data after flashing
+    #[arg(long)]
+    verify: bool,
+    /// Read back every blob to find those that changed, instead of trusting
+    /// the blob table in flash
+    #[arg(long)]
+    full_check: bool,
+    /// Reattach and retry a blob up to this many times after a probe error.
+    /// Each blob is then flashed separately.
+    #[arg(long, default_value_t = 0, conflicts_with_all = ["chip_erase", "elf"])]
+    retries: u32,
+    /// Application ELF file to flash together with the blobs, in the same
+    /// probe session
+    #[arg(long)]
+    elf: Option<PathBuf>,
+    /// Flash even if the blob info file is from another build than the ELF
+    /// file, or than the files generated with it, or blob files don't match
+    /// their checksums. The latter still fails with --require-signature.
+    #[arg(long)]
+    allow_stale: bool,
+    /// Print the time spent reading, erasing, programming and verifying
+    #[arg(long)]
+    timings: bool,
+    /// Write the timings as JSON to this file
+    #[arg(long)]
+    timings_json: Option<PathBuf>,
+    /// How to write the blobs. All blobs are then written, and the options
+    /// for erasing, verifying and the application need a debug probe.
+    #[arg(long, value_enum, default_value_t = Via::Probe)]
+    via: Via,
+    /// DFU device to use, as VID:PID
+    #[arg(long, value_parser = parse_vid_pid)]
+    dfu_device: Option<(u16, u16)>,
+    /// Serial port of the bootloader
+    #[arg(long, required_if_eq("via", "serial"))]
+    port: Option<String>,
+    /// Baud rate of the serial port
+    #[arg(long, default_value_t = 115200)]
+    baud: u32,
+    /// Flash through a GDB server, e.g. on a machine in the lab, as
+    /// host:port
+    #[arg(long, conflicts_with = "via")]
+    remote: Option<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+enum Via {
+    /// Debug probe
+    Probe,
+    /// USB DFU with the DfuSe extensions, e.g. the STM32 ROM bootloader
+    Dfu,
+    /// STM32 ROM bootloader on a UART
+    Serial,
+}
+
+/// Flash arguments as if flash was given without any, used when there is
+/// no subcommand. Parsed by clap so that the fields get their defaults.
+fn default_flash_args() -> FlashArgs {
+    let matches = FlashArgs::augment_args(clap::Command::new("flash")).get_matches_from(["flash"]);
+    FlashArgs::from_arg_matches(&matches).expect("Flash arguments have defaults")
+}
+
+/// Flash arguments that only work with a debug probe, and are set
+fn probe_only_flags(args: &FlashArgs) -> Vec<&'static str> {
+    [
+        (args.force, "--force"),
+        (args.chip_erase, "--chip-erase"),
+        (args.skip_erase, "--skip-erase"),
+        (args.restore_unwritten, "--restore-unwritten"),
+        (args.fill.is_some(), "--fill"),
+        (args.verify, "--verify"),
+        (args.full_check, "--full-check"),
+        (args.retries > 0, "--retries"),
+        (args.elf.is_some(), "--elf"),
+        (args.rtt, "--rtt"),
+        (!args.flash_algorithm.is_empty(), "--flash-algorithm"),
+        // A GDB server can halt the target after a reset
+        (args.halt && args.remote.is_none(), "--halt"),
+    ]
+    .into_iter()
+    .filter_map(|(set, flag)| set.then_some(flag))
+    .collect()
+}
+
+/// Exits like clap does for conflicting arguments if flash arguments that
+/// need a debug probe are given with --via or --remote
+fn check_flash_args(cli: &Cli) {
+    let Some(Command::Flash(args)) = &cli.command else {
+        return;
+    };
+    let backend = match (args.via, &args.remote) {
+        (_, Some(_)) => "--remote",
+        (Via::Dfu, None) => "--via dfu",
+        (Via::Serial, None) => "--via serial",
+        (Via::Probe, None) => return,
+    };
+    let flags = probe_only_flags(args);
+    if !flags.is_empty() {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                format!("{} can't be used with {}", flags.join(", "), backend),
+            )
+            .exit();
+    }
+}
+
+fn parse_vid_pid(s: &str) -> Result<(u16, u16), String> {
+    let (vid, pid) = s.split_once(':').ok_or("Expected VID:PID")?;
+    let vid = u16::from_str_radix(vid, 16).map_err(|e| e.to_string())?;
+    let pid = u16::from_str_radix(pid, 16).map_err(|e| e.to_string())?;
+    Ok((vid, pid))
+}
+
+#[derive(Args)]
+struct ExportArgs {
+    /// Format of the image
+    #[arg(long, value_enum, default_value_t = ExportFormat::Bin)]
+    format: ExportFormat,
+    /// Image file to write. Blobs in external flash are written to a
+    /// second image, with "-external" added to the file name.
+    #[arg(short, long)]
+    output: PathBuf,
+    /// Value of bytes between blobs
+    #[arg(long, default_value = "0xff", value_parser = parse_int::<u8>)]
+    fill: u8,
+    /// UF2 family ID of the target, e.g. 0xe48bff56 for RP2040
+    #[arg(long, value_parser = parse_int::<u32>)]
+    family_id: Option<u32>,
+    /// Export even if blob files don't match their checksums
+    #[arg(long)]
+    allow_stale: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+enum ExportFormat {
+    /// Raw binary starting at the lowest blob address
+    Bin,
+    /// UF2 for USB mass storage bootloaders. Those usually erase whole
+    /// sectors, so the blob region should be sector aligned.
+    Uf2,
+}
+
+/// Parses a decimal or 0x prefixed hexadecimal number
+fn parse_int<T: TryFrom<u64>>(s: &str) -> Result<T, String> {
+    let value = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| e.to_string())?;
+    T::try_from(value).map_err(|_| format!("{} is out of range", s))
+}
+
+/// Selector of the probe to use, from the command line, the blob info file
+/// or the only connected probe
+fn probe_selector(cli: &Cli, probe: &ProbeInfo) -> DynResult<DebugProbeSelector> {
+    match (&cli.probe, &probe.selector) {
+        (Some(selector), _) => return Ok(selector.clone()),
+        (None, Some(selector)) => {
+            return Ok(selector
+                .parse::<DebugProbeSelector>()
+                .map_err(|e| format!("Invalid probe selector '{}': {}", selector, e))?)
+        }
+        (None, None) => {}
+    }
+    let probes = Lister::new().list_all();
+    match probes.as_slice() {
+        [] => Err("No debug probe found".into()),
+        [info] => Ok(DebugProbeSelector::from(info)),
+        _ => {
+            let mut msg = "Several debug probes found, select one with --probe:".to_string();
+            for info in &probes {
+                msg += &format!(
+                    "\n  {} ({})",
+                    DebugProbeSelector::from(info),
+                    info.identifier
+                );
+            }
+            Err(msg.into())
+        }
+    }
+}
+
+/// Opens the probe selected on the command line or in the blob info file.
+/// Without a selector there must be exactly one probe connected.
+fn open_probe(cli: &Cli, probe: &ProbeInfo) -> DynResult<Probe> {
+    Ok(Lister::new().open(probe_selector(cli, probe)?)?)
+}
+
+/// Advisory lock on a probe, held while using it so that other instances,
+/// e.g. a parallel CI job or a forgotten watch, don't use it at the same
+/// time
+struct ProbeLock {
+    _file: File,
+}
+
+/// Locks the probe, waiting for other instances to release it unless
+/// --no-wait is given
+fn lock_probe(cli: &Cli, selector: &DebugProbeSelector) -> DynResult<ProbeLock> {
+    let dir = std::env::temp_dir().join("cargo-load-blob");
+    std::fs::create_dir_all(&dir)?;
+    let name: String = selector
+        .to_string()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let path = dir.join(format!("{}.lock", name));
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(|e| format!("Failed to open '{}': {}", path.display(), e))?;
+    let started = Instant::now();
+    let mut waiting = false;
+    loop {
+        match file.try_lock() {
+            Ok(()) => break,
+            Err(std::fs::TryLockError::WouldBlock) => {}
+            Err(std::fs::TryLockError::Error(e)) => {
+                return Err(format!("Failed to lock '{}': {}", path.display(), e).into())
+            }
+        }
+        // The owner writes its process ID to the file
+        let owner = match std::fs::read_to_string(&path) {
+            Ok(pid) if !pid.trim().is_empty() => format!("process {}", pid.trim()),
+            _ => "another process".to_string(),
+        };
+        let message = format!("Probe {} is in use by {}", selector, owner);
+        if cli.no_wait {
+            return Err(failed(Failure::ProbeBusy, message));
+        }
+        if let Some(timeout) = cli.wait_timeout {
+            if started.elapsed() >= Duration::from_secs(timeout) {
+                return Err(failed(
+                    Failure::ProbeBusy,
+                    format!("{}, gave up after {} s", message, timeout),
+                ));
+            }
+        }
+        if !waiting {
+            info!("{}, waiting", message);
+            waiting = true;
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+    file.set_len(0)?;
+    write!(file, "{}", std::process::id())?;
+    Ok(ProbeLock { _file: file })
+}
+
+/// Opens the probe and attaches to the chip
+fn attach(cli: &Cli, probe_info: &ProbeInfo) -> DynResult<Session> {
+    let mut probe = open_probe(cli, probe_info).map_err(|e| failed(Failure::ProbeNotFound, e))?;
+    let protocol = match (cli.protocol, &probe_info.protocol) {
+        (Some(protocol), _) => Some(protocol),
+        (None, Some(protocol)) => Some(
+            protocol
+                .parse::<WireProtocol>()
+                .map_err(|e| format!("Invalid protocol '{}': {}", protocol, e))?,
+        ),
+        (None, None) => None,
+    };
+    if let Some(protocol) = protocol {
+        probe.select_protocol(protocol)?;
+    }
+    if let Some(speed) = cli.speed_khz.or(probe_info.speed_khz) {
+        let actual = probe.set_speed(speed)?;
+        if actual != speed {
+            warn!("Using protocol speed {} kHz", actual);
+        }
+    }
+    if cli.reset_before_attach || probe_info.reset_before_attach {
+        probe.target_reset_assert()?;
+        std::thread::sleep(Duration::from_millis(10));
+        probe.target_reset_deassert()?;
+    }
+    let chip = cli.chip.as_ref().unwrap_or(&probe_info.chip);
+    let mut target = registry(cli)?
+        .get_target_by_name(chip)
+        .map_err(|e| failed(Failure::ChipMismatch, e))?;
+    let core = core_index(cli, probe_info);
+    let Some(core_desc) = target.cores.get_mut(core) else {
+        return Err(failed(
+            Failure::ChipMismatch,
+            format!("{} has no core {}", chip, core),
+        ));
+    };
+    if let Some(ap) = cli.ap.or(probe_info.ap) {
+        match &mut core_desc.core_access_options {
+            CoreAccessOptions::Arm(options) => options.ap = ApAddress::V1(ap),
+            _ => return Err(format!("Core {} of {} has no access port", core, chip).into()),
+        }
+    }
+    let session = if cli.connect_under_reset || probe_info.connect_under_reset {
+        probe.attach_under_reset(target, Permissions::default())?
+    } else {
+        probe.attach(target, Permissions::default())?
+    };
+    Ok(session)
+}
+
+/// Chips known to probe-rs, and those in the description file given with
+/// --chip-description-path
+fn registry(cli: &Cli) -> DynResult<Registry> {
+    let mut registry = Registry::from_builtin_families();
+    if let Some(path) = &cli.chip_description_path {
+        let yaml = std::fs::read_to_string(path).map_err(|e| {
+            failed(
+                Failure::FileMissing,
+                format!("Failed to read '{}': {}", path.display(), e),
+            )
+        })?;
+        let family = registry
+            .add_target_family_from_yaml(&yaml)
+            .map_err(|e| format!("Invalid target description '{}': {}", path.display(), e))?;
+        debug!("Added chip family {}", family);
+    }
+    Ok(registry)
+}
+
+/// Index of the core used to access the flash
+fn core_index(cli: &Cli, probe: &ProbeInfo) -> usize {
+    cli.core.or(probe.core).unwrap_or(0)
+}
+
+/// Whether blob files that don't match their checksums may be written. A
+/// required signature is only worth something if they may not.
+fn allow_modified(cli: &Cli, allow_stale: bool) -> bool {
+    allow_stale && !cli.require_signature
+}
+
+/// Flash options selected by args
+fn flash_options(cli: &Cli, args: &FlashArgs) -> FlashOptions {
+    FlashOptions {
+        force: args.force,
+        full_check: args.full_check,
+        chip_erase: args.chip_erase,
+        skip_erase: args.skip_erase && !args.no_skip_erase,
+        restore_unwritten: args.restore_unwritten,
+        fill: args.fill,
+        verify: args.verify,
+        flash_algorithms: args.flash_algorithm.clone(),
+        allow_modified: allow_modified(cli, args.allow_stale),
+        retries: args.retries,
+    }
+}
+
+/// Prints the timings as a table
+fn print_timings(timings: &FlashTimings) {
+    let secs = |d: Option<Duration>| {
+        d.map(|d| format!("{:.3}", d.as_secs_f64()))
+            .unwrap_or_else(|| "-".to_string())
+    };
+    println!(
+        "{:<16} {:>10} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}",
+        "Blob", "Size", "Read s", "Fill s", "Erase s", "Prog s", "Verify s", "KiB/s"
+    );
+    for blob in &timings.blobs {
+        let ops = blob.operations.as_ref();
+        let rate = ops
+            .map(|ops| format!("{:.1}", kib_per_sec(blob.size, ops.total())))
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "{:<16} {:>10} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}",
+            blob.name,
+            blob.size,
+            secs(Some(blob.read)),
+            secs(ops.map(|o| o.fill)),
+            secs(ops.map(|o| o.erase)),
+            secs(ops.map(|o| o.program)),
+            secs(ops.map(|o| o.verify)),
+            if blob.written {
+                rate
+            } else {
+                "skipped".to_string()
+            }
+        );
+    }
+    let ops = &timings.operations;
+    println!(
+        "{:<16} {:>10} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8.1}",
+        "Total",
+        timings.written_bytes(),
+        secs(Some(timings.blobs.iter().map(|b| b.read).sum())),
+        secs(Some(ops.fill)),
+        secs(Some(ops.erase)),
+        secs(Some(ops.program)),
+        secs(Some(ops.verify)),
+        kib_per_sec(timings.written_bytes(), timings.elapsed)
+    );
+    println!("Elapsed: {:.3} s", timings.elapsed.as_secs_f64());
+}
+
+/// Prints the timings and writes them as JSON, as requested by args
+fn report_timings(args: &FlashArgs, timings: &FlashTimings) -> DynResult<()> {
+    if args.timings {
+        print_timings(timings);
+    }
+    if let Some(path) = &args.timings_json {
+        std::fs::write(path, serde_json::to_string_pretty(&timings.to_json())?)?;
+    }
+    Ok(())
+}
+
+/// Progress bars are shown at the same verbosity as info messages
+fn progress_enabled() -> bool {
+    tracing::enabled!(Level::INFO)
+}
+
+fn progress_style() -> ProgressStyle {
+    ProgressStyle::with_template(
+        "{msg:24} [{bar:40}] {bytes:>10}/{total_bytes:10} {binary_bytes_per_sec:>12} {eta:>4}",
+    )
+    .unwrap()
+    .progress_chars("=> ")
+}
+
+/// Progress bar for writing an image without a probe
+fn blob_progress(message: &str, size: u32) -> ProgressBar {
+    let bar = ProgressBar::new(size as u64)
+        .with_style(progress_style())
+        .with_message(message.to_string());
+    if !progress_enabled() {
+        bar.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    bar
+}
+
+fn operation_name(operation: ProgressOperation) -> &'static str {
+    match operation {
+        ProgressOperation::Fill => "Reading kept bytes",
+        ProgressOperation::Erase => "Erasing",
+        ProgressOperation::Program => "Programming",
+        ProgressOperation::Verify => "Verifying",
+    }
+}
+
+/// Progress bars for reading back blobs and for each operation of a flash
+/// loader commit
+struct ProgressBars {
+    multi: MultiProgress,
+    operations: Vec<(&'static str, ProgressBar)>,
+    read: Option<ProgressBar>,
+}
+
+impl ProgressBars {
+    fn new() -> ProgressBars {
+        let multi = MultiProgress::new();
+        if !progress_enabled() {
+            multi.set_draw_target(ProgressDrawTarget::hidden());
+        }
+        ProgressBars {
+            multi,
+            operations: Vec::new(),
+            read: None,
+        }
+    }
+
+    /// Bar of the latest commit for operation
+    fn operation(&self, operation: ProgressOperation) -> Option<&ProgressBar> {
+        let name = operation_name(operation);
+        self.operations
+            .iter()
+            .rev()
+            .find(|(n, _)| *n == name)
+            .map(|(_, bar)| bar)
+    }
+
+    fn handle(&mut self, progress: Progress) {
+        match progress {
+            Progress::ReadStarted { kind, name, size } => {
+                let action = match kind {
+                    ReadKind::Check => "Checking",
+                    ReadKind::Verify => "Verifying",
+                    ReadKind::Dump => "Reading",
+                };
+                self.read = Some(
+                    self.multi.add(
+                        ProgressBar::new(size)
+                            .with_style(progress_style())
+                            .with_message(format!("{} {}", action, name)),
+                    ),
+                );
+            }
+            Progress::Read(len) => {
+                if let Some(bar) = &self.read {
+                    bar.inc(len as u64);
+                }
+            }
+            Progress::ReadFinished => {
+                if let Some(bar) = self.read.take() {
+                    bar.finish_and_clear();
+                }
+            }
+            Progress::Flash(ProgressEvent::AddProgressBar { operation, total }) => {
+                let bar = self.multi.add(
+                    ProgressBar::new(total.unwrap_or(0))
+                        .with_style(progress_style())
+                        .with_message(operation_name(operation)),
+                );
+                self.operations.push((operation_name(operation), bar));
+            }
+            Progress::Flash(ProgressEvent::Started(operation)) => {
+                if let Some(bar) = self.operation(operation) {
+                    bar.reset_elapsed();
+                }
+            }
+            Progress::Flash(ProgressEvent::Progress {
+                operation, size, ..
+            }) => {
+                if let Some(bar) = self.operation(operation) {
+                    bar.inc(size);
+                }
+            }
+            Progress::Flash(ProgressEvent::Failed(operation)) => {
+                if let Some(bar) = self.operation(operation) {
+                    bar.abandon_with_message(format!("{} failed", operation_name(operation)));
+                }
+            }
+            Progress::Flash(ProgressEvent::Finished(operation)) => {
+                if let Some(bar) = self.operation(operation) {
+                    bar.finish();
+                }
+            }
+            Progress::Flash(ProgressEvent::DiagnosticMessage { message }) => {
+                self.multi.suspend(|| info!("{}", message));
+            }
+            Progress::Flash(_) => {}
+        }
+    }
+}
+
+/// Waits for the firmware to print a line with VERIFY_OK_MARKER or
+/// VERIFY_FAIL_MARKER on the first RTT up channel. Returns whether the
+/// firmware verified the blobs.
+fn wait_rtt_report(session: &mut Session, core: usize, args: &FlashArgs) -> DynResult<bool> {
+    let elf = match &args.defmt {
+        Some(path) => Some(
+            std::fs::read(path)
+                .map_err(|e| format!("Failed to read '{}': {}", path.display(), e))?,
+        ),
+        None => None,
+    };
+    let table = match &elf {
+        Some(elf) => Some(
+            defmt_decoder::Table::parse(elf)
+                .map_err(|e| format!("Failed to read defmt table: {}", e))?
+                .ok_or("No defmt table in ELF file")?,
+        ),
+        None => None,
+    };
+    let mut decoder = table.as_ref().map(|t| t.new_stream_decoder());
+    let deadline = Instant::now() + Duration::from_secs(args.rtt_timeout);
+    let mut core = session.core(core)?;
+    // The control block is set up by the firmware after reset
+    let mut rtt = loop {
+        match Rtt::attach(&mut core) {
+            Ok(rtt) => break rtt,
+            Err(e) if Instant::now() < deadline => {
+                debug!("RTT not ready: {}", e);
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => return Err(format!("Failed to attach to RTT: {}", e).into()),
+        }
+    };
+    let channel = rtt.up_channel(0).ok_or("No RTT up channel")?;
     let mut buf = [0u8; 1024];
+    let mut text = String::new();
+    while Instant::now() < deadline {
+        let len = channel.read(&mut core, &mut buf)?;
+        if len == 0 {
+            std::thread::sleep(Duration::from_millis(10));
+            continue;
+        }
+        match &mut decoder {
+            Some(decoder) => {
+                decoder.received(&buf[..len]);
+                loop {
+                    match decoder.decode() {
+                        Ok(frame) => {
+                            text += &frame.display_message().to_string();
+                            text.push('\n');
+                        }
+                        Err(defmt_decoder::DecodeError::UnexpectedEof) => break,
+                        Err(defmt_decoder::DecodeError::Malformed) => {
+                            return Err("Malformed defmt data over RTT".into())
+                        }
+                    }
+                }
+            }
+            None => text += &String::from_utf8_lossy(&buf[..len]),
+        }
+        while let Some(end) = text.find('\n') {
+            let line: String = text.drain(..=end).collect();
+            let line = line.trim_end();
+            debug!("RTT: {}", line);
+            // The line may have a prefix from the logging framework
+            if line.contains(VERIFY_OK_MARKER) {
+                info!("Firmware verified the blobs");
+                return Ok(true);
+            }
+            if line.contains(VERIFY_FAIL_MARKER) {
+                error!("Firmware failed to verify the blobs: {}", line);
+                return Ok(false);
+            }
+        }
+    }
+    Err("Timeout waiting for the firmware to report over RTT".into())
+}
+
+/// Reads back each blob, and the blob table if there is one, and compares
+/// them with the blob info. Nothing is written to the target. Returns true
+/// if everything matches.
+fn verify_blob(flasher: &mut Flasher, blob_info: &BlobInfoFile) -> DynResult<bool> {
+    let results = flasher.verify(blob_info)?;
+    let mut failed = 0;
+    for (name, result) in &results {
+        let start = blob_info
+            .info
+            .get(name)
+            .map(|b| b.load_address())
+            .or(blob_info.table.as_ref().map(|t| t.start as u64))
+            .unwrap_or(0);
+        match result {
+            Verified::Match => info!("{} ok", name),
+            Verified::Mismatch => {
+                error!("{} at 0x{:x} doesn't match", name, start);
+                failed += 1;
+            }
+            Verified::ReadFailed(e) => {
+                error!("Failed to read {} at 0x{:x}: {}", name, start, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed == 0 {
+        info!("All {} blobs match", blob_info.info.len());
+    } else {
+        error!("{} verification failures", failed);
+    }
+    Ok(failed == 0)
+}
+
+/// Writes a compressed blob read from the target decompressed, next to the
+/// compressed one. Returns a note for the report.
+fn dump_decompressed(out: &Path, name: &str, blob: &BlobInfo, data: &[u8]) -> DynResult<String> {
+    let (Some(compression), None) = (&blob.compression, &blob.encryption) else {
+        return Ok(String::new());
+    };
+    let Some(codec) = Codec::from_name(&compression.algorithm) else {
+        return Ok(format!(
+            " (compressed with unknown {})",
+            compression.algorithm
+        ));
+    };
+    let decompressed = match codec.decompress(data, compression.uncompressed_size as usize) {
+        Ok(decompressed) => decompressed,
+        Err(e) => return Ok(format!(" (failed to decompress: {})", e)),
+    };
+    std::fs::write(
+        out.join(format!("{}.decompressed.bin", name)),
+        &decompressed,
+    )?;
+    let checksum = Checksum::of(compression.uncompressed_checksum.algorithm, &decompressed);
+    Ok(format!(
+        " (decompressed {})",
+        if checksum == compression.uncompressed_checksum {
+            "matches"
+        } else {
+            "differs"
+        }
+    ))
+}
+
+/// Reads each blob from the target into a file in out, and writes a report
+/// of which blobs match their checksum
+fn dump_blob(flasher: &mut Flasher, blob_info: &BlobInfoFile, out: &Path) -> DynResult<()> {
+    std::fs::create_dir_all(out)?;
+    let mut report = String::new();
     for (name, blob) in &blob_info.info {
-	let mut start = blob.start;
-	print!("Reading {} at 0x{:x} ...", name,start);
-	let mut f = File::open(&blob.filename)?;
-	loop {
-            let r = f.read(&mut buf)?;
-            if r == 0 {
-                break;
+        let data = flasher.read_blob(name, blob)?;
+        let file = out.join(format!("{}.bin", name));
+        std::fs::write(&file, &data)?;
+        let checksum = Checksum::of(blob.checksum.algorithm, &data);
+        // What is in flash of an encrypted blob is the ciphertext
+        let encrypted = blob
+            .encryption
+            .as_ref()
+            .map(|e| format!(" (encrypted with key '{}')", e.key_id))
+            .unwrap_or_default();
+        let decompressed = dump_decompressed(out, name, blob, &data)?;
+        let line = format!(
+            "{}: 0x{:08x} {} bytes {} {}{}{}\n",
+            name,
+            blob.start,
+            blob.size,
+            checksum,
+            if checksum == blob.checksum {
+                "matches"
+            } else {
+                "differs"
+            },
+            encrypted,
+            decompressed
+        );
+        print!("{}", line);
+        report += &line;
+    }
+    std::fs::write(out.join("report.txt"), report)?;
+    Ok(())
+}
+
+/// Source file of each blob built from one, which is the blob file itself
+/// unless the build compressed or encrypted it
+fn source_paths(blob_info: &BlobInfoFile) -> Vec<(String, PathBuf)> {
+    blob_info
+        .info
+        .iter()
+        .filter_map(|(name, blob)| {
+            let source = blob.source.as_ref()?;
+            Some((name.clone(), blob_info.path(&source.filename)))
+        })
+        .collect()
+}
+
+/// Modification time of each file, None for files that can't be read
+fn modified_times(paths: &[(String, PathBuf)]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|(_, path)| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Polls the source files of the blobs until interrupted. When the source
+/// of a blob that is flashed as it is changes, its checksum is updated and
+/// the blobs are flashed, which skips the unchanged ones. Compressed and
+/// encrypted blobs, and a file whose size has changed, need a rebuild.
+/// Blobs built from directories aren't watched.
+fn watch_blobs(
+    flasher: &mut Flasher,
+    blob_info: &mut BlobInfoFile,
+    interval: Duration,
+    reset: bool,
+) -> DynResult<()> {
+    // Only the changed blobs are written, so it has to be safe to erase
+    // their sectors before the loop starts
+    flasher.check_erase(blob_info, false)?;
+    let paths = source_paths(blob_info);
+    let mut times = modified_times(&paths);
+    info!("Watching {} source files", paths.len());
+    loop {
+        std::thread::sleep(interval);
+        let current = modified_times(&paths);
+        if current == times {
+            continue;
+        }
+        let mut changed = false;
+        for (((name, path), modified), old) in paths.iter().zip(&current).zip(&times) {
+            if modified == old || modified.is_none() {
+                continue;
+            }
+            if *path != blob_info.blob_path(&blob_info.info[name]) {
+                warn!(
+                    "{} is built from '{}', rebuild to flash the change",
+                    name,
+                    path.display()
+                );
+                continue;
+            }
+            let blob = blob_info.info.get_mut(name).unwrap();
+            let data = match std::fs::read(path) {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("Failed to read '{}': {}", path.display(), e);
+                    continue;
+                }
+            };
+            if data.len() != blob.size as usize {
+                warn!(
+                    "Size of '{}' for blob {} has changed, rebuild to update the layout",
+                    path.display(),
+                    name
+                );
+                continue;
             }
-	    loader.add_data(start as u64, &buf[..r])?;
-	    start += r as u32;
+            let checksum = Checksum::of(blob.checksum.algorithm, &data);
+            if checksum != blob.checksum {
+                info!("{} changed", name);
+                warn!(
+                    "The firmware has the checksum of {} from the build, rebuild it before \
+                     verifying the blob",
+                    name
+                );
+                blob.checksum = checksum;
+                changed = true;
+            }
+        }
+        times = current;
+        if !changed {
+            continue;
+        }
+        let mut timings = FlashTimings::default();
+        if let Err(e) = flasher.flash(blob_info, None, &mut timings) {
+            error!("Failed to load blobs: {}", e);
+            continue;
+        }
+        if reset {
+            flasher.reset(false)?;
+        }
+        info!("Flashed in {:.1} s", timings.elapsed.as_secs_f64());
+    }
+}
+
+/// Asks on the terminal for confirmation of a destructive operation, unless
+/// yes is set. Fails if not confirmed, or if stdin isn't a terminal.
+fn confirm(yes: bool, message: &str) -> DynResult<()> {
+    if yes {
+        return Ok(());
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err(format!("{}, pass --yes to confirm", message).into());
+    }
+    eprint!("{}. Continue? [y/N] ", message);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    match answer.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err("Aborted".into()),
+    }
+}
+
+/// Erases the sectors of the named blobs, or of all blobs and the blob
+/// table if names is empty
+fn erase_blob(
+    flasher: &mut Flasher,
+    blob_info: &BlobInfoFile,
+    names: &[String],
+    yes: bool,
+) -> DynResult<()> {
+    let maps = sector_maps(flasher.session()?.target());
+    let ranges = flash::erase_ranges(&maps, blob_info, names)?;
+    confirm(yes, &format!("This erases {}", format_ranges(&ranges)))?;
+    flasher.erase(&ranges)
+}
+
+/// Unix time as a UTC date and time
+fn format_utc(secs: u64) -> String {
+    // Civil from days, proleptic Gregorian calendar
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let time = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+fn print_info(registry: &Registry, blob_info: &BlobInfoFile, chip: &str) {
+    if let Some(build) = &blob_info.build {
+        println!(
+            "Built from: {} {} ({})",
+            build.package, build.version, build.profile
+        );
+        if let Some(git) = &build.git {
+            println!("Git: {}", git);
+        }
+        if let Some(timestamp) = build.timestamp {
+            println!("Built at: {}", format_utc(timestamp));
+        }
+    }
+    if let Some(artifacts) = &blob_info.artifacts {
+        println!("Build id: {}", artifacts.build_id);
+    }
+    println!("Format version: {}", blob_info.format_version);
+    if !blob_info.environments.is_empty() {
+        let names: Vec<&str> = blob_info.environments.keys().map(String::as_str).collect();
+        println!("Environments: {}", names.join(", "));
+    }
+    if let Some(base_dir) = &blob_info.base_dir {
+        println!("Blob files in: {}", base_dir.display());
+    }
+    println!("Chip: {}", chip);
+    if let Some(selector) = &blob_info.probe.selector {
+        println!("Probe: {}", selector);
+    }
+    if let Some(protocol) = &blob_info.probe.protocol {
+        println!("Protocol: {}", protocol);
+    }
+    if let Some(speed) = blob_info.probe.speed_khz {
+        println!("Speed: {} kHz", speed);
+    }
+    if blob_info.probe.reset_before_attach {
+        println!("Reset before attach");
+    }
+    if blob_info.probe.connect_under_reset {
+        println!("Connect under reset");
+    }
+    if let Some(core) = blob_info.probe.core {
+        println!("Core: {}", core);
+    }
+    if let Some(ap) = blob_info.probe.ap {
+        println!("Access port: {}", ap);
+    }
+    // The recorded sectors are used if they are for the chip
+    let target = if blob_info.sector_maps().is_some() && chip == blob_info.probe.chip {
+        None
+    } else {
+        registry
+            .get_target_by_name(chip)
+            .map_err(|e| warn!("No flash layout for chip: {}", e))
+            .ok()
+    };
+    let maps = match &target {
+        Some(target) => Some(sector_maps(target)),
+        None => blob_info.sector_maps().map(<[SectorMap]>::to_vec),
+    };
+    print_layout(blob_info, maps.as_deref());
+    if let Some(layout) = &blob_info.layout {
+        print_memory_map(blob_info, layout);
+    }
+    print_notes(blob_info);
+}
+
+/// Blobs with tags or notes, sorted by name
+fn annotated_blobs(blob_info: &BlobInfoFile) -> Vec<(&str, &BlobInfo)> {
+    blob_info
+        .info
+        .iter()
+        .filter(|(_, blob)| !blob.tags.is_empty() || blob.notes.is_some())
+        .map(|(name, blob)| (name.as_str(), blob))
+        .collect()
+}
+
+/// Prints the tags and notes of the blobs, e.g. "font [fcc-test-image]: do
+/// not ship"
+fn print_notes(blob_info: &BlobInfoFile) {
+    let blobs = annotated_blobs(blob_info);
+    if blobs.is_empty() {
+        return;
+    }
+    println!("Notes:");
+    for (name, blob) in blobs {
+        let mut line = format!("  {}", name);
+        if !blob.tags.is_empty() {
+            line += &format!(" [{}]", blob.tags.join(", "));
+        }
+        if let Some(notes) = &blob.notes {
+            line += &format!(": {}", notes);
+        }
+        println!("{}", line);
+    }
+}
+
+/// Prints FLASH from memory.x, split into the application, the blob table,
+/// the blobs and the gaps between them
+fn print_memory_map(blob_info: &BlobInfoFile, layout: &LayoutInfo) {
+    let flash = layout.flash.range();
+    let mut parts: Vec<(Range<u64>, String)> = blob_info
+        .regions()
+        .into_iter()
+        .filter(|(_, r)| r.start >= flash.start && r.end <= flash.end)
+        .map(|(name, r)| (r, name.to_string()))
+        .collect();
+    parts.push((layout.app.range(), "(application)".to_string()));
+    parts.extend(
+        layout
+            .gaps
+            .iter()
+            .map(|g| (g.range(), "(unused)".to_string())),
+    );
+    parts.sort_by_key(|(r, _)| r.start);
+    println!(
+        "Memory map of FLASH 0x{:08x}-0x{:08x}:",
+        flash.start,
+        flash.end.saturating_sub(1)
+    );
+    for (range, name) in parts {
+        println!(
+            "  0x{:08x}-0x{:08x} {:>10} {}",
+            range.start,
+            range.end.saturating_sub(1),
+            range.end - range.start,
+            name
+        );
+    }
+}
+
+/// Prints the blobs and the blob table sorted by address, with the flash
+/// sectors they occupy and the flash left for the application
+fn print_layout(blob_info: &BlobInfoFile, maps: Option<&[SectorMap]>) {
+    let mut regions: Vec<(String, String, &str, u64, u64, String)> = blob_info
+        .info
+        .iter()
+        .map(|(name, blob)| {
+            (
+                name.clone(),
+                blob_info.blob_path(blob).display().to_string(),
+                blob.region.as_deref().unwrap_or(""),
+                blob.start,
+                blob.size,
+                blob.checksum.to_string(),
+            )
+        })
+        .collect();
+    if let Some(table) = &blob_info.table {
+        regions.push((
+            "(blob table)".to_string(),
+            String::new(),
+            "",
+            table.start as u64,
+            table.size as u64,
+            String::new(),
+        ));
+    }
+    regions.sort_by_key(|r| r.3);
+    println!(
+        "{:<16} {:<8} {:<23} {:>10} {:<40} {:<30} File",
+        "Name", "Region", "Address range", "Size", "Checksum", "Erase sectors"
+    );
+    for (name, filename, region, start, size, checksum) in &regions {
+        let (start, size) = (*start, *size);
+        let sectors = maps
+            .and_then(|m| erase_sectors(m, start, start + size))
+            .map(|(range, count)| {
+                format!("0x{:08x}-0x{:08x} ({})", range.start, range.end - 1, count)
+            })
+            .unwrap_or_else(|| "?".to_string());
+        println!(
+            "{:<16} {:<8} 0x{:08x}-0x{:08x} {:>10} {:<40} {:<30} {}",
+            name,
+            region,
+            start,
+            (start + size).saturating_sub(1),
+            size,
+            checksum,
+            sectors,
+            filename
+        );
+    }
+    let compressed: Vec<_> = blob_info
+        .info
+        .iter()
+        .filter_map(|(name, blob)| Some((name, blob.compression.as_ref()?)))
+        .collect();
+    for (name, compression) in compressed {
+        println!(
+            "{} is compressed with {}, {} bytes uncompressed with checksum {}",
+            name,
+            compression.algorithm,
+            compression.uncompressed_size,
+            compression.uncompressed_checksum
+        );
+    }
+    let encrypted: Vec<_> = blob_info
+        .info
+        .iter()
+        .filter_map(|(name, blob)| Some((name, blob.encryption.as_ref()?)))
+        .collect();
+    for (name, encryption) in encrypted {
+        println!(
+            "{} is encrypted with {}, key '{}', nonce {}",
+            name,
+            encryption.cipher,
+            encryption.key_id,
+            encryption
+                .nonce
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        );
+    }
+    let padded: Vec<_> = blob_info
+        .info
+        .iter()
+        .filter_map(|(name, blob)| Some((name, blob.padding.as_ref()?)))
+        .filter(|(_, padding)| padding.before > 0 || padding.after > 0)
+        .collect();
+    for (name, padding) in padded {
+        println!(
+            "{} is aligned to {} bytes, padded with {} bytes before and {} after (0x{:02x})",
+            name, padding.align, padding.before, padding.after, padding.fill
+        );
+    }
+    let (Some(first), Some(last)) = (
+        regions.iter().map(|r| r.3).min(),
+        regions.iter().map(|r| r.3 + r.4).max(),
+    ) else {
+        return;
+    };
+    let total: u64 = regions.iter().map(|r| r.4).sum();
+    println!(
+        "{:<16} {:<8} 0x{:08x}-0x{:08x} {:>10} ({} bytes reserved)",
+        "Total",
+        "",
+        first,
+        last - 1,
+        total,
+        last - first
+    );
+    // The application gets the rest of the flash holding the blobs
+    let Some(flash) = maps.and_then(|m| m.iter().find(|m| m.contains(first))) else {
+        return;
+    };
+    let (app_start, app_end) = if first > flash.start {
+        (flash.start, first)
+    } else {
+        (last, flash.end)
+    };
+    println!(
+        "Flash left for the application: {} bytes at 0x{:08x}-0x{:08x}",
+        app_end - app_start,
+        app_start,
+        app_end.saturating_sub(1)
+    );
+}
+
+/// Prints the probe settings and the blobs that would be flashed
+fn dry_run(cli: &Cli, blob_info: &BlobInfoFile) {
+    let chip = cli.chip.as_ref().unwrap_or(&blob_info.probe.chip);
+    let probe = match (&cli.probe, &blob_info.probe.selector) {
+        (Some(selector), _) => selector.to_string(),
+        (None, Some(selector)) => selector.clone(),
+        (None, None) => "the only connected probe".to_string(),
+    };
+    println!("Chip: {}", chip);
+    println!("Probe: {}", probe);
+    if let Some(protocol) = cli
+        .protocol
+        .map(|p| p.to_string())
+        .or_else(|| blob_info.probe.protocol.clone())
+    {
+        println!("Protocol: {}", protocol);
+    }
+    if let Some(speed) = cli.speed_khz.or(blob_info.probe.speed_khz) {
+        println!("Speed: {} kHz", speed);
+    }
+    if cli.reset_before_attach || blob_info.probe.reset_before_attach {
+        println!("Reset before attach");
+    }
+    if cli.connect_under_reset || blob_info.probe.connect_under_reset {
+        println!("Connect under reset");
+    }
+    println!("Core: {}", core_index(cli, &blob_info.probe));
+    if let Some(ap) = cli.ap.or(blob_info.probe.ap) {
+        println!("Access port: {}", ap);
+    }
+    let maps = chip_sector_maps(cli, blob_info)
+        .map_err(|e| warn!("No flash layout for chip: {}", e))
+        .ok();
+    print_layout(blob_info, maps.as_deref());
+}
+
+fn export(cli: &Cli, blob_info: &BlobInfoFile, args: &ExportArgs) -> DynResult<()> {
+    let allow_modified = allow_modified(cli, args.allow_stale);
+    let image = blob_image::build(blob_info, args.fill, allow_modified)?;
+    write_image(&image, &args.output, args)?;
+    println!("Base address: 0x{:08x}", image.base);
+    // Blobs in external flash are programmed separately
+    if let Some(image) = blob_image::build_external(blob_info, args.fill, allow_modified)? {
+        let mut name = args.output.file_stem().unwrap_or_default().to_owned();
+        name.push("-external");
+        let mut path = args.output.with_file_name(name);
+        if let Some(extension) = args.output.extension() {
+            path.set_extension(extension);
+        }
+        write_image(&image, &path, args)?;
+        println!("External base address: 0x{:08x}", image.base);
+    }
+    Ok(())
+}
+
+fn write_image(image: &BlobImage, path: &Path, args: &ExportArgs) -> DynResult<()> {
+    match args.format {
+        ExportFormat::Bin => std::fs::write(path, &image.data)?,
+        ExportFormat::Uf2 => std::fs::write(path, image.to_uf2(args.family_id, args.fill))?,
+    }
+    info!("Wrote {} bytes to {}", image.data.len(), path.display());
+    Ok(())
+}
+
+/// Warns about the blobs in external flash, which only a debug probe writes
+fn warn_external(blob_info: &BlobInfoFile, backend: &str) {
+    let external: Vec<&str> = blob_info
+        .info
+        .iter()
+        .filter(|(_, blob)| blob.external.is_some())
+        .map(|(name, _)| name.as_str())
+        .collect();
+    if !external.is_empty() {
+        warn!(
+            "Blobs in external flash aren't written {}: {}",
+            backend,
+            external.join(", ")
+        );
+    }
+}
+
+/// Writes the blobs and the blob table over DFU. Erasing sectors shared
+/// with the application is refused, and erasing outside the blob region
+/// has to be confirmed, as when flashing with a probe.
+fn load_blob_dfu(cli: &Cli, blob_info: &BlobInfoFile, args: &FlashArgs) -> DynResult<()> {
+    warn_external(blob_info, "over DFU");
+    let image = blob_image::build(blob_info, 0xff, allow_modified(cli, args.allow_stale))?;
+    let end = image.base + image.data.len() as u32;
+    let mut device = DfuDevice::open(args.dfu_device, image.base, end)?;
+    flash::check_erase(
+        &[device.segment().sector_map()],
+        blob_info,
+        &FlashOptions::default(),
+        false,
+        &mut |message| confirm(cli.yes, message),
+    )?;
+    info!(
+        "Writing {} bytes at 0x{:08x} to {}",
+        image.data.len(),
+        image.base,
+        device.segment().name
+    );
+    let progress = blob_progress("Writing", image.data.len() as u32);
+    device.write(&image, &mut |written| progress.set_position(written as u64))?;
+    progress.finish_and_clear();
+    if args.reset {
+        device.leave()?;
+    }
+    Ok(())
+}
+
+/// Erase sectors of the chip, as recorded in the blob info file unless
+/// another chip or chip description is given
+fn chip_sector_maps(cli: &Cli, blob_info: &BlobInfoFile) -> DynResult<Vec<SectorMap>> {
+    if let (Some(maps), None, None) = (
+        blob_info.sector_maps(),
+        &cli.chip,
+        &cli.chip_description_path,
+    ) {
+        return Ok(maps.to_vec());
+    }
+    let chip = cli.chip.as_ref().unwrap_or(&blob_info.probe.chip);
+    Ok(sector_maps(&registry(cli)?.get_target_by_name(chip)?))
+}
+
+/// Writes the blobs and the blob table through the STM32 UART bootloader.
+/// The flash pages to erase are taken from the chip description.
+fn load_blob_serial(cli: &Cli, blob_info: &BlobInfoFile, args: &FlashArgs) -> DynResult<()> {
+    let chip = cli.chip.as_ref().unwrap_or(&blob_info.probe.chip);
+    warn_external(blob_info, "over the serial port");
+    let maps = chip_sector_maps(cli, blob_info)?;
+    let image = blob_image::build(blob_info, 0xff, allow_modified(cli, args.allow_stale))?;
+    let start = image.base as u64;
+    let end = start + image.data.len() as u64;
+    let sectors = flash_sectors(&maps, start)
+        .ok_or_else(|| format!("No flash at 0x{:08x} for {}", start, chip))?;
+    flash::check_erase(
+        &maps,
+        blob_info,
+        &FlashOptions::default(),
+        false,
+        &mut |message| confirm(cli.yes, message),
+    )?;
+    let pages: Vec<u16> = sectors
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| s.start < end && s.end > start)
+        .map(|(page, _)| page as u16)
+        .collect();
+    let port = args.port.as_deref().ok_or("No serial port selected")?;
+    let mut boot = SerialBootloader::open(port, args.baud)?;
+    info!("Erasing {} pages", pages.len());
+    boot.erase(&pages)?;
+    info!("Writing {} bytes at 0x{:08x}", image.data.len(), image.base);
+    let progress = blob_progress("Writing", image.data.len() as u32);
+    boot.write(image.base, &image.data, &mut |written| {
+        progress.set_position(written as u64)
+    })?;
+    progress.finish_and_clear();
+    if args.reset {
+        boot.go(sectors[0].start as u32)?;
+    }
+    Ok(())
+}
+
+/// Writes the blobs and the blob table through a GDB server. Erasing is
+/// done for whole sectors, as described for the chip.
+fn load_blob_remote(cli: &Cli, blob_info: &BlobInfoFile, args: &FlashArgs) -> DynResult<()> {
+    let chip = cli.chip.as_ref().unwrap_or(&blob_info.probe.chip);
+    warn_external(blob_info, "through a GDB server");
+    let maps = chip_sector_maps(cli, blob_info)?;
+    let image = blob_image::build(blob_info, 0xff, allow_modified(cli, args.allow_stale))?;
+    let start = image.base as u64;
+    let end = start + image.data.len() as u64;
+    let (sectors, _) = erase_sectors(&maps, start, end)
+        .ok_or_else(|| format!("No flash at 0x{:08x} for {}", start, chip))?;
+    flash::check_erase(
+        &maps,
+        blob_info,
+        &FlashOptions::default(),
+        false,
+        &mut |message| confirm(cli.yes, message),
+    )?;
+    let addr = args.remote.as_deref().ok_or("No GDB server selected")?;
+    let mut remote = GdbRemote::connect(addr)?;
+    info!(
+        "Erasing 0x{:08x}-0x{:08x} through {}",
+        sectors.start,
+        sectors.end - 1,
+        addr
+    );
+    remote.flash_erase(sectors.start as u32, (sectors.end - sectors.start) as u32)?;
+    info!("Writing {} bytes at 0x{:08x}", image.data.len(), image.base);
+    let progress = blob_progress("Writing", image.data.len() as u32);
+    remote.flash_write(image.base, &image.data, &mut |written| {
+        progress.set_position(written as u64)
+    })?;
+    remote.flash_done()?;
+    progress.finish_and_clear();
+    if args.reset {
+        remote.monitor("reset")?;
+    } else if args.halt {
+        remote.monitor("reset halt")?;
+    }
+    Ok(())
+}
+
+fn list_probes() {
+    let probes = Lister::new().list_all();
+    if probes.is_empty() {
+        println!("No debug probes found");
+    }
+    for info in &probes {
+        println!("{} ({})", DebugProbeSelector::from(info), info.identifier);
+    }
+}
+
+fn list_chips(registry: &Registry, name: Option<&str>) {
+    let mut chips = registry.search_chips(name.unwrap_or(""));
+    chips.sort();
+    chips.dedup();
+    for chip in chips {
+        println!("{}", chip);
+    }
+}
+
+/// Written by prepare_blob, depending on the configured format
+const BLOB_INFO_EXTENSIONS: [&str; 2] = ["toml", "json"];
+
+/// The most recently modified of the files that exist
+fn latest_file(candidates: Vec<PathBuf>) -> Option<PathBuf> {
+    candidates
+        .into_iter()
+        .filter_map(|path| {
+            let modified = path.metadata().and_then(|m| m.modified()).ok()?;
+            Some((modified, path))
+        })
+        .max()
+        .map(|(_, path)| path)
+}
+
+/// Directory name used by cargo for a profile
+fn profile_dir(profile: &str) -> &str {
+    match profile {
+        "dev" | "test" => "debug",
+        "bench" => "release",
+        _ => profile,
+    }
+}
+
+/// Path of the blob info file in the target directory of the current
+/// package or workspace. If a package or profile is selected, the file
+/// written for that package is used.
+fn default_blob_info(package: Option<&str>, profile: Option<&str>) -> DynResult<PathBuf> {
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let output = std::process::Command::new(cargo)
+        .args(["metadata", "--format-version", "1", "--no-deps"])
+        .output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "cargo metadata failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    let metadata: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    let target_dir = PathBuf::from(
+        metadata["target_directory"]
+            .as_str()
+            .ok_or("No target directory in cargo metadata")?,
+    );
+    if package.is_none() && profile.is_none() {
+        let candidates = BLOB_INFO_EXTENSIONS
+            .iter()
+            .map(|extension| target_dir.join(format!("BlobInfo.{}", extension)))
+            .collect();
+        return Ok(latest_file(candidates).unwrap_or_else(|| target_dir.join("BlobInfo.toml")));
+    }
+    let packages: Vec<&str> = metadata["packages"]
+        .as_array()
+        .map(|p| p.iter().filter_map(|p| p["name"].as_str()).collect())
+        .unwrap_or_default();
+    let package = match (package, packages.as_slice()) {
+        (Some(package), _) if packages.contains(&package) => package,
+        (Some(package), _) => return Err(format!("No package named '{}'", package).into()),
+        (None, [package]) => package,
+        (None, _) => {
+            return Err(format!(
+                "Several packages in workspace, select one with -p: {}",
+                packages.join(", ")
+            )
+            .into())
+        }
+    };
+    let profile = profile_dir(profile.unwrap_or("dev"));
+    // The profile directory is inside a directory named after the target
+    // triple when cross compiling. Use the latest if there are several.
+    let mut dirs = vec![target_dir.join(profile)];
+    for entry in std::fs::read_dir(&target_dir)?.flatten() {
+        dirs.push(entry.path().join(profile));
+    }
+    let candidates = dirs
+        .iter()
+        .flat_map(|dir| {
+            BLOB_INFO_EXTENSIONS
+                .iter()
+                .map(move |extension| dir.join(format!("BlobInfo-{}.{}", package, extension)))
+        })
+        .collect();
+    latest_file(candidates).ok_or_else(|| {
+        format!(
+            "No blob info for package '{}' with profile '{}', build it first",
+            package, profile
+        )
+        .into()
+    })
+}
+
+/// Checks that several blob info files are for the same chip and don't
+/// overlap
+fn check_blob_infos(cli: &Cli, paths: &[PathBuf], blob_infos: &[BlobInfoFile]) -> DynResult<()> {
+    if cli.chip.is_none() {
+        let chip = &blob_infos[0].probe.chip;
+        if let Some(index) = blob_infos.iter().position(|b| b.probe.chip != *chip) {
+            return Err(format!(
+                "'{}' is for {}, but '{}' is for {}",
+                paths[0].display(),
+                chip,
+                paths[index].display(),
+                blob_infos[index].probe.chip
+            )
+            .into());
         }
-	println!("done");
+    }
+    let overlaps = blob_info::find_overlaps(blob_infos);
+    for ((a, a_name), (b, b_name)) in &overlaps {
+        error!(
+            "{} of '{}' overlaps {} of '{}'",
+            a_name,
+            paths[*a].display(),
+            b_name,
+            paths[*b].display()
+        );
+    }
+    if !overlaps.is_empty() {
+        return Err("The blob info files overlap".into());
+    }
+    Ok(())
+}
+
+/// Reads the build id from an ELF file. None if it has no build id symbol,
+/// e.g. if it doesn't include blob.rs.
+fn elf_build_id(path: &Path) -> DynResult<Option<Vec<u8>>> {
+    let data = std::fs::read(path).map_err(|e| MissingFile {
+        path: path.to_path_buf(),
+        error: e,
+    })?;
+    let elf = object::File::parse(&*data)?;
+    let Some(symbol) = elf
+        .symbols()
+        .find(|s| s.name() == Ok(blob_info::BUILD_ID_SYMBOL))
+    else {
+        return Ok(None);
+    };
+    let section = symbol
+        .section_index()
+        .ok_or("The build id symbol has no section")?;
+    let id = elf
+        .section_by_index(section)?
+        .data_range(symbol.address(), symbol.size())?
+        .ok_or("The build id symbol is outside its section")?;
+    Ok(Some(id.to_vec()))
+}
 
+/// Checks that the blob info file is from the same build as the files
+/// generated with it, and as the ELF file if one is flashed along with it.
+/// Files from before build ids were recorded can't be checked.
+fn check_build(blob_info: &BlobInfoFile, elf: Option<&Path>) -> DynResult<()> {
+    let Some(artifacts) = &blob_info.artifacts else {
+        return Ok(());
+    };
+    let changed = artifacts.changed();
+    if !changed.is_empty() {
+        return Err(format!(
+            "{} in '{}' changed after the blob info file was written, it's from an earlier build",
+            changed.join(" and "),
+            artifacts.out_dir.display()
+        )
+        .into());
+    }
+    let Some(elf) = elf else {
+        return Ok(());
+    };
+    match elf_build_id(elf)? {
+        Some(id) if id == artifacts.build_id.digest => {
+            debug!("'{}' is from build {}", elf.display(), artifacts.build_id)
+        }
+        Some(_) => {
+            return Err(format!(
+                "'{}' is from another build than the blob info file",
+                elf.display()
+            )
+            .into())
+        }
+        None => warn!(
+            "'{}' has no {} symbol, can't tell if it's from the same build",
+            elf.display(),
+            blob_info::BUILD_ID_SYMBOL
+        ),
     }
-    print!("Flashing ...");
-    loader.commit(&mut session, DownloadOptions::default())?;
-    println!("done");
     Ok(())
 }
 
-pub fn read_blob_info<R>(file: &mut R) -> DynResult<BlobInfoFile>
-where
-    R: Read,
-{
-    let mut buf = String::new();
-    file.read_to_string(&mut buf)?;
-    let blobs = toml::from_str(&buf)?;
-    Ok(blobs)
+/// Reads the public key given on the command line, either the key itself
+/// or a file holding it
+fn read_public_key(arg: &str) -> DynResult<VerifyingKey> {
+    let text = if arg.len() == 64 && arg.bytes().all(|b| b.is_ascii_hexdigit()) {
+        arg.to_string()
+    } else {
+        std::fs::read_to_string(arg)
+            .map_err(|e| format!("Failed to read public key '{}': {}", arg, e))?
+    };
+    Ok(signing::verifying_key(&text)?)
+}
+
+/// Checks the signature of a blob info file. Unless required, a file
+/// without a signature is only warned about.
+fn check_signature(key: &VerifyingKey, path: &Path, data: &[u8], require: bool) -> DynResult<()> {
+    let signature = if path.as_os_str() == "-" {
+        None
+    } else {
+        let signature_path = signing::signature_path(path);
+        match std::fs::read_to_string(&signature_path) {
+            Ok(signature) => Some(signature),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                return Err(format!(
+                    "Failed to read signature '{}': {}",
+                    signature_path.display(),
+                    e
+                )
+                .into())
+            }
+        }
+    };
+    let Some(signature) = signature else {
+        if require {
+            return Err(format!("Blob info file '{}' isn't signed", path.display()).into());
+        }
+        warn!("Blob info file '{}' isn't signed", path.display());
+        return Ok(());
+    };
+    signing::verify(key, data, &signature)
+        .map_err(|e| format!("{} for blob info file '{}'", e, path.display()))?;
+    debug!("Signature of '{}' is valid", path.display());
+    Ok(())
 }
 
-const BLOB_INFO_FILE: &str = "BlobInfo.toml";
+/// Logs to stderr at the level selected by -q and -v. The probe-rs logs are
+/// one level less verbose. RUST_LOG overrides both.
+fn init_logging(cli: &Cli) {
+    let (own, probe_rs) = match (cli.quiet, cli.verbose) {
+        (2.., _) => ("error", "error"),
+        (1, _) => ("warn", "error"),
+        (0, 0) => ("info", "warn"),
+        (0, 1) => ("debug", "info"),
+        (0, 2) => ("trace", "debug"),
+        (0, 3..) => ("trace", "trace"),
+    };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::new(format!(
+            "{}={},probe_rs={}",
+            env!("CARGO_CRATE_NAME"),
+            own,
+            probe_rs
+        ))
+    });
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .without_time()
+        .with_target(false)
+        .init();
+}
 
 fn main() -> ExitCode {
-    let info_file = PathBuf::from("target").join(BLOB_INFO_FILE);
-    let mut info_in = match File::open(&info_file) {
-        Ok(f) => f,
+    // When run as "cargo load-blob" the subcommand name is passed as the
+    // first argument
+    let mut args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|a| a == "load-blob").unwrap_or(false) {
+        args.remove(1);
+    }
+    let cli = Cli::parse_from(args);
+    check_flash_args(&cli);
+    init_logging(&cli);
+    let mut report = Report::default();
+    let result = run(&cli, &mut report);
+    if let Some(path) = &cli.report {
+        if let Err(e) = report.write(path, result) {
+            error!("Failed to write report: {}", e);
+            return Failure::Other.into();
+        }
+    }
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => failure.into(),
+    }
+}
+
+fn run(cli: &Cli, report: &mut Report) -> Result<(), Failure> {
+    if cli.print_exit_codes {
+        print_exit_codes();
+        return Ok(());
+    }
+    if cli.help_json {
+        print_help_json();
+        return Ok(());
+    }
+    match &cli.command {
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(
+                *shell,
+                &mut Cli::command(),
+                "cargo-load-blob",
+                &mut std::io::stdout(),
+            );
+            return Ok(());
+        }
+        Some(Command::ListProbes) => {
+            list_probes();
+            return Ok(());
+        }
+        Some(Command::ListChips { name }) => {
+            match registry(cli) {
+                Ok(registry) => list_chips(&registry, name.as_deref()),
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(failure_of(&*e, Failure::Other));
+                }
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+    let profile = if cli.release {
+        Some("release")
+    } else {
+        cli.profile.as_deref()
+    };
+    let info_files = if cli.blob_info.is_empty() {
+        match default_blob_info(cli.package.as_deref(), profile) {
+            Ok(path) => vec![path],
+            Err(e) => {
+                error!("Failed to find blob info: {}", e);
+                return Err(Failure::FileMissing);
+            }
+        }
+    } else {
+        cli.blob_info.clone()
+    };
+    report.blob_info = info_files.iter().map(|p| p.display().to_string()).collect();
+    let public_key = match cli.public_key.as_deref().map(read_public_key).transpose() {
+        Ok(key) => key,
+        Err(e) => {
+            error!("{}", e);
+            return Err(Failure::Other);
+        }
+    };
+    let environment = cli
+        .env
+        .clone()
+        .or_else(|| std::env::var(ENVIRONMENT_VAR).ok());
+    let mut blob_infos = Vec::new();
+    for info_file in &info_files {
+        // Read whole, the signature is of the bytes of the file
+        let mut data = Vec::new();
+        let read = if info_file.as_os_str() == "-" {
+            std::io::stdin().lock().read_to_end(&mut data)
+        } else {
+            let mut info_in = match File::open(info_file) {
+                Ok(f) => f,
+                Err(e) => {
+                    error!("Failed to open file '{}': {}", info_file.display(), e);
+                    return Err(Failure::FileMissing);
+                }
+            };
+            info_in.read_to_end(&mut data)
+        };
+        if let Err(e) = read {
+            error!("Failed to read file '{}': {}", info_file.display(), e);
+            return Err(Failure::FileMissing);
+        }
+        if let Some(key) = &public_key {
+            if let Err(e) = check_signature(key, info_file, &data, cli.require_signature) {
+                error!("{}", e);
+                return Err(Failure::Signature);
+            }
+        }
+        let read = BlobInfoFile::from_slice(&data).and_then(|b| {
+            b.validate()?;
+            Ok(b)
+        });
+        match read {
+            Ok(mut b) => {
+                if let Some(asset_root) = &cli.asset_root {
+                    b.base_dir = Some(asset_root.clone());
+                }
+                if let Some(env) = &environment {
+                    if let Err(e) = b.select_environment(env) {
+                        error!("{} in '{}'", e, info_file.display());
+                        return Err(Failure::BlobInfo);
+                    }
+                }
+                blob_infos.push(b)
+            }
+            Err(e) => {
+                error!("Failed to read file '{}': {}", info_file.display(), e);
+                return Err(Failure::BlobInfo);
+            }
+        }
+    }
+    if !cli.no_embed_toml {
+        match embed_config::read(Path::new("."), &cli.embed_profile) {
+            Ok(Some(settings)) => {
+                debug!("Using probe settings from Embed.toml");
+                for blob_info in &mut blob_infos {
+                    settings.apply_to(&mut blob_info.probe);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("{}", e);
+                return Err(Failure::Other);
+            }
+        }
+    }
+    if let Err(e) = check_blob_infos(cli, &info_files, &blob_infos) {
+        error!("{}", e);
+        return Err(Failure::BlobInfo);
+    }
+    report.notes = blob_infos
+        .iter()
+        .flat_map(annotated_blobs)
+        .map(|(name, blob)| {
+            serde_json::json!({"blob": name, "tags": blob.tags, "notes": blob.notes})
+        })
+        .collect();
+    // The blob files are what was built, and what the firmware expects, but
+    // likely not what is wanted
+    for blob_info in &blob_infos {
+        let stale = blob_info.stale_sources();
+        for (name, change) in &stale {
+            warn!(
+                "Source of blob {} has changed since the build: {}",
+                name, change
+            );
+        }
+        if !stale.is_empty() {
+            warn!("Rebuild to flash the current source files");
+        }
+    }
+    let default_command = Command::Flash(default_flash_args());
+    let command = cli.command.as_ref().unwrap_or(&default_command);
+    let single_only = match command {
+        Command::Info | Command::Verify => false,
+        Command::Flash(args) => args.via != Via::Probe || args.remote.is_some(),
+        _ => true,
+    };
+    if single_only && blob_infos.len() > 1 {
+        error!("Only flashing, verifying and info take several blob info files");
+        return Err(Failure::Other);
+    }
+    if let Command::Flash(args) = command {
+        if !args.allow_stale {
+            for (index, blob_info) in blob_infos.iter().enumerate() {
+                // The application is flashed along with the first file
+                let elf = args.elf.as_deref().filter(|_| index == 0);
+                if let Err(e) = check_build(blob_info, elf) {
+                    error!("{}", e);
+                    error!("Rebuild, or pass --allow-stale to flash anyway");
+                    return Err(failure_of(&*e, Failure::Stale));
+                }
+            }
+        }
+    }
+    let blob_info = &blob_infos[0];
+    match command {
+        Command::Info => {
+            let registry = match registry(cli) {
+                Ok(registry) => registry,
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(failure_of(&*e, Failure::Other));
+                }
+            };
+            for blob_info in &blob_infos {
+                print_info(
+                    &registry,
+                    blob_info,
+                    cli.chip.as_ref().unwrap_or(&blob_info.probe.chip),
+                );
+            }
+            return Ok(());
+        }
+        Command::Flash(args) if args.dry_run => {
+            for blob_info in &blob_infos {
+                dry_run(cli, blob_info);
+            }
+            return Ok(());
+        }
+        Command::Flash(args) if args.via == Via::Dfu => {
+            if let Err(e) = load_blob_dfu(cli, blob_info, args) {
+                error!("Failed to load blobs over DFU: {}", e);
+                return Err(failure_of(&*e, Failure::Flash));
+            }
+            return Ok(());
+        }
+        Command::Flash(args) if args.remote.is_some() => {
+            if let Err(e) = load_blob_remote(cli, blob_info, args) {
+                error!("Failed to load blobs through GDB server: {}", e);
+                return Err(failure_of(&*e, Failure::Flash));
+            }
+            return Ok(());
+        }
+        Command::Flash(args) if args.via == Via::Serial => {
+            if let Err(e) = load_blob_serial(cli, blob_info, args) {
+                error!("Failed to load blobs over serial port: {}", e);
+                return Err(failure_of(&*e, Failure::Flash));
+            }
+            return Ok(());
+        }
+        Command::Export(args) => {
+            if let Err(e) = export(cli, blob_info, args) {
+                error!("Failed to export blobs: {}", e);
+                return Err(failure_of(&*e, Failure::Other));
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+    // Held until the end of main
+    let _lock = match probe_selector(cli, &blob_info.probe)
+        .map_err(|e| failed(Failure::ProbeNotFound, e))
+        .and_then(|selector| lock_probe(cli, &selector))
+    {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("{}", e);
+            return Err(failure_of(&*e, Failure::Other));
+        }
+    };
+    let session = match attach(cli, &blob_info.probe) {
+        Ok(s) => s,
         Err(e) => {
-            eprintln!("Failed to open file '{}': {}", info_file.display(), e);
-            return ExitCode::FAILURE;
+            error!("Failed to attach to target: {}", e);
+            return Err(failure_of(&*e, Failure::Other));
         }
     };
-    let blob_info = match read_blob_info(&mut info_in) {
-	Ok(b) => b,
-	Err(e) => {
-            eprintln!("Failed to read file '{}': {}", info_file.display(), e);
-	    return ExitCode::FAILURE;
-	}
+    let options = match command {
+        Command::Flash(args) => flash_options(cli, args),
+        // Blobs are written one at a time, the rest of their sectors has to
+        // survive
+        Command::Watch { .. } => FlashOptions {
+            restore_unwritten: true,
+            ..FlashOptions::default()
+        },
+        _ => FlashOptions::default(),
     };
-    if let Err(e) = load_blob(&blob_info) {
-        eprintln!("Failed to load blobs: {} ({:?})", e, e);
-        return ExitCode::FAILURE;
+    let probe = blob_info.probe.clone();
+    let mut bars = ProgressBars::new();
+    let mut flasher = Flasher::new(session)
+        .with_core(core_index(cli, &probe))
+        .with_options(options)
+        .on_progress(move |progress| bars.handle(progress))
+        .confirm_with(move |message| confirm(cli.yes, message))
+        .reattach_with(move || attach(cli, &probe));
+    match command {
+        Command::Flash(args) => {
+            let mut timings = FlashTimings::default();
+            for (index, blob_info) in blob_infos.iter().enumerate() {
+                // The application is flashed along with the first file
+                let elf = args.elf.as_deref().filter(|_| index == 0);
+                if let Err(e) = flasher.flash(blob_info, elf, &mut timings) {
+                    error!("Failed to load blobs: {}", e);
+                    debug!("{:?}", e);
+                    return Err(failure_of(&*e, Failure::Flash));
+                }
+            }
+            report.flash = Some(timings.to_json());
+            if let Err(e) = report_timings(args, &timings) {
+                error!("Failed to write timings: {}", e);
+                return Err(Failure::Other);
+            }
+            if args.reset || args.halt || args.rtt {
+                if let Err(e) = flasher.reset(args.halt) {
+                    error!("Failed to reset target: {}", e);
+                    return Err(Failure::Other);
+                }
+            }
+            if args.rtt {
+                let core = flasher.core();
+                let verified = flasher
+                    .session()
+                    .and_then(|session| wait_rtt_report(session, core, args));
+                report.rtt_verified = verified.as_ref().ok().copied();
+                match verified {
+                    Ok(true) => {}
+                    Ok(false) => return Err(Failure::Verify),
+                    Err(e) => {
+                        error!("{}", e);
+                        return Err(failure_of(&*e, Failure::Other));
+                    }
+                }
+            }
+        }
+        Command::Verify => {
+            let mut all_match = true;
+            for (path, blob_info) in info_files.iter().zip(&blob_infos) {
+                match verify_blob(&mut flasher, blob_info) {
+                    Ok(matches) => {
+                        report.verified.push((path.display().to_string(), matches));
+                        all_match &= matches;
+                    }
+                    Err(e) => {
+                        error!("Failed to verify blobs: {}", e);
+                        debug!("{:?}", e);
+                        return Err(failure_of(&*e, Failure::Other));
+                    }
+                }
+            }
+            if !all_match {
+                return Err(Failure::Verify);
+            }
+        }
+        Command::Dump { out } => {
+            if let Err(e) = dump_blob(&mut flasher, blob_info, out) {
+                error!("Failed to dump blobs: {}", e);
+                return Err(failure_of(&*e, Failure::Other));
+            }
+        }
+        Command::Erase { only, all } => {
+            if only.is_empty() && !all {
+                error!("Select the blobs to erase with --only, or --all");
+                return Err(Failure::Other);
+            }
+            if let Err(e) = erase_blob(&mut flasher, blob_info, only, cli.yes) {
+                error!("Failed to erase blobs: {}", e);
+                return Err(failure_of(&*e, Failure::Flash));
+            }
+        }
+        Command::Watch { interval, reset } => {
+            if let Err(e) = watch_blobs(
+                &mut flasher,
+                &mut blob_infos[0],
+                Duration::from_millis(*interval),
+                *reset,
+            ) {
+                error!("Stopped watching blobs: {}", e);
+                return Err(failure_of(&*e, Failure::Other));
+            }
+        }
+        Command::Info
+        | Command::Export(_)
+        | Command::ListProbes
+        | Command::ListChips { .. }
+        | Command::Completions { .. } => {}
     }
-    ExitCode::SUCCESS
+    Ok(())
 }
@@ -0,0 +1,205 @@
+// Combined image of the blobs loaded into flash, for programming with tools
+// that don't read BlobInfo.toml. Blobs in external flash get an image of
+// their own.
+
+use crate::blob_info::{BlobInfoFile, ModifiedFile};
+use crate::blob_table;
+use std::fs;
+use std::io;
+use tracing::warn;
+
+const UF2_MAGIC_START0: u32 = 0x0a324655;
+const UF2_MAGIC_START1: u32 = 0x9e5d5157;
+const UF2_MAGIC_END: u32 = 0x0ab16f30;
+const UF2_FLAG_FAMILY_ID: u32 = 0x2000;
+const UF2_BLOCK_SIZE: usize = 512;
+const UF2_PAYLOAD_SIZE: usize = 256;
+
+pub struct BlobImage {
+    /// Flash address of the first byte
+    pub base: u32,
+    pub data: Vec<u8>,
+}
+
+impl BlobImage {
+    /// Places each (address, data) part in the image, filling the gaps
+    /// between them with fill
+    pub fn from_parts(parts: &[(u32, &[u8])], fill: u8) -> BlobImage {
+        let base = parts.iter().map(|(addr, _)| *addr).min().unwrap_or(0);
+        let end = parts
+            .iter()
+            .map(|(addr, data)| *addr + data.len() as u32)
+            .max()
+            .unwrap_or(0);
+        let mut data = vec![fill; (end - base) as usize];
+        for (addr, part) in parts {
+            let offset = (addr - base) as usize;
+            data[offset..offset + part.len()].copy_from_slice(part);
+        }
+        BlobImage { base, data }
+    }
+
+    /// Encodes the image as UF2 blocks of 256 bytes. The start is padded
+    /// with fill down to a multiple of 256, and so is the end.
+    pub fn to_uf2(&self, family_id: Option<u32>, fill: u8) -> Vec<u8> {
+        let pad = self.base as usize % UF2_PAYLOAD_SIZE;
+        let mut data = vec![fill; pad];
+        data.extend_from_slice(&self.data);
+        let block_count = data.len().div_ceil(UF2_PAYLOAD_SIZE);
+        data.resize(block_count * UF2_PAYLOAD_SIZE, fill);
+        let mut uf2 = Vec::with_capacity(block_count * UF2_BLOCK_SIZE);
+        for (index, payload) in data.chunks(UF2_PAYLOAD_SIZE).enumerate() {
+            let addr = self.base - pad as u32 + (index * UF2_PAYLOAD_SIZE) as u32;
+            let header = [
+                UF2_MAGIC_START0,
+                UF2_MAGIC_START1,
+                family_id.map(|_| UF2_FLAG_FAMILY_ID).unwrap_or(0),
+                addr,
+                UF2_PAYLOAD_SIZE as u32,
+                index as u32,
+                block_count as u32,
+                family_id.unwrap_or(0),
+            ];
+            for word in header {
+                uf2.extend_from_slice(&word.to_le_bytes());
+            }
+            uf2.extend_from_slice(payload);
+            // The data area is 476 bytes, only partly used
+            uf2.resize(uf2.len() + UF2_BLOCK_SIZE - 32 - 4 - payload.len(), 0);
+            uf2.extend_from_slice(&UF2_MAGIC_END.to_le_bytes());
+        }
+        uf2
+    }
+}
+
+/// Builds the image of the internal flash from the blob files, their
+/// padding and the blob table. Blobs in external flash are left out, see
+/// build_external. Files that don't match their checksum fail with a
+/// ModifiedFile error, unless allow_modified is set.
+pub fn build(blob_info: &BlobInfoFile, fill: u8, allow_modified: bool) -> io::Result<BlobImage> {
+    let mut parts = blob_parts(blob_info, false, allow_modified)?;
+    if let Some(table) = &blob_info.table {
+        parts.push((table.start, blob_table::encode(blob_info)));
+    }
+    Ok(image_of(&parts, fill))
+}
+
+/// Builds the image of the blobs in external flash, at the addresses the
+/// loader programs them at, or None if there are no such blobs
+pub fn build_external(
+    blob_info: &BlobInfoFile,
+    fill: u8,
+    allow_modified: bool,
+) -> io::Result<Option<BlobImage>> {
+    let parts = blob_parts(blob_info, true, allow_modified)?;
+    Ok((!parts.is_empty()).then(|| image_of(&parts, fill)))
+}
+
+fn image_of(parts: &[(u32, Vec<u8>)], fill: u8) -> BlobImage {
+    let parts: Vec<(u32, &[u8])> = parts
+        .iter()
+        .map(|(addr, data)| (*addr, data.as_slice()))
+        .collect();
+    BlobImage::from_parts(&parts, fill)
+}
+
+/// Contents and padding of the blobs in external flash, or in internal
+/// flash if external isn't set
+fn blob_parts(
+    blob_info: &BlobInfoFile,
+    external: bool,
+    allow_modified: bool,
+) -> io::Result<Vec<(u32, Vec<u8>)>> {
+    let mut parts = Vec::new();
+    for (name, blob) in &blob_info.info {
+        if blob.external.is_some() != external {
+            continue;
+        }
+        let path = blob_info.blob_path(blob);
+        let data = fs::read(&path)?;
+        if data.len() != blob.size as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Size of '{}' for blob {} has changed since the build",
+                    path.display(),
+                    name
+                ),
+            ));
+        }
+        blob.check_encrypted(&data)
+            .and_then(|()| blob.check_compressed(&data))
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("'{}' for blob {}: {}", path.display(), name, e),
+                )
+            })?;
+        if !blob.check_data(&data) {
+            let modified = ModifiedFile {
+                path,
+                blob: name.clone(),
+            };
+            if !allow_modified {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, modified));
+            }
+            warn!("{}", modified);
+        }
+        let (Ok(start), Ok(end)) = (
+            u32::try_from(blob.load_address()),
+            u32::try_from(blob.load_address() + blob.size),
+        ) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Blob {} at 0x{:x} is beyond the 32 bit addresses of an image",
+                    name,
+                    blob.load_address()
+                ),
+            ));
+        };
+        // The recorded padding makes the image match what is in flash,
+        // including the padding after the last blob
+        if let Some(padding) = &blob.padding {
+            parts.push((
+                start - padding.before,
+                vec![padding.fill; padding.before as usize],
+            ));
+            parts.push((end, vec![padding.fill; padding.after as usize]));
+        }
+        parts.push((start, data));
+    }
+    Ok(parts)
+}
+
+#[test]
+fn test_from_parts() {
+    let image = BlobImage::from_parts(&[(0x1008, &[3, 4]), (0x1000, &[1, 2])], 0xff);
+    assert_eq!(image.base, 0x1000);
+    assert_eq!(image.data, [1, 2, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 3, 4]);
+    assert!(BlobImage::from_parts(&[], 0).data.is_empty());
+}
+
+#[test]
+fn test_to_uf2() {
+    let image = BlobImage::from_parts(&[(0x10000080, &[7; 300])], 0xff);
+    let uf2 = image.to_uf2(Some(0xe48bff56), 0xff);
+    assert_eq!(uf2.len(), 2 * UF2_BLOCK_SIZE);
+    let word = |block: usize, i: usize| {
+        let pos = block * UF2_BLOCK_SIZE + i * 4;
+        u32::from_le_bytes([uf2[pos], uf2[pos + 1], uf2[pos + 2], uf2[pos + 3]])
+    };
+    assert_eq!(word(0, 0), UF2_MAGIC_START0);
+    assert_eq!(word(0, 2), UF2_FLAG_FAMILY_ID);
+    assert_eq!(word(0, 3), 0x10000000);
+    assert_eq!(word(1, 3), 0x10000100);
+    assert_eq!(word(1, 5), 1);
+    assert_eq!(word(1, 6), 2);
+    assert_eq!(word(1, 7), 0xe48bff56);
+    assert_eq!(word(1, 127), UF2_MAGIC_END);
+    // Padded before and after the image
+    assert_eq!(uf2[32 + 0x7f], 0xff);
+    assert_eq!(uf2[32 + 0x80], 7);
+    assert_eq!(uf2[UF2_BLOCK_SIZE + 32 + 0xab], 7);
+    assert_eq!(uf2[UF2_BLOCK_SIZE + 32 + 0xac], 0xff);
+}
@@ -0,0 +1,112 @@
+// Records describing each blob that the generated code places in the
+// .blob_index section of the program, so that tools can tell which blobs a
+// binary expects by reading the section from the ELF file. The generated
+// memory.x keeps the section, which takes no space in flash.
+//
+// Layout of each record, all values little endian:
+//   magic: u32, name_hash: u32, addr: u32, size: u32, flags: u32,
+//   checksum: [u8; 20], name: [u8; 32]
+//
+// The name is padded with zeros and truncated if longer than 32 bytes.
+
+use crate::blob_table::name_hash;
+
+pub const SECTION: &str = ".blob_index";
+pub const MAGIC: u32 = 0x58444942;
+pub const RECORD_SIZE: usize = 72;
+pub const NAME_SIZE: usize = 32;
+
+/// The blob is included in the executable and addr is 0
+pub const FLAG_INLINE: u32 = 1;
+/// The blob is read through BlobStorage and addr is in its address space
+pub const FLAG_STORAGE: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexRecord {
+    pub name_hash: u32,
+    pub addr: u32,
+    pub size: u32,
+    pub flags: u32,
+    pub checksum: [u8; 20],
+    pub name: String,
+}
+
+impl IndexRecord {
+    pub fn new(name: &str, addr: u32, size: u32, flags: u32, checksum: [u8; 20]) -> IndexRecord {
+        IndexRecord {
+            name_hash: name_hash(name),
+            addr,
+            size,
+            flags,
+            checksum,
+            name: name.to_string(),
+        }
+    }
+
+    /// Name as stored in the record
+    pub fn name_bytes(&self) -> [u8; NAME_SIZE] {
+        let mut bytes = [0u8; NAME_SIZE];
+        let len = self.name.len().min(NAME_SIZE);
+        bytes[..len].copy_from_slice(&self.name.as_bytes()[..len]);
+        bytes
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(RECORD_SIZE);
+        for word in [MAGIC, self.name_hash, self.addr, self.size, self.flags] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        data.extend_from_slice(&self.checksum);
+        data.extend_from_slice(&self.name_bytes());
+        data
+    }
+}
+
+/// Decodes the contents of a .blob_index section. Returns None if the
+/// data doesn't consist of valid records.
+pub fn decode(section: &[u8]) -> Option<Vec<IndexRecord>> {
+    if !section.len().is_multiple_of(RECORD_SIZE) {
+        return None;
+    }
+    section
+        .chunks(RECORD_SIZE)
+        .map(|record| {
+            let word = |i: usize| {
+                u32::from_le_bytes([record[i], record[i + 1], record[i + 2], record[i + 3]])
+            };
+            if word(0) != MAGIC {
+                return None;
+            }
+            let mut checksum = [0u8; 20];
+            checksum.copy_from_slice(&record[20..40]);
+            let name = &record[40..RECORD_SIZE];
+            let name_len = name.iter().position(|&b| b == 0).unwrap_or(NAME_SIZE);
+            Some(IndexRecord {
+                name_hash: word(4),
+                addr: word(8),
+                size: word(12),
+                flags: word(16),
+                checksum,
+                name: String::from_utf8_lossy(&name[..name_len]).into_owned(),
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn test_encode_decode() {
+    let records = [
+        IndexRecord::new("logo", 0x10080000, 1234, 0, [7; 20]),
+        IndexRecord::new("font", 0, 16, FLAG_INLINE, [9; 20]),
+    ];
+    let mut section = Vec::new();
+    for record in &records {
+        let data = record.encode();
+        assert_eq!(data.len(), RECORD_SIZE);
+        section.extend_from_slice(&data);
+    }
+    assert_eq!(decode(&section).unwrap(), records);
+    assert_eq!(decode(&section[1..]), None);
+    section[0] = 0;
+    assert_eq!(decode(&section), None);
+}
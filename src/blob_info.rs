@@ -1,22 +1,1727 @@
+use crate::compress::Codec;
+use crate::crc32::Crc32;
+use serde::{Deserializer, Serializer};
 use serde_derive::{Deserialize, Serialize};
-use std::collections::HashMap;
+use sha1_smol::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::UNIX_EPOCH;
 
-#[derive(Serialize, Deserialize)]
+type DynResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// Keys this version doesn't know, e.g. written by a newer builder. They are
+/// kept as read and written back, so that passing a file through an older
+/// tool doesn't lose them.
+pub type UnknownFields = BTreeMap<String, serde_json::Value>;
+
+/// Printed over RTT by the firmware when verify_all succeeds
+pub const VERIFY_OK_MARKER: &str = "BLOB_VERIFY_OK";
+/// Printed over RTT by the firmware when verify_all fails, followed by the
+/// names of the failed blobs
+pub const VERIFY_FAIL_MARKER: &str = "BLOB_VERIFY_FAIL";
+/// Symbol of the build id in the firmware, a static defined by the
+/// generated blob.rs
+pub const BUILD_ID_SYMBOL: &str = "BLOB_BUILD_ID";
+
+/// Version of the blob info format written by this crate, as major and
+/// minor version. The minor version is increased for additions that older
+/// loaders can ignore, the major version for changes they would
+/// misinterpret.
+pub const FORMAT_VERSION: (u32, u32) = (3, 7);
+
+/// Oldest major version still read. Version 1 files have SHA-1 checksums
+/// as arrays of 20 integers.
+const OLDEST_FORMAT_VERSION: u32 = 1;
+
+/// Files written before the version was added
+fn default_format_version() -> String {
+    "1.0".to_string()
+}
+
+/// Fails if a file of the given format version can't be read by this
+/// crate. Newer minor versions are accepted, their additions are ignored.
+pub fn check_format_version(version: &str) -> Result<(), String> {
+    let (major, minor) = version
+        .split_once('.')
+        .and_then(|(major, minor)| Some((major.parse::<u32>().ok()?, minor.parse::<u32>().ok()?)))
+        .ok_or_else(|| format!("Invalid format version '{}'", version))?;
+    if major > FORMAT_VERSION.0 {
+        return Err(format!(
+            "Blob info format {}.{} is from a newer version of blob_loader, this one reads {}.x",
+            major, minor, FORMAT_VERSION.0
+        ));
+    }
+    if major < OLDEST_FORMAT_VERSION {
+        return Err(format!(
+            "Blob info format {}.{} is no longer supported, rebuild with this version of blob_loader",
+            major, minor
+        ));
+    }
+    Ok(())
+}
+
+/// Algorithm of a blob checksum
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    Crc32,
+    Sha1,
+    Sha256,
+}
+
+impl DigestAlgorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            DigestAlgorithm::Crc32 => "crc32",
+            DigestAlgorithm::Sha1 => "sha1",
+            DigestAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    /// Length of the digest in bytes
+    pub fn digest_len(self) -> usize {
+        match self {
+            DigestAlgorithm::Crc32 => 4,
+            DigestAlgorithm::Sha1 => 20,
+            DigestAlgorithm::Sha256 => 32,
+        }
+    }
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            DigestAlgorithm::Crc32 => Hasher::Crc32(Crc32::new()),
+            DigestAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            DigestAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+}
+
+impl FromStr for DigestAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            DigestAlgorithm::Crc32,
+            DigestAlgorithm::Sha1,
+            DigestAlgorithm::Sha256,
+        ]
+        .into_iter()
+        .find(|a| a.name() == s)
+        .ok_or_else(|| format!("Unknown checksum algorithm '{}'", s))
+    }
+}
+
+/// Calculates a checksum from data passed in pieces
+pub enum Hasher {
+    Crc32(Crc32),
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Crc32(crc) => crc.update(data),
+            Hasher::Sha1(sha1) => sha1.update(data),
+            Hasher::Sha256(sha256) => sha256.update(data),
+        }
+    }
+
+    pub fn finish(self) -> Checksum {
+        match self {
+            Hasher::Crc32(crc) => Checksum::crc32(crc.value()),
+            Hasher::Sha1(sha1) => Checksum::sha1(sha1.digest().bytes()),
+            Hasher::Sha256(sha256) => Checksum {
+                algorithm: DigestAlgorithm::Sha256,
+                digest: sha256.finalize().to_vec(),
+            },
+        }
+    }
+}
+
+/// Checksum of a blob, written as the algorithm and the digest in hex, e.g.
+/// "crc32:cbf43926"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    pub algorithm: DigestAlgorithm,
+    pub digest: Vec<u8>,
+}
+
+impl Checksum {
+    /// Fails if the digest isn't as long as the algorithm makes them
+    pub fn validate(&self) -> Result<(), String> {
+        if self.digest.len() != self.algorithm.digest_len() {
+            return Err(format!(
+                "A {} checksum has {} bytes, not {}",
+                self.algorithm.name(),
+                self.algorithm.digest_len(),
+                self.digest.len()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn sha1(digest: [u8; 20]) -> Checksum {
+        Checksum {
+            algorithm: DigestAlgorithm::Sha1,
+            digest: digest.to_vec(),
+        }
+    }
+
+    /// The digest is the value in big endian, so that the hex string reads
+    /// like the number
+    pub fn crc32(value: u32) -> Checksum {
+        Checksum {
+            algorithm: DigestAlgorithm::Crc32,
+            digest: value.to_be_bytes().to_vec(),
+        }
+    }
+
+    pub fn of(algorithm: DigestAlgorithm, data: &[u8]) -> Checksum {
+        let mut hasher = algorithm.hasher();
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    /// The 20 bytes stored in a blob table entry, the digest truncated or
+    /// padded with zeros
+    pub fn table_bytes(&self) -> [u8; 20] {
+        let mut bytes = [0u8; 20];
+        let len = self.digest.len().min(20);
+        bytes[..len].copy_from_slice(&self.digest[..len]);
+        bytes
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:", self.algorithm.name())?;
+        for b in &self.digest {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algorithm, hex) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Checksum '{}' has no algorithm", s))?;
+        let algorithm: DigestAlgorithm = algorithm.parse()?;
+        if hex.len() != algorithm.digest_len() * 2 {
+            return Err(format!(
+                "A {} checksum has {} hex digits",
+                algorithm.name(),
+                algorithm.digest_len() * 2
+            ));
+        }
+        let digest = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2).unwrap_or("-"), 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| format!("Invalid hex digits in checksum '{}'", s))?;
+        Ok(Checksum { algorithm, digest })
+    }
+}
+
+impl serde::Serialize for Checksum {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Checksum {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Text(String),
+            // Format version 1
+            Sha1([u8; 20]),
+        }
+        match Repr::deserialize(deserializer)? {
+            Repr::Text(text) => text.parse().map_err(serde::de::Error::custom),
+            Repr::Sha1(digest) => Ok(Checksum::sha1(digest)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct BlobInfo {
-    pub start: u32,
-    pub size: u32,
-    pub checksum: [u8; 20],
+    /// Up to format version 3.6 start and size were at most 32 bits
+    pub start: u64,
+    pub size: u64,
+    pub checksum: Checksum,
     pub filename: String,
+    /// Memory region the blob is in, e.g. "FLASH" from memory.x. Added in
+    /// format version 3.7.
+    #[serde(default)]
+    pub region: Option<String>,
+    /// Labels from the blob configuration, e.g. "fcc-test-image". Added in
+    /// format version 3.5, like notes.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Free form remarks from the blob configuration, e.g. "do not ship"
+    #[serde(default)]
+    pub notes: Option<String>,
+    /// Set for blobs in external flash, e.g. QSPI flash in the storage
+    /// address space
+    #[serde(default)]
+    pub external: Option<ExternalFlash>,
+    /// Set for blobs stored compressed. The size and checksum above are of
+    /// the compressed data, which is what is in flash.
+    #[serde(default)]
+    pub compression: Option<CompressionInfo>,
+    /// Set for blobs stored encrypted. Like with compression, the size and
+    /// checksum are of what is in flash, the ciphertext. Added in format
+    /// version 3.6.
+    #[serde(default)]
+    pub encryption: Option<EncryptionInfo>,
+    #[serde(default)]
+    pub padding: Option<Padding>,
+    /// Added in format version 3.1
+    #[serde(default)]
+    pub source: Option<SourceInfo>,
+    #[serde(flatten)]
+    pub extra: UnknownFields,
+}
+
+impl BlobInfo {
+    /// Blob in memory mapped flash, without compression, padding or source
+    pub fn new(start: u64, size: u64, checksum: Checksum, filename: impl Into<String>) -> BlobInfo {
+        BlobInfo {
+            start,
+            size,
+            checksum,
+            filename: filename.into(),
+            region: None,
+            tags: Vec::new(),
+            notes: None,
+            external: None,
+            compression: None,
+            encryption: None,
+            padding: None,
+            source: None,
+            extra: UnknownFields::new(),
+        }
+    }
+
+    /// Checks that the data of an encrypted blob is the ciphertext its
+    /// checksum is of, so that e.g. a plaintext file in its place isn't
+    /// programmed. Other blobs aren't checked.
+    pub fn check_encrypted(&self, data: &[u8]) -> Result<(), String> {
+        match &self.encryption {
+            Some(encryption) if Checksum::of(self.checksum.algorithm, data) != self.checksum => {
+                Err(format!(
+                    "The data doesn't match the checksum of the ciphertext encrypted with key '{}'",
+                    encryption.key_id
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that data is what was built for the blob. A signature of the
+    /// blob info file only covers the blob files through this.
+    pub fn check_data(&self, data: &[u8]) -> bool {
+        Checksum::of(self.checksum.algorithm, data) == self.checksum
+    }
+
+    /// Checks that the data of a compressed blob decompresses to what the
+    /// firmware expects. Encrypted blobs and blobs compressed with an
+    /// algorithm that isn't known aren't checked.
+    pub fn check_compressed(&self, data: &[u8]) -> Result<(), String> {
+        let Some(compression) = &self.compression else {
+            return Ok(());
+        };
+        let Some(codec) = Codec::from_name(&compression.algorithm) else {
+            return Ok(());
+        };
+        if self.encryption.is_some() {
+            return Ok(());
+        }
+        let decompressed = codec
+            .decompress(data, compression.uncompressed_size as usize)
+            .map_err(|e| format!("Failed to decompress with {}: {}", codec.name(), e))?;
+        let checksum = Checksum::of(compression.uncompressed_checksum.algorithm, &decompressed);
+        if checksum != compression.uncompressed_checksum {
+            return Err(format!(
+                "The data decompressed with {} doesn't match its checksum",
+                codec.name()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Address the loader reads and writes the blob at
+    pub fn load_address(&self) -> u64 {
+        self.external
+            .as_ref()
+            .map_or(self.start, |e| e.load_address)
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+/// How the loader programs a blob in external flash
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ExternalFlash {
+    /// Address of the blob in the memory mapped alias of the flash
+    pub load_address: u64,
+    /// Name of the probe-rs flash algorithm to use, if the target has
+    /// several for the region
+    #[serde(default)]
+    pub algorithm: Option<String>,
+}
+
+/// Padding around a blob in flash. Each gap between blobs is the padding
+/// before the following blob, only the last blob of a region has padding
+/// after it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Padding {
+    /// Alignment of the start address
+    pub align: u32,
+    /// Bytes from the end of the previous blob, or the start of the region
+    pub before: u32,
+    /// Bytes up to the end of the region
+    pub after: u32,
+    /// Value the padding reads as
+    pub fill: u8,
+}
+
+/// A blob file doesn't match the checksum in the blob info file, e.g.
+/// because it was replaced after the build
+#[derive(Debug)]
+pub struct ModifiedFile {
+    pub path: PathBuf,
+    pub blob: String,
+}
+
+impl fmt::Display for ModifiedFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "'{}' for blob {} doesn't match the checksum from the build",
+            self.path.display(),
+            self.blob
+        )
+    }
+}
+
+impl std::error::Error for ModifiedFile {}
+
+/// Fingerprint of the file a blob was built from, to tell if it has
+/// changed since. Directory blobs have none.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SourceInfo {
+    /// Relative to base_dir, like the blob file name
+    pub filename: String,
+    pub size: u64,
+    /// Seconds since the Unix epoch
+    #[serde(default)]
+    pub modified: Option<u64>,
+    /// Of the file as read, before any compression
+    pub checksum: Checksum,
+}
+
+fn modified_secs(metadata: &fs::Metadata) -> Option<u64> {
+    let modified = metadata.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+impl SourceInfo {
+    /// Fingerprint of the file at path
+    pub fn of(path: &Path) -> io::Result<SourceInfo> {
+        let data = fs::read(path)?;
+        Ok(SourceInfo {
+            filename: path.to_string_lossy().into_owned(),
+            size: data.len() as u64,
+            modified: modified_secs(&fs::metadata(path)?),
+            checksum: Checksum::of(DigestAlgorithm::Sha1, &data),
+        })
+    }
+
+    /// Why the file at path no longer matches the fingerprint, or None if
+    /// it does. The contents are only read if the modification time
+    /// differs.
+    pub fn changed(&self, path: &Path) -> Option<String> {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) => return Some(format!("can't be read: {}", e)),
+        };
+        if metadata.len() != self.size {
+            return Some(format!(
+                "is {} bytes, was {} bytes",
+                metadata.len(),
+                self.size
+            ));
+        }
+        if self.modified.is_some() && modified_secs(&metadata) == self.modified {
+            return None;
+        }
+        match fs::read(path) {
+            Ok(data) if Checksum::of(self.checksum.algorithm, &data) == self.checksum => None,
+            Ok(_) => Some("has different contents".to_string()),
+            Err(e) => Some(format!("can't be read: {}", e)),
+        }
+    }
+}
+
+/// How a blob was compressed, and what it decompresses to
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CompressionInfo {
+    /// Compression algorithm, e.g. "lz4"
+    pub algorithm: String,
+    pub uncompressed_size: u32,
+    pub uncompressed_checksum: Checksum,
+}
+
+/// How a blob was encrypted. Only which key was used is recorded, never
+/// the key itself.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EncryptionInfo {
+    /// Cipher, e.g. "aes-128-ctr"
+    pub cipher: String,
+    /// Name of the key, for the firmware or the provisioning to find it by
+    pub key_id: String,
+    /// Nonce or initialization vector, written in hex
+    #[serde(with = "hex_bytes")]
+    pub nonce: Vec<u8>,
+}
+
+impl EncryptionInfo {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.cipher.is_empty() {
+            return Err("No cipher given".to_string());
+        }
+        if self.key_id.is_empty() {
+            return Err("No key id given".to_string());
+        }
+        if self.nonce.is_empty() {
+            return Err("No nonce given".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Bytes as a string of hex digits
+mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(
+            &bytes
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>(),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        if text.len() % 2 != 0 {
+            return Err(serde::de::Error::custom("Odd number of hex digits"));
+        }
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(text.get(i..i + 2).unwrap_or("-"), 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| serde::de::Error::custom(format!("Invalid hex digits in '{}'", text)))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct ProbeInfo {
     pub chip: String,
+    /// Probe to use, as VID:PID or VID:PID:SERIAL
+    #[serde(default)]
+    pub selector: Option<String>,
+    /// Wire protocol, swd or jtag
+    #[serde(default)]
+    pub protocol: Option<String>,
+    #[serde(default)]
+    pub speed_khz: Option<u32>,
+    /// Hold nRESET asserted while attaching
+    #[serde(default)]
+    pub connect_under_reset: bool,
+    /// Pulse nRESET before attaching
+    #[serde(default)]
+    pub reset_before_attach: bool,
+    /// Core with access to the flash, as an index in the target description
+    #[serde(default)]
+    pub core: Option<usize>,
+    /// Access port of that core, overriding the target description
+    #[serde(default)]
+    pub ap: Option<u8>,
+    #[serde(flatten)]
+    pub extra: UnknownFields,
+}
+
+impl ProbeInfo {
+    /// Settings for chip, with the rest left to the loader
+    pub fn new(chip: impl Into<String>) -> ProbeInfo {
+        ProbeInfo {
+            chip: chip.into(),
+            selector: None,
+            protocol: None,
+            speed_khz: None,
+            connect_under_reset: false,
+            reset_before_attach: false,
+            core: None,
+            ap: None,
+            extra: UnknownFields::new(),
+        }
+    }
+
+    /// Checks the settings the loader parses, so that mistakes show up when
+    /// building rather than when flashing
+    pub fn validate(&self) -> Result<(), String> {
+        if self.chip.is_empty() {
+            return Err("No chip given".to_string());
+        }
+        if let Some(selector) = &self.selector {
+            let parts: Vec<&str> = selector.splitn(3, ':').collect();
+            let hex_id = |s: &str| u16::from_str_radix(s, 16).is_ok();
+            if parts.len() < 2 || !hex_id(parts[0]) || !hex_id(parts[1]) {
+                return Err(format!(
+                    "Probe selector '{}' isn't VID:PID or VID:PID:SERIAL",
+                    selector
+                ));
+            }
+        }
+        if let Some(protocol) = &self.protocol {
+            if !["swd", "jtag"].contains(&protocol.to_ascii_lowercase().as_str()) {
+                return Err(format!("Protocol '{}' isn't swd or jtag", protocol));
+            }
+        }
+        if self.speed_khz == Some(0) {
+            return Err("Probe speed can't be 0 kHz".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Location of the blob table written by the loader
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TableInfo {
+    pub start: u32,
+    pub size: u32,
+}
+
+/// Which build the file is from
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BuildInfo {
+    pub package: String,
+    pub version: String,
+    pub profile: String,
+    /// Output of git describe, if built from a git checkout
+    #[serde(default)]
+    pub git: Option<String>,
+    /// Seconds since the Unix epoch, only recorded if enabled
+    #[serde(default)]
+    pub timestamp: Option<u64>,
 }
 
-#[derive(Serialize,Deserialize)]
+/// Digests of the files the build script generates along with the blob
+/// info file, to tell if they are from the same build
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ArtifactInfo {
+    /// OUT_DIR of the build script, where the files are
+    pub out_dir: PathBuf,
+    /// Of the generated linker script
+    pub link_script: Checksum,
+    /// Of the generated source, including the build id
+    pub source: Checksum,
+    /// Of the source generated for each group, by file name in out_dir
+    #[serde(default)]
+    pub group_files: BTreeMap<String, Checksum>,
+    /// Also in the firmware, as BUILD_ID_SYMBOL
+    pub build_id: Checksum,
+}
+
+impl ArtifactInfo {
+    /// Name of the generated linker script in out_dir
+    pub const LINK_SCRIPT: &'static str = "memory.x";
+    /// Name of the generated source in out_dir
+    pub const SOURCE: &'static str = "blob.rs";
+
+    /// Build id of the generated linker script and source, the latter
+    /// before the build id is added to it, followed by the group files in
+    /// order of file name
+    pub fn build_id(link_script: &[u8], source: &[u8], group_files: &[&[u8]]) -> Checksum {
+        let mut hasher = DigestAlgorithm::Sha1.hasher();
+        for data in [link_script, source]
+            .into_iter()
+            .chain(group_files.iter().copied())
+        {
+            hasher.update(&(data.len() as u64).to_le_bytes());
+            hasher.update(data);
+        }
+        hasher.finish()
+    }
+
+    /// Generated files that have changed since, e.g. by a later build that
+    /// failed before writing the blob info file. Files that can't be read,
+    /// like after cargo clean or on another machine, don't tell.
+    pub fn changed(&self) -> Vec<&str> {
+        [
+            (Self::LINK_SCRIPT, &self.link_script),
+            (Self::SOURCE, &self.source),
+        ]
+        .into_iter()
+        .chain(
+            self.group_files
+                .iter()
+                .map(|(name, checksum)| (name.as_str(), checksum)),
+        )
+        .filter(|(name, checksum)| {
+            fs::read(self.out_dir.join(name))
+                .is_ok_and(|data| Checksum::of(checksum.algorithm, &data) != **checksum)
+        })
+        .map(|(name, _)| name)
+        .collect()
+    }
+}
+
+/// Flash region of the application, from memory.x
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AppInfo {
+    pub start: u32,
+    pub size: u32,
+}
+
+/// Size of the sectors from offset up to the next SectorSize, or the end
+/// of the flash
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SectorSize {
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Erase sectors of one flash, as in the target description
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SectorMap {
+    pub start: u64,
+    pub end: u64,
+    /// Sorted by offset
+    pub sectors: Vec<SectorSize>,
+}
+
+impl SectorMap {
+    pub fn contains(&self, addr: u64) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+
+    /// Address ranges of all the sectors
+    pub fn sectors(&self) -> Vec<Range<u64>> {
+        let mut sectors = Vec::new();
+        for (i, sector) in self.sectors.iter().enumerate() {
+            let region_end = self
+                .sectors
+                .get(i + 1)
+                .map(|next| self.start + next.offset)
+                .unwrap_or(self.end);
+            let mut addr = self.start + sector.offset;
+            // A size of 0 would never reach the end
+            while sector.size > 0 && addr < region_end {
+                sectors.push(addr..addr + sector.size);
+                addr += sector.size;
+            }
+        }
+        sectors
+    }
+}
+
+/// Erase sectors of the flash of a chip, so that sector boundaries are
+/// known without the target description
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FlashGeometry {
+    /// Chip the target description is for
+    pub chip: String,
+    /// One map for each flash, e.g. internal and QSPI
+    pub flash: Vec<SectorMap>,
+}
+
+/// Start and size of a part of flash
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Window {
+    pub start: u32,
+    pub size: u32,
+}
+
+impl Window {
+    pub fn range(&self) -> Range<u64> {
+        self.start as u64..self.start as u64 + self.size as u64
+    }
+}
+
+/// Memory map of the FLASH region in memory.x
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LayoutInfo {
+    /// FLASH as given in memory.x
+    pub flash: Window,
+    /// What is left of FLASH for the application
+    pub app: Window,
+    /// The end of FLASH, taken by the blob table and the loaded blobs
+    pub blobs: Window,
+    /// Unused parts of the blob window, sorted by address. Left out when
+    /// empty, TOML can't have an empty array after the tables.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gaps: Vec<Window>,
+}
+
+impl LayoutInfo {
+    /// Finds the gaps in the blob window between the used ranges. Ranges
+    /// outside the window, like those of storage blobs, are ignored.
+    pub fn new(flash: Window, app: Window, blobs: Window, used: &[Range<u64>]) -> LayoutInfo {
+        let window = blobs.range();
+        let mut used: Vec<Range<u64>> = used
+            .iter()
+            .map(|r| r.start.max(window.start)..r.end.min(window.end))
+            .filter(|r| r.start < r.end)
+            .collect();
+        used.sort_by_key(|r| r.start);
+        let mut gaps = Vec::new();
+        let mut pos = window.start;
+        for range in used.iter().chain([&(window.end..window.end)]) {
+            if range.start > pos {
+                gaps.push(Window {
+                    start: pos as u32,
+                    size: (range.start - pos) as u32,
+                });
+            }
+            pos = pos.max(range.end);
+        }
+        LayoutInfo {
+            flash,
+            app,
+            blobs,
+            gaps,
+        }
+    }
+
+    /// Checks that a write of the named blob, or the blob table, stays out
+    /// of the application and within the blob window if it's in FLASH
+    pub fn check_write(&self, name: &str, range: &Range<u64>) -> Result<(), String> {
+        let overlaps = |window: &Window| {
+            let window = window.range();
+            range.start < window.end && range.end > window.start
+        };
+        let inside = |window: &Window| {
+            let window = window.range();
+            range.start >= window.start && range.end <= window.end
+        };
+        if overlaps(&self.app) || (overlaps(&self.flash) && !inside(&self.blobs)) {
+            return Err(format!(
+                "Writing {} at 0x{:08x}-0x{:08x} is outside the blob region 0x{:08x}-0x{:08x}",
+                name,
+                range.start,
+                range.end.saturating_sub(1),
+                self.blobs.range().start,
+                self.blobs.range().end.saturating_sub(1)
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct BlobInfoFile {
-    pub info: HashMap<String, BlobInfo>,
+    /// Format version as "major.minor", see FORMAT_VERSION
+    #[serde(default = "default_format_version")]
+    pub format_version: String,
+    /// Directory the blob file names are relative to, on the machine that
+    /// built them. Added in format version 3.0, before that the names were
+    /// absolute. Ahead of the tables, which TOML requires.
+    #[serde(default)]
+    pub base_dir: Option<PathBuf>,
+    /// Sorted by name, like all maps in the file, so that the same build
+    /// writes the same file
+    pub info: BTreeMap<String, BlobInfo>,
     pub probe: ProbeInfo,
+    /// Named probe settings replacing probe, e.g. for a development kit and
+    /// a production jig. Added in format version 3.2.
+    #[serde(default)]
+    pub environments: BTreeMap<String, ProbeInfo>,
+    pub table: Option<TableInfo>,
+    #[serde(default)]
+    pub app: Option<AppInfo>,
+    /// Added in format version 2.1
+    #[serde(default)]
+    pub build: Option<BuildInfo>,
+    /// Added in format version 2.4
+    #[serde(default)]
+    pub layout: Option<LayoutInfo>,
+    /// Added in format version 3.3
+    #[serde(default)]
+    pub geometry: Option<FlashGeometry>,
+    /// Added in format version 3.4
+    #[serde(default)]
+    pub artifacts: Option<ArtifactInfo>,
+    #[serde(flatten)]
+    pub extra: UnknownFields,
+}
+
+impl BlobInfoFile {
+    /// Replaces the probe settings with those of the named environment
+    pub fn select_environment(&mut self, name: &str) -> Result<(), String> {
+        let Some(probe) = self.environments.get(name) else {
+            let mut names: Vec<&str> = self.environments.keys().map(String::as_str).collect();
+            names.sort();
+            return Err(if names.is_empty() {
+                format!("No environment named '{}', there are none", name)
+            } else {
+                format!(
+                    "No environment named '{}', there are {}",
+                    name,
+                    names.join(", ")
+                )
+            });
+        };
+        self.probe = probe.clone();
+        Ok(())
+    }
+
+    /// Recorded erase sectors, if they are for the chip of the probe
+    /// settings
+    pub fn sector_maps(&self) -> Option<&[SectorMap]> {
+        self.geometry
+            .as_ref()
+            .filter(|g| g.chip.eq_ignore_ascii_case(&self.probe.chip))
+            .map(|g| g.flash.as_slice())
+    }
+
+    /// Address ranges the loader writes, of the blobs and the blob table
+    pub fn regions(&self) -> Vec<(&str, Range<u64>)> {
+        let mut regions: Vec<(&str, Range<u64>)> = self
+            .info
+            .iter()
+            .map(|(name, blob)| {
+                let start = blob.load_address();
+                (name.as_str(), start..start + blob.size)
+            })
+            .collect();
+        if let Some(table) = &self.table {
+            let start = table.start as u64;
+            regions.push(("blob table", start..start + table.size as u64));
+        }
+        regions
+    }
+
+    /// Path of the file of a blob. Absolute file names are used as is.
+    pub fn blob_path(&self, blob: &BlobInfo) -> PathBuf {
+        self.path(&blob.filename)
+    }
+
+    /// Path of a file name recorded relative to base_dir
+    pub fn path(&self, filename: &str) -> PathBuf {
+        match &self.base_dir {
+            Some(base_dir) => base_dir.join(filename),
+            None => PathBuf::from(filename),
+        }
+    }
+
+    /// Blobs whose source file has changed since the build, with how
+    pub fn stale_sources(&self) -> Vec<(&str, String)> {
+        let mut stale: Vec<(&str, String)> = self
+            .info
+            .iter()
+            .filter_map(|(name, blob)| {
+                let source = blob.source.as_ref()?;
+                let path = self.path(&source.filename);
+                let change = source.changed(&path)?;
+                Some((name.as_str(), format!("'{}' {}", path.display(), change)))
+            })
+            .collect();
+        stale.sort();
+        stale
+    }
+
+    /// Makes the blob and source file names relative to the deepest
+    /// directory holding all of them, and records it as base_dir. File
+    /// names that are already relative are left alone.
+    pub fn make_relative(&mut self) {
+        let mut absolute = self
+            .info
+            .values()
+            .flat_map(|blob| {
+                [
+                    Some(&blob.filename),
+                    blob.source.as_ref().map(|s| &s.filename),
+                ]
+            })
+            .flatten()
+            .map(Path::new)
+            .filter(|path| path.is_absolute());
+        let Some(mut base_dir) = absolute.next().and_then(Path::parent) else {
+            return;
+        };
+        for path in absolute {
+            while !path.starts_with(base_dir) {
+                let Some(parent) = base_dir.parent() else {
+                    break;
+                };
+                base_dir = parent;
+            }
+        }
+        let base_dir = base_dir.to_path_buf();
+        let relative = |filename: &mut String| {
+            if let Ok(relative) = Path::new(filename.as_str()).strip_prefix(&base_dir) {
+                *filename = relative.to_string_lossy().into_owned();
+            }
+        };
+        for blob in self.info.values_mut() {
+            relative(&mut blob.filename);
+            if let Some(source) = &mut blob.source {
+                relative(&mut source.filename);
+            }
+        }
+        self.base_dir = Some(base_dir);
+    }
+}
+
+/// Format of a blob info file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileFormat {
+    Toml,
+    Json,
+}
+
+impl FileFormat {
+    /// Format of a file by its name, TOML unless it ends with .json
+    pub fn of_path(path: &Path) -> FileFormat {
+        match path.extension() {
+            Some(extension) if extension == "json" => FileFormat::Json,
+            _ => FileFormat::Toml,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            FileFormat::Toml => "toml",
+            FileFormat::Json => "json",
+        }
+    }
+}
+
+impl BlobInfoFile {
+    /// Parses a blob info file in TOML or JSON, told apart by the first
+    /// character. The format version is checked first, a file of a newer
+    /// format may not parse.
+    pub fn from_slice(data: &[u8]) -> DynResult<BlobInfoFile> {
+        let text = std::str::from_utf8(data)?;
+        if text.trim_start().starts_with('{') {
+            let value: serde_json::Value = serde_json::from_str(text)?;
+            if let Some(version) = value.get("format_version") {
+                let version = version.as_str().ok_or("format_version isn't a string")?;
+                check_format_version(version)?;
+            }
+            return Ok(serde_json::from_value(value)?);
+        }
+        let value: toml::Value = toml::from_str(text)?;
+        if let Some(version) = value.get("format_version") {
+            let version = version.as_str().ok_or("format_version isn't a string")?;
+            check_format_version(version)?;
+        }
+        Ok(value.try_into()?)
+    }
+
+    /// Reads a blob info file in either format
+    pub fn load(path: &Path) -> DynResult<BlobInfoFile> {
+        BlobInfoFile::from_slice(&fs::read(path)?)
+    }
+
+    /// Serializes the file, JSON pretty printed. TOML goes through a
+    /// toml::Value, which puts plain values ahead of tables as TOML
+    /// requires, wherever unknown fields put them.
+    pub fn to_vec(&self, format: FileFormat) -> DynResult<Vec<u8>> {
+        Ok(match format {
+            FileFormat::Toml => toml::to_vec(&toml::Value::try_from(self)?)?,
+            FileFormat::Json => serde_json::to_vec_pretty(self)?,
+        })
+    }
+
+    /// Writes the file in the format given by its name
+    pub fn save(&self, path: &Path) -> DynResult<()> {
+        fs::write(path, self.to_vec(FileFormat::of_path(path))?)?;
+        Ok(())
+    }
+
+    /// Checks what the loader relies on: the probe settings, that blobs and
+    /// the blob table aren't empty and don't overlap, and that checksums
+    /// are as long as their algorithm makes them
+    pub fn validate(&self) -> Result<(), String> {
+        self.probe.validate()?;
+        for (name, probe) in &self.environments {
+            probe
+                .validate()
+                .map_err(|e| format!("Environment {}: {}", name, e))?;
+        }
+        for (name, blob) in &self.info {
+            if blob.size == 0 {
+                return Err(format!("Blob {} is empty", name));
+            }
+            if blob.load_address().checked_add(blob.size).is_none() {
+                return Err(format!("Blob {} ends beyond the address space", name));
+            }
+            let checksums = [
+                Some(&blob.checksum),
+                blob.compression.as_ref().map(|c| &c.uncompressed_checksum),
+                blob.source.as_ref().map(|s| &s.checksum),
+            ];
+            for checksum in checksums.into_iter().flatten() {
+                checksum
+                    .validate()
+                    .map_err(|e| format!("Blob {}: {}", name, e))?;
+            }
+            if let Some(encryption) = &blob.encryption {
+                encryption
+                    .validate()
+                    .map_err(|e| format!("Blob {}: {}", name, e))?;
+            }
+        }
+        if let Some(artifacts) = &self.artifacts {
+            for checksum in [
+                &artifacts.link_script,
+                &artifacts.source,
+                &artifacts.build_id,
+            ] {
+                checksum
+                    .validate()
+                    .map_err(|e| format!("Generated files: {}", e))?;
+            }
+        }
+        if self.table.as_ref().is_some_and(|t| t.size == 0) {
+            return Err("The blob table is empty".to_string());
+        }
+        if self.table.is_some() {
+            for (name, blob) in &self.info {
+                if u32::try_from(blob.start).is_err() || u32::try_from(blob.size).is_err() {
+                    return Err(format!(
+                        "Blob {} at 0x{:x} is beyond the 32 bit addresses of the blob table",
+                        name, blob.start
+                    ));
+                }
+            }
+        }
+        let mut regions = self.regions();
+        regions.sort_by_key(|(name, r)| (r.start, *name));
+        for pair in regions.windows(2) {
+            let ((first, a), (second, b)) = (&pair[0], &pair[1]);
+            if a.end > b.start {
+                return Err(format!(
+                    "{} at 0x{:08x}-0x{:08x} overlaps {} at 0x{:08x}",
+                    first,
+                    a.start,
+                    a.end - 1,
+                    second,
+                    b.start
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builds a BlobInfoFile of the current format version, e.g. for tests or
+/// provisioning tools
+#[derive(Clone, Debug)]
+pub struct BlobInfoFileBuilder {
+    file: BlobInfoFile,
+}
+
+impl BlobInfoFileBuilder {
+    pub fn new(probe: ProbeInfo) -> BlobInfoFileBuilder {
+        BlobInfoFileBuilder {
+            file: BlobInfoFile {
+                format_version: format!("{}.{}", FORMAT_VERSION.0, FORMAT_VERSION.1),
+                info: BTreeMap::new(),
+                probe,
+                environments: BTreeMap::new(),
+                table: None,
+                app: None,
+                build: None,
+                layout: None,
+                base_dir: None,
+                geometry: None,
+                artifacts: None,
+                extra: UnknownFields::new(),
+            },
+        }
+    }
+
+    /// Adds a blob, replacing any with the same name
+    pub fn with_blob(mut self, name: impl Into<String>, blob: BlobInfo) -> Self {
+        self.file.info.insert(name.into(), blob);
+        self
+    }
+
+    /// Adds named probe settings, replacing any with the same name
+    pub fn with_environment(mut self, name: impl Into<String>, probe: ProbeInfo) -> Self {
+        self.file.environments.insert(name.into(), probe);
+        self
+    }
+
+    pub fn with_table(mut self, table: TableInfo) -> Self {
+        self.file.table = Some(table);
+        self
+    }
+
+    pub fn with_app(mut self, app: AppInfo) -> Self {
+        self.file.app = Some(app);
+        self
+    }
+
+    pub fn with_build_info(mut self, build: BuildInfo) -> Self {
+        self.file.build = Some(build);
+        self
+    }
+
+    pub fn with_layout(mut self, layout: LayoutInfo) -> Self {
+        self.file.layout = Some(layout);
+        self
+    }
+
+    pub fn with_geometry(mut self, geometry: FlashGeometry) -> Self {
+        self.file.geometry = Some(geometry);
+        self
+    }
+
+    pub fn with_artifacts(mut self, artifacts: ArtifactInfo) -> Self {
+        self.file.artifacts = Some(artifacts);
+        self
+    }
+
+    /// Directory relative blob file names are relative to
+    pub fn with_base_dir(mut self, base_dir: impl Into<PathBuf>) -> Self {
+        self.file.base_dir = Some(base_dir.into());
+        self
+    }
+
+    pub fn build(self) -> BlobInfoFile {
+        self.file
+    }
+}
+
+/// Region of one of several files, as the file index and the region name
+pub type FileRegion<'a> = (usize, &'a str);
+
+/// Regions of different files that overlap. The application region of a
+/// file counts as a region named "application".
+pub fn find_overlaps(files: &[BlobInfoFile]) -> Vec<(FileRegion<'_>, FileRegion<'_>)> {
+    fn regions(file: &BlobInfoFile) -> Vec<(&str, Range<u64>)> {
+        let mut regions = file.regions();
+        if let Some(app) = &file.app {
+            let start = app.start as u64;
+            regions.push(("application", start..start + app.size as u64));
+        }
+        regions.sort_by_key(|(name, _)| *name);
+        regions
+    }
+    let all: Vec<_> = files.iter().map(regions).collect();
+    let mut overlaps = Vec::new();
+    for (i, a) in all.iter().enumerate() {
+        for (j, b) in all.iter().enumerate().skip(i + 1) {
+            for (a_name, a_range) in a {
+                for (b_name, b_range) in b {
+                    // Applications may share flash, the blobs may not
+                    if *a_name == "application" && *b_name == "application" {
+                        continue;
+                    }
+                    if a_range.start < b_range.end && b_range.start < a_range.end {
+                        overlaps.push(((i, *a_name), (j, *b_name)));
+                    }
+                }
+            }
+        }
+    }
+    overlaps
+}
+
+#[test]
+fn test_find_overlaps() {
+    let file = |blobs: &[(&str, u64, u64)], app: (u32, u32)| BlobInfoFile {
+        format_version: default_format_version(),
+        info: blobs
+            .iter()
+            .map(|&(name, start, size)| {
+                (
+                    name.to_string(),
+                    BlobInfo {
+                        start,
+                        size,
+                        checksum: Checksum::sha1([0; 20]),
+                        filename: String::new(),
+                        region: None,
+                        tags: Vec::new(),
+                        notes: None,
+                        external: None,
+                        compression: None,
+                        encryption: None,
+                        padding: None,
+                        source: None,
+                        extra: UnknownFields::new(),
+                    },
+                )
+            })
+            .collect(),
+        probe: ProbeInfo {
+            chip: String::new(),
+            selector: None,
+            protocol: None,
+            speed_khz: None,
+            connect_under_reset: false,
+            reset_before_attach: false,
+            core: None,
+            ap: None,
+            extra: UnknownFields::new(),
+        },
+        environments: BTreeMap::new(),
+        table: None,
+        app: Some(AppInfo {
+            start: app.0,
+            size: app.1,
+        }),
+        build: None,
+        layout: None,
+        base_dir: None,
+        geometry: None,
+        artifacts: None,
+        extra: UnknownFields::new(),
+    };
+    let boot = file(&[("font", 0x6000, 0x1000)], (0x0, 0x6000));
+    let app = file(
+        &[("image", 0x10_0000 - 0x2000, 0x2000)],
+        (0x0, 0x10_0000 - 0x2000),
+    );
+    assert_eq!(
+        find_overlaps(&[boot, app]),
+        vec![((0, "font"), (1, "application"))]
+    );
+    let boot = file(&[("font", 0x6000, 0x1000)], (0x0, 0x6000));
+    let app = file(&[("image", 0x8000, 0x2000)], (0x7000, 0x1000));
+    assert!(find_overlaps(&[boot, app]).is_empty());
+}
+
+#[test]
+fn test_layout() {
+    let window = |start, size| Window { start, size };
+    let layout = LayoutInfo::new(
+        window(0x0, 0x10000),
+        window(0x0, 0xc000),
+        window(0xc000, 0x4000),
+        &[0xc000..0xc040, 0xd000..0xe000, 0x9000_0000..0x9000_1000],
+    );
+    assert_eq!(layout.gaps, [window(0xc040, 0xfc0), window(0xe000, 0x2000)]);
+    assert!(layout.check_write("font", &(0xd000..0xe000)).is_ok());
+    assert!(layout
+        .check_write("font", &(0x9000_0000..0x9000_1000))
+        .is_ok());
+    assert!(layout.check_write("font", &(0xbf00..0xc100)).is_err());
+    assert!(layout.check_write("font", &(0xf000..0x10100)).is_err());
+}
+
+#[test]
+fn test_make_relative() {
+    let mut file: BlobInfoFile = toml::from_str(
+        r#"
+format_version = "3.0"
+[probe]
+chip = "nRF52840_xxAA"
+[info.font]
+start = 0x6000
+size = 16
+checksum = "crc32:00000000"
+filename = "/work/blobs/fonts/font.bin"
+[info.image]
+start = 0x7000
+size = 16
+checksum = "crc32:00000000"
+filename = "/work/target/out/image.lz4"
+[info.logo]
+start = 0x8000
+size = 16
+checksum = "crc32:00000000"
+filename = "logo.bin"
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+        file.blob_path(&file.info["font"]),
+        Path::new("/work/blobs/fonts/font.bin")
+    );
+    file.make_relative();
+    assert_eq!(file.base_dir.as_deref(), Some(Path::new("/work")));
+    assert_eq!(file.info["font"].filename, "blobs/fonts/font.bin");
+    assert_eq!(file.info["logo"].filename, "logo.bin");
+    file.base_dir = Some(PathBuf::from("/mnt/assets"));
+    assert_eq!(
+        file.blob_path(&file.info["image"]),
+        Path::new("/mnt/assets/target/out/image.lz4")
+    );
+}
+
+#[test]
+fn test_source_changed() {
+    let path = std::env::temp_dir().join(format!("blob_info_source_{}.bin", std::process::id()));
+    fs::write(&path, b"abcd").unwrap();
+    let mut source = SourceInfo::of(&path).unwrap();
+    assert_eq!(source.size, 4);
+    assert_eq!(source.changed(&path), None);
+    // Written within the same second, only the contents tell
+    source.modified = None;
+    fs::write(&path, b"abce").unwrap();
+    assert_eq!(
+        source.changed(&path).as_deref(),
+        Some("has different contents")
+    );
+    fs::write(&path, b"abcde").unwrap();
+    assert_eq!(
+        source.changed(&path).as_deref(),
+        Some("is 5 bytes, was 4 bytes")
+    );
+    fs::remove_file(&path).unwrap();
+    assert!(source.changed(&path).is_some());
+}
+
+#[test]
+fn test_artifacts_changed() {
+    let out_dir = std::env::temp_dir().join(format!("blob_info_out_{}", std::process::id()));
+    fs::create_dir_all(&out_dir).unwrap();
+    let link_script = b"MEMORY { FLASH : ORIGIN = 0, LENGTH = 64K }\n";
+    let source = b"pub fn font() -> &'static [u8] { &[] }\n";
+    fs::write(out_dir.join(ArtifactInfo::LINK_SCRIPT), link_script).unwrap();
+    fs::write(out_dir.join(ArtifactInfo::SOURCE), source).unwrap();
+    let group = b"pub fn icons() -> &'static [u8] { &[] }\n";
+    fs::write(out_dir.join("blob_ui.rs"), group).unwrap();
+    let build_id = ArtifactInfo::build_id(link_script, source, &[group]);
+    assert_eq!(build_id.digest.len(), 20);
+    assert_ne!(
+        build_id,
+        ArtifactInfo::build_id(source, link_script, &[group])
+    );
+    assert_ne!(build_id, ArtifactInfo::build_id(link_script, source, &[]));
+    let artifacts = ArtifactInfo {
+        out_dir: out_dir.clone(),
+        link_script: Checksum::of(DigestAlgorithm::Sha1, link_script),
+        source: Checksum::of(DigestAlgorithm::Sha1, source),
+        group_files: BTreeMap::from([(
+            "blob_ui.rs".to_string(),
+            Checksum::of(DigestAlgorithm::Sha1, group),
+        )]),
+        build_id,
+    };
+    assert!(artifacts.changed().is_empty());
+    fs::write(out_dir.join("blob_ui.rs"), b"").unwrap();
+    assert_eq!(artifacts.changed(), ["blob_ui.rs"]);
+    fs::write(out_dir.join("blob_ui.rs"), group).unwrap();
+    fs::write(out_dir.join(ArtifactInfo::SOURCE), b"").unwrap();
+    assert_eq!(artifacts.changed(), [ArtifactInfo::SOURCE]);
+    fs::remove_dir_all(&out_dir).unwrap();
+    assert!(artifacts.changed().is_empty());
+}
+
+#[test]
+fn test_encryption() {
+    let ciphertext = [0x8f, 0x1d, 0x42, 0x07];
+    let checksum = Checksum::of(DigestAlgorithm::Sha1, &ciphertext);
+    let mut blob = BlobInfo::new(0x1000, 4, checksum, "font.enc");
+    assert!(blob.check_encrypted(b"font").is_ok());
+    blob.encryption = Some(EncryptionInfo {
+        cipher: "aes-128-ctr".to_string(),
+        key_id: "prod-2024".to_string(),
+        nonce: vec![0x00, 0x01, 0xab],
+    });
+    assert!(blob.check_encrypted(&ciphertext).is_ok());
+    assert!(blob.check_encrypted(b"font").is_err());
+    let text = toml::to_string(&blob).unwrap();
+    assert!(text.contains("nonce = \"0001ab\""));
+    assert_eq!(toml::from_str::<BlobInfo>(&text).unwrap(), blob);
+    let invalid = "cipher = \"aes-128-ctr\"\nkey_id = \"prod-2024\"\nnonce = \"0g\"";
+    assert!(toml::from_str::<EncryptionInfo>(invalid).is_err());
 }
 
+#[test]
+fn test_compression() {
+    let compressed = [0xb0, 0x80, 0x08];
+    let checksum = Checksum::of(DigestAlgorithm::Sha1, &compressed);
+    let mut blob = BlobInfo::new(0x1000, 3, checksum, "fill.heatshrink");
+    blob.compression = Some(CompressionInfo {
+        algorithm: "heatshrink".to_string(),
+        uncompressed_size: 4,
+        uncompressed_checksum: Checksum::of(DigestAlgorithm::Sha1, b"aaaa"),
+    });
+    assert!(blob.check_compressed(&compressed).is_ok());
+    assert!(blob.check_compressed(&[0xb0, 0x80, 0x00]).is_err());
+    blob.compression.as_mut().unwrap().uncompressed_checksum =
+        Checksum::of(DigestAlgorithm::Sha1, b"aaab");
+    assert!(blob.check_compressed(&compressed).is_err());
+    blob.compression.as_mut().unwrap().algorithm = "zstd".to_string();
+    assert!(blob.check_compressed(&compressed).is_ok());
+}
+
+#[test]
+fn test_check_data() {
+    let blob = BlobInfo::new(
+        0x1000,
+        4,
+        Checksum::of(DigestAlgorithm::Crc32, b"font"),
+        "f",
+    );
+    assert!(blob.check_data(b"font"));
+    assert!(!blob.check_data(b"fonT"));
+}
+
+#[test]
+fn test_builder() {
+    let checksum = Checksum::of(DigestAlgorithm::Crc32, b"font");
+    let file = BlobInfoFileBuilder::new(ProbeInfo::new("nRF52840_xxAA"))
+        .with_blob("font", BlobInfo::new(0xf_0000, 4, checksum, "font.bin"))
+        .with_table(TableInfo {
+            start: 0xe_ff00,
+            size: 0x100,
+        })
+        .with_base_dir("/work/blobs")
+        .build();
+    assert!(check_format_version(&file.format_version).is_ok());
+    assert_eq!(file.regions().len(), 2);
+    assert_eq!(
+        file.blob_path(&file.info["font"]),
+        Path::new("/work/blobs/font.bin")
+    );
+    let text = toml::to_string(&file).unwrap();
+    assert_eq!(toml::from_str::<BlobInfoFile>(&text).unwrap(), file);
+}
+
+#[test]
+fn test_select_environment() {
+    let mut jig = ProbeInfo::new("nRF52840_xxAA");
+    jig.selector = Some("1366:1015".to_string());
+    let mut file = BlobInfoFileBuilder::new(ProbeInfo::new("nRF52833_xxAA"))
+        .with_environment("jig", jig.clone())
+        .build();
+    assert!(file.select_environment("field").is_err());
+    assert_eq!(file.probe.chip, "nRF52833_xxAA");
+    file.select_environment("jig").unwrap();
+    assert_eq!(file.probe, jig);
+    jig.speed_khz = Some(0);
+    let file = BlobInfoFileBuilder::new(ProbeInfo::new("nRF52833_xxAA"))
+        .with_environment("jig", jig)
+        .build();
+    assert!(file.validate().is_err());
+}
+
+#[test]
+fn test_sector_map() {
+    let map = SectorMap {
+        start: 0x0800_0000,
+        end: 0x0802_0000,
+        sectors: vec![
+            SectorSize {
+                offset: 0,
+                size: 0x4000,
+            },
+            SectorSize {
+                offset: 0x1_0000,
+                size: 0x1_0000,
+            },
+        ],
+    };
+    let sectors = map.sectors();
+    assert_eq!(sectors.len(), 5);
+    assert_eq!(sectors[3], 0x0800_c000..0x0801_0000);
+    assert_eq!(sectors[4], 0x0801_0000..0x0802_0000);
+    assert!(map.contains(0x0801_ffff));
+    assert!(!map.contains(0x0802_0000));
+    let mut file = BlobInfoFileBuilder::new(ProbeInfo::new("STM32F411CEUx"))
+        .with_geometry(FlashGeometry {
+            chip: "STM32F411CEUx".to_string(),
+            flash: vec![map],
+        })
+        .build();
+    let text = toml::to_string(&file).unwrap();
+    assert_eq!(toml::from_str::<BlobInfoFile>(&text).unwrap(), file);
+    assert!(file.sector_maps().is_some());
+    // Recorded for another chip than the one selected
+    file.probe.chip = "STM32F401CCUx".to_string();
+    assert!(file.sector_maps().is_none());
+}
+
+#[test]
+fn test_validate() {
+    let blob = |start, size| BlobInfo::new(start, size, Checksum::sha1([0; 20]), "blob.bin");
+    let builder = BlobInfoFileBuilder::new(ProbeInfo::new("nRF52840_xxAA"))
+        .with_blob("font", blob(0xf_0000, 0x1000))
+        .with_table(TableInfo {
+            start: 0xe_ff00,
+            size: 0x100,
+        });
+    assert_eq!(builder.clone().build().validate(), Ok(()));
+    let overlapping = builder.clone().with_blob("logo", blob(0xf_0800, 0x1000));
+    assert!(overlapping.build().validate().is_err());
+    assert!(builder
+        .clone()
+        .with_blob("logo", blob(0xf_1000, 0))
+        .build()
+        .validate()
+        .is_err());
+    // Beyond 4 GiB only without a blob table
+    let external = BlobInfoFileBuilder::new(ProbeInfo::new("nRF52840_xxAA"))
+        .with_blob("video", blob(0x1_2000_0000, 0x10));
+    assert_eq!(external.clone().build().validate(), Ok(()));
+    assert!(builder
+        .clone()
+        .with_blob("video", blob(0x1_2000_0000, 0x10))
+        .build()
+        .validate()
+        .is_err());
+    assert!(external
+        .with_blob("end", blob(u64::MAX, 0x10))
+        .build()
+        .validate()
+        .is_err());
+    let mut short = blob(0xf_1000, 0x10);
+    short.checksum.algorithm = DigestAlgorithm::Sha256;
+    assert!(builder.with_blob("logo", short).build().validate().is_err());
+}
+
+#[test]
+fn test_save_load() {
+    let file = BlobInfoFileBuilder::new(ProbeInfo::new("nRF52840_xxAA"))
+        .with_blob(
+            "font",
+            BlobInfo::new(0xf_0000, 4, Checksum::sha1([7; 20]), "font.bin"),
+        )
+        .build();
+    for extension in ["toml", "json"] {
+        let path = std::env::temp_dir().join(format!(
+            "BlobInfo-test-{}.{}",
+            std::process::id(),
+            extension
+        ));
+        file.save(&path).unwrap();
+        assert_eq!(BlobInfoFile::load(&path).unwrap(), file);
+        fs::remove_file(&path).unwrap();
+    }
+    assert!(BlobInfoFile::from_slice(b"format_version = \"99.0\"").is_err());
+}
+
+#[test]
+fn test_unknown_fields() {
+    let text = r#"
+format_version = "3.99"
+signed_by = "release-ci"
+
+[info.font]
+start = 983040
+size = 4
+checksum = "crc32:cbf43926"
+filename = "font.bin"
+license = "OFL-1.1"
+
+[info.font.provenance]
+url = "https://example.com/font"
+
+[probe]
+chip = "nRF52840_xxAA"
+swo_khz = 2000
+"#;
+    let file = BlobInfoFile::from_slice(text.as_bytes()).unwrap();
+    assert_eq!(file.extra["signed_by"], "release-ci");
+    assert_eq!(file.info["font"].extra["license"], "OFL-1.1");
+    assert_eq!(
+        file.info["font"].extra["provenance"]["url"],
+        "https://example.com/font"
+    );
+    assert_eq!(file.probe.extra["swo_khz"], 2000);
+    for format in [FileFormat::Toml, FileFormat::Json] {
+        let data = file.to_vec(format).unwrap();
+        assert_eq!(BlobInfoFile::from_slice(&data).unwrap(), file);
+    }
+}
+
+#[test]
+fn test_sorted_output() {
+    let blob = |start| BlobInfo::new(start, 4, Checksum::sha1([0; 20]), "blob.bin");
+    let names = ["logo", "font", "sound", "icons"];
+    let build = |names: &[&str]| {
+        let mut builder = BlobInfoFileBuilder::new(ProbeInfo::new("nRF52840_xxAA"));
+        for name in names {
+            builder = builder
+                .with_blob(*name, blob(name.len() as u64 * 0x100))
+                .with_environment(*name, ProbeInfo::new("nRF52840_xxAA"));
+        }
+        builder.build()
+    };
+    let reversed: Vec<&str> = names.iter().rev().copied().collect();
+    for format in [FileFormat::Toml, FileFormat::Json] {
+        let data = build(&names).to_vec(format).unwrap();
+        assert_eq!(data, build(&reversed).to_vec(format).unwrap());
+        let text = String::from_utf8(data).unwrap();
+        let positions: Vec<usize> = ["font", "icons", "logo", "sound"]
+            .iter()
+            .map(|name| text.find(name).unwrap())
+            .collect();
+        assert!(positions.windows(2).all(|p| p[0] < p[1]));
+    }
+}
+
+#[test]
+fn test_check_format_version() {
+    assert!(check_format_version("1.0").is_ok());
+    assert!(check_format_version("2.7").is_ok());
+    assert!(check_format_version("3.2").is_ok());
+    assert!(check_format_version("4.0").is_err());
+    assert!(check_format_version("0.9").is_err());
+    assert!(check_format_version("1").is_err());
+}
+
+#[test]
+fn test_checksum() {
+    let crc = Checksum::of(DigestAlgorithm::Crc32, b"123456789");
+    assert_eq!(crc.to_string(), "crc32:cbf43926");
+    assert_eq!("crc32:cbf43926".parse::<Checksum>(), Ok(crc));
+    let sha1 = Checksum::of(DigestAlgorithm::Sha1, b"");
+    assert_eq!(
+        sha1.to_string(),
+        "sha1:da39a3ee5e6b4b0d3255bfef95601890afd80709"
+    );
+    assert_eq!(sha1.table_bytes().to_vec(), sha1.digest);
+    assert!("sha1:da39".parse::<Checksum>().is_err());
+    assert!("md5:d41d8cd98f00b204e9800998ecf8427e"
+        .parse::<Checksum>()
+        .is_err());
+    #[derive(Deserialize)]
+    struct File {
+        checksum: Checksum,
+    }
+    let old: File = toml::from_str(&format!("checksum = {:?}", sha1.digest)).unwrap();
+    assert_eq!(old.checksum, sha1);
+}
+
+#[test]
+fn test_validate_probe() {
+    let mut probe = ProbeInfo {
+        chip: "STM32H745ZITx".to_string(),
+        selector: Some("0483:374e:0030003F3331510F37363734".to_string()),
+        protocol: Some("SWD".to_string()),
+        speed_khz: Some(4000),
+        connect_under_reset: true,
+        reset_before_attach: false,
+        core: Some(1),
+        ap: None,
+        extra: UnknownFields::new(),
+    };
+    assert_eq!(probe.validate(), Ok(()));
+    probe.selector = Some("stlink".to_string());
+    assert!(probe.validate().is_err());
+    probe.selector = None;
+    probe.protocol = Some("spi".to_string());
+    assert!(probe.validate().is_err());
+}
@@ -0,0 +1,213 @@
+// Index of loaded blobs stored at the start of the blob region.
+//
+// Layout, all values little endian:
+//   magic: u32, count: u32, fingerprint: u32
+//   count entries of name_hash: u32, start: u32, size: u32, checksum: [u8; 20]
+//
+// The fingerprint is the CRC32 of all entries. It's also compiled into the
+// firmware so that it can detect if the blobs were flashed for a different
+// layout.
+
+use crate::blob_info::BlobInfoFile;
+use crate::crc32::Crc32;
+
+pub const MAGIC: u32 = 0x424c4f42;
+pub const HEADER_SIZE: u32 = 12;
+pub const ENTRY_SIZE: u32 = 32;
+
+pub fn table_size(count: usize) -> u32 {
+    HEADER_SIZE + ENTRY_SIZE * count as u32
+}
+
+/// 32-bit FNV-1a hash of a blob name
+pub const fn name_hash(name: &str) -> u32 {
+    let bytes = name.as_bytes();
+    let mut hash = 0x811c9dc5u32;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u32;
+        hash = hash.wrapping_mul(0x01000193);
+        i += 1;
+    }
+    hash
+}
+
+/// Source of name_hash for inclusion in generated code
+pub const NAME_HASH_SOURCE: &str = r#"
+const fn blob_name_hash(name: &str) -> u32 {
+    let bytes = name.as_bytes();
+    let mut hash = 0x811c9dc5u32;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u32;
+        hash = hash.wrapping_mul(0x01000193);
+        i += 1;
+    }
+    hash
+}
+"#;
+
+/// Encodes table entries from (name, start, size, checksum). The entries
+/// must be sorted by name.
+pub fn encode_entries<'a, I>(entries: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = (&'a str, u32, u32, &'a [u8; 20])>,
+{
+    let mut data = Vec::new();
+    for (name, start, size, checksum) in entries {
+        data.extend_from_slice(&name_hash(name).to_le_bytes());
+        data.extend_from_slice(&start.to_le_bytes());
+        data.extend_from_slice(&size.to_le_bytes());
+        data.extend_from_slice(checksum);
+    }
+    data
+}
+
+/// Layout fingerprint of encoded entries
+pub fn fingerprint(entries: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(entries);
+    crc.value()
+}
+
+/// Builds the table for all blobs in the info file, sorted by name
+pub fn encode(blob_info: &BlobInfoFile) -> Vec<u8> {
+    let mut names: Vec<&String> = blob_info.info.keys().collect();
+    names.sort();
+    let checksums: Vec<[u8; 20]> = names
+        .iter()
+        .map(|name| blob_info.info[*name].checksum.table_bytes())
+        .collect();
+    // BlobInfoFile::validate checks that the blobs fit in the 32 bit fields
+    let entries = encode_entries(names.iter().zip(&checksums).map(|(name, checksum)| {
+        let blob = &blob_info.info[*name];
+        (name.as_str(), blob.start as u32, blob.size as u32, checksum)
+    }));
+    let mut table = Vec::with_capacity(table_size(names.len()) as usize);
+    table.extend_from_slice(&MAGIC.to_le_bytes());
+    table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+    table.extend_from_slice(&fingerprint(&entries).to_le_bytes());
+    table.extend_from_slice(&entries);
+    table
+}
+
+/// Entry of a table read back from flash
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableEntry {
+    pub name_hash: u32,
+    pub start: u32,
+    pub size: u32,
+    pub checksum: [u8; 20],
+}
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+/// Number of entries given by a table header, or None if it isn't one
+pub fn decode_header(header: &[u8]) -> Option<usize> {
+    if header.len() < HEADER_SIZE as usize || read_u32(header, 0) != MAGIC {
+        return None;
+    }
+    Some(read_u32(header, 4) as usize)
+}
+
+/// Decodes a table read back from flash. Returns None if the magic or the
+/// fingerprint doesn't match.
+pub fn decode(data: &[u8]) -> Option<Vec<TableEntry>> {
+    let count = decode_header(data)?;
+    let entries = data.get(HEADER_SIZE as usize..table_size(count) as usize)?;
+    if fingerprint(entries) != read_u32(data, 8) {
+        return None;
+    }
+    Some(
+        entries
+            .chunks(ENTRY_SIZE as usize)
+            .map(|entry| TableEntry {
+                name_hash: read_u32(entry, 0),
+                start: read_u32(entry, 4),
+                size: read_u32(entry, 8),
+                checksum: entry[12..32].try_into().unwrap(),
+            })
+            .collect(),
+    )
+}
+
+#[test]
+fn test_name_hash() {
+    assert_eq!(name_hash(""), 0x811c9dc5);
+    assert_eq!(name_hash("a"), 0xe40c292c);
+    assert_eq!(name_hash("foobar"), 0xbf9cf968);
+}
+
+#[test]
+fn test_encode() {
+    use crate::blob_info::{BlobInfo, Checksum, ProbeInfo};
+    use std::collections::BTreeMap;
+    let mut info = BTreeMap::new();
+    for (name, start) in [("b", 0x2000), ("a", 0x1000)] {
+        info.insert(
+            name.to_string(),
+            BlobInfo {
+                start,
+                size: 16,
+                checksum: Checksum::sha1([start as u8; 20]),
+                filename: String::new(),
+                region: None,
+                tags: Vec::new(),
+                notes: None,
+                external: None,
+                compression: None,
+                encryption: None,
+                padding: None,
+                source: None,
+                extra: BTreeMap::new(),
+            },
+        );
+    }
+    let table = encode(&BlobInfoFile {
+        format_version: "2.0".to_string(),
+        info,
+        app: None,
+        build: None,
+        layout: None,
+        base_dir: None,
+        geometry: None,
+        artifacts: None,
+        extra: BTreeMap::new(),
+        probe: ProbeInfo {
+            chip: String::new(),
+            selector: None,
+            protocol: None,
+            speed_khz: None,
+            connect_under_reset: false,
+            reset_before_attach: false,
+            core: None,
+            ap: None,
+            extra: BTreeMap::new(),
+        },
+        environments: BTreeMap::new(),
+        table: None,
+    });
+    assert_eq!(table.len(), table_size(2) as usize);
+    assert_eq!(table[0..4], MAGIC.to_le_bytes());
+    assert_eq!(table[4..8], 2u32.to_le_bytes());
+    assert_eq!(table[8..12], fingerprint(&table[12..]).to_le_bytes());
+    // Sorted by name
+    assert_eq!(table[12..16], name_hash("a").to_le_bytes());
+    assert_eq!(table[16..20], 0x1000u32.to_le_bytes());
+    let entries = decode(&table).unwrap();
+    assert_eq!(
+        entries[1],
+        TableEntry {
+            name_hash: name_hash("b"),
+            start: 0x2000,
+            size: 16,
+            checksum: [0; 20]
+        }
+    );
+    let mut corrupt = table.clone();
+    corrupt[20] ^= 1;
+    assert_eq!(decode(&corrupt), None);
+    assert_eq!(decode(&table[..40]), None);
+}
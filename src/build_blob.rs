@@ -1,228 +1,3822 @@
-use crate::blob_info::{BlobInfo, BlobInfoFile, ProbeInfo};
+use crate::blob_index;
+use crate::blob_info::{
+    self, AppInfo, ArtifactInfo, BlobInfo, BlobInfoFile, BuildInfo, Checksum, CompressionInfo,
+    DigestAlgorithm, EncryptionInfo, ExternalFlash, FileFormat, FlashGeometry, LayoutInfo, Padding,
+    ProbeInfo, SourceInfo, TableInfo, UnknownFields, Window, FORMAT_VERSION,
+};
+use crate::blob_table;
+use crate::compress::{Codec, HEATSHRINK_SOURCE};
+use crate::crc32::{Crc32, CRC32_SOURCE, CRC32_UPDATE_SOURCE};
+use crate::encrypt::{self, Cipher};
+use crate::flash;
 use crate::link_script_parser;
-use serde_derive::Deserialize;
+use crate::littlefs::{self, Geometry};
+use crate::perfect_hash;
+use crate::signing;
+use ed25519_dalek::SigningKey;
+use probe_rs::config::Registry;
+use serde_derive::{Deserialize, Serialize};
 use sha1_smol::Sha1;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::env;
 use std::fs::File;
 use std::io::Read;
 use std::io::Write;
-use std::path::PathBuf;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
 use toml;
 
+/// When the generated accessor checks the blob against its checksum
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+enum VerifyMode {
+    Never,
+    DebugOnly,
+    #[default]
+    Always,
+}
+
+/// Cargo feature of the crate including blob.rs that turns off all verification
+const SKIP_VERIFY_FEATURE: &str = "skip-blob-verify";
+
+/// Cargo feature of the crate including blob.rs that adds defmt support
+const DEFMT_FEATURE: &str = "defmt";
+
+/// Region in memory.x the loaded blobs are placed at the end of
+const FLASH_REGION: &str = "FLASH";
+
 #[derive(Deserialize)]
 struct BlobParams {
     filename: String,
     inline: Option<bool>, // Blob is part of the executable. Overrides inline-dev and inline-release
     inline_dev: Option<bool>, // Blob is part of the executable for dev profiles
     inline_release: Option<bool>, // Blob is part of the executable for release profiles
+    verify: Option<VerifyMode>, // Overrides the verify mode from the generate section
+    version: Option<String>, // Free form version string included in descriptors
+    align: Option<u32>,   // Required alignment of the blob data, must be a power of two
+    #[serde(rename = "type")]
+    type_name: Option<String>, // Path of a zerocopy::FromBytes type the accessor returns
+    #[serde(default)]
+    kind: BlobKind,
+    // Overrides the compression from the flash section
+    compress: Option<Compression>,
+    // Encrypt the blob with a key from the encryption section
+    encrypt: Option<EncryptParams>,
+    // Build a littlefs image from the directory in filename
+    littlefs: Option<LittleFsParams>,
+    #[serde(default)]
+    storage: bool, // Blob is in flash that is not memory mapped and read through BlobStorage
+    group: Option<String>, // Generate the accessors in a separate file for the group
+    #[serde(default)]
+    tags: Vec<String>, // Labels shown by the loader, e.g. "fcc-test-image"
+    notes: Option<String>, // Free form remarks shown by the loader
+}
+
+/// Compression of the blob data in flash
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+enum Compression {
+    #[default]
+    None,
+    Lz4,        // LZ4 block format, decompressed with lz4_flex
+    Heatshrink, // Decompressed by generated code, needing no dependency
+    Deflate,    // Raw deflate, decompressed with miniz_oxide
+}
+
+impl Compression {
+    fn codec(&self) -> Option<Codec> {
+        match self {
+            Compression::None => None,
+            Compression::Lz4 => Some(Codec::Lz4),
+            Compression::Heatshrink => Some(Codec::Heatshrink),
+            Compression::Deflate => Some(Codec::Deflate),
+        }
+    }
+}
+
+/// How a blob is encrypted
+#[derive(Deserialize)]
+struct EncryptParams {
+    cipher: Cipher,
+    key: String, // Key id, looked up in the encryption section
+}
+
+/// littlefs filesystem built from a directory. The geometry has to match
+/// the flash and the configuration the firmware mounts it with.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+struct LittleFsParams {
+    block_size: u32,  // Erase block size of the flash
+    block_count: u32, // Number of blocks, the image takes all of them
+    #[serde(default = "LittleFsParams::default_prog_size")]
+    prog_size: u32, // Read and program size, a multiple of the flash write size
+    cache_size: Option<u32>, // Defaults to 256 or the block size if smaller
+    #[serde(default)]
+    littlefs2: bool, // Generate a littlefs2::driver::Storage for the blob
+}
+
+impl LittleFsParams {
+    fn default_prog_size() -> u32 {
+        16
+    }
+
+    fn geometry(&self) -> Geometry {
+        Geometry {
+            block_size: self.block_size,
+            block_count: self.block_count,
+            prog_size: self.prog_size,
+            cache_size: self.cache_size.unwrap_or(self.block_size.min(256)),
+        }
+    }
+}
+
+/// What kind of data a blob contains
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+enum BlobKind {
+    #[default]
+    Binary, // Accessors return bytes
+    Text, // UTF-8 validated at build time. Accessors return &'static str
+}
+
+/// How the generated accessors report a checksum mismatch
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+enum ErrorMode {
+    #[default]
+    Panic, // Accessors return the data and panic on mismatch
+    Result, // Accessors return Result<&'static [u8], BlobError>
+    Hook,   // Accessors return the data and call a user function on mismatch
+}
+
+/// Checksum used by generated code for verification
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+enum ChecksumAlgorithm {
+    #[default]
+    Sha1, // Uses sha1_smol
+    Crc32, // Uses a small built-in implementation or crc32_hook
+}
+
+#[derive(Deserialize, Default, Clone)]
+struct GenerateParams {
+    #[serde(default)]
+    verify: VerifyMode,
+    #[serde(default)]
+    errors: ErrorMode,
+    #[serde(default)]
+    descriptors: bool, // Generate a Blob descriptor constant for each blob
+    #[serde(default)]
+    registry: bool, // Generate ALL_BLOBS and find(). Implies descriptors
+    #[serde(default)]
+    checksum: ChecksumAlgorithm,
+    crc32_hook: Option<String>, // Path of a fn(&[u8]) -> u32 used instead of the built-in CRC32
+    // Path of a fn(&str) -> ! called on mismatch in hook mode. If not set,
+    // an extern function blob_integrity_failure has to be defined.
+    failure_hook: Option<String>,
+    visibility: Option<String>, // Visibility of generated items, defaults to pub
+    module: Option<String>,     // Put the generated items in a module with this name
+    #[serde(default)]
+    blob_table: bool, // Reserve space for a blob table written by the loader
+    #[serde(default)]
+    embedded_storage: bool, // Generate readers implementing embedded_storage::ReadStorage
+    #[serde(default)]
+    embedded_io: bool, // Also implement embedded_io::Read and Seek for the readers
+    format: Option<bool>,       // Format the generated source with rustfmt, defaults to true
+    #[serde(default)]
+    host_tests: bool, // Generate tests checking the blob files against the baked in checksums
+    #[serde(default)]
+    arrays: bool, // Generate accessors returning fixed size arrays
+    #[serde(default)]
+    lookup: bool, // Generate the blob! macro and BLOBS_BY_NAME
+    // Path of the generated items used in blob! expansions, e.g.
+    // crate::blobs. If not set the names are resolved where blob! is used.
+    lookup_path: Option<String>,
+    // minijinja template, relative to the manifest directory, used to
+    // render blob.rs instead of the built-in generator
+    template: Option<String>,
+    #[serde(default)]
+    blob_index: bool, // Put a record for each blob in the .blob_index section
+    // Name of the file generated for each group, with {group} replaced by
+    // the group name. Defaults to blob_{group}.rs
+    group_file: Option<String>,
+    #[serde(default)]
+    async_storage: bool, // Also generate async accessors using BlobStorageAsync
+    #[serde(default)]
+    verify_all: bool, // Generate verify_all() checking all loaded blobs
+}
+
+impl GenerateParams {
+    fn visibility(&self) -> &str {
+        self.visibility.as_deref().unwrap_or("pub")
+    }
+
+    fn format(&self) -> bool {
+        self.format.unwrap_or(true)
+    }
+
+    fn group_file(&self, group: &str) -> String {
+        self.group_file
+            .as_deref()
+            .unwrap_or("blob_{group}.rs")
+            .replace("{group}", group)
+    }
+}
+
+/// Flash that is not memory mapped, e.g. external SPI flash
+#[derive(Deserialize, Default)]
+struct StorageParams {
+    #[serde(default)]
+    origin: u32, // Address of the first storage blob, as passed to BlobStorage::read
+    // Address of origin in the memory map while the loader programs the
+    // flash, e.g. 0x90000000 for QSPI flash on STM32. Defaults to origin.
+    mapped_address: Option<u32>,
+    // Name of the probe-rs flash algorithm for the flash, if the target has
+    // several for the region
+    flash_algorithm: Option<String>,
+    // Name of the memory region in BlobInfo.toml, STORAGE by default
+    region: Option<String>,
+}
+
+/// The flash region holding loaded blobs
+#[derive(Deserialize, Default)]
+struct FlashParams {
+    // Path of a fn() called before blobs are first read, e.g. to invalidate
+    // an XIP cache that may hold data from before the blobs were flashed
+    pre_read_barrier: Option<String>,
+    // Compression of the blobs that don't set their own. Blobs that can't
+    // be compressed, like storage, directory, text or typed blobs, are
+    // left uncompressed.
+    #[serde(default)]
+    compress: Compression,
+}
+
+/// Keys for encrypting blobs. They are only needed when building, the
+/// firmware gets them from a BlobKeyProvider.
+#[derive(Deserialize, Default)]
+struct EncryptionParams {
+    // Files with the keys in hex by key id, relative to the package.
+    // BLOB_ENCRYPTION_KEY_<ID> overrides the file of a key.
+    #[serde(default)]
+    keys: BTreeMap<String, String>,
+}
+
+/// Formats the blob info is written in
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+enum BlobInfoFormat {
+    #[default]
+    Toml, // BlobInfo.toml
+    Json, // BlobInfo.json
+    Both,
+}
+
+/// What is recorded in BlobInfo.toml besides the layout
+#[derive(Deserialize)]
+struct BlobInfoParams {
+    #[serde(default)]
+    format: BlobInfoFormat,
+    // Record the build time. Off by default, since it makes otherwise
+    // identical builds differ. SOURCE_DATE_EPOCH is used if set.
+    #[serde(default)]
+    timestamp: bool,
+    // Value the padding between blobs reads as, the erased value of the
+    // flash unless the loader is told to fill it
+    #[serde(default = "default_fill")]
+    fill: u8,
+    // File with the key for signing the blob info files, relative to the
+    // package. BLOB_INFO_SIGNING_KEY overrides it.
+    #[serde(default)]
+    signing_key: Option<String>,
+    // Probe settings the loader can choose instead of those in the probe
+    // section, by name
+    #[serde(default)]
+    environments: BTreeMap<String, ProbeInfo>,
+}
+
+fn default_fill() -> u8 {
+    0xff
+}
+
+impl Default for BlobInfoParams {
+    fn default() -> Self {
+        BlobInfoParams {
+            format: BlobInfoFormat::default(),
+            timestamp: false,
+            fill: default_fill(),
+            signing_key: None,
+            environments: BTreeMap::new(),
+        }
+    }
 }
 
 #[derive(Deserialize)]
 struct BlobConfig {
-    files: HashMap<String, BlobParams>,
+    files: BTreeMap<String, BlobParams>, // Sorted, so that the layout is the same every build
     probe: ProbeInfo,
+    #[serde(default)]
+    generate: GenerateParams,
+    #[serde(default)]
+    storage: StorageParams,
+    #[serde(default)]
+    flash: FlashParams,
+    #[serde(default)]
+    blob_info: BlobInfoParams,
+    #[serde(default)]
+    encryption: EncryptionParams,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Blob {
     name: String,
     start: u32,
     size: u32,
     checksum: [u8; 20],
+    crc32: u32,
     filename: String,
+    path_expr: String, // Rust expression for the path of filename in generated code
+    source_name: String, // As given in Blobs.toml, relative to the package
     inline: bool,
+    verify: VerifyMode,
+    version: Option<String>,
+    align: u32,
+    type_name: Option<String>,
+    kind: BlobKind,
+    compress: Compression,
+    decompressed_size: u32,
+    decompressed_checksum: [u8; 20], // SHA-1 before compression
+    storage: bool,
+    pre_read_barrier: Option<String>,
+    dir_files: Option<Vec<DirFile>>, // Files of a directory blob, sorted by path
+    group: Option<String>,
+    external: Option<ExternalFlash>, // How the loader programs a storage blob
+    region: Option<String>,          // Memory region, None for inline blobs
+    source: Option<SourceInfo>,      // Fingerprint of the file, None for directories
+    tags: Vec<String>,
+    notes: Option<String>,
+    encryption: Option<EncryptionInfo>,
+    littlefs: Option<LittleFsParams>,
+    source_check: Option<SourceCheck>, // Set if the blob file is built from the source
+}
+
+// Source file of a compressed or encrypted blob, checked by the host tests
+// instead of the blob file built from it
+#[derive(Debug, Clone)]
+struct SourceCheck {
+    path_expr: String,
+    size: u32,
+    checksum: [u8; 20],
+    crc32: u32,
+}
+
+// File in a directory blob
+#[derive(Debug, Clone)]
+struct DirFile {
+    path: String, // Relative to the directory, with / as separator
+    offset: u32,
+    len: u32,
+}
+
+// Collects the files below dir, with paths relative to root
+fn collect_dir(root: &Path, dir: &Path, files: &mut Vec<(String, PathBuf)>) -> DynResult<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_dir(root, &path, files)?;
+        } else {
+            let relative = path
+                .strip_prefix(root)?
+                .components()
+                .map(|c| {
+                    c.as_os_str()
+                        .to_str()
+                        .ok_or("Path can not be converted to UTF-8")
+                })
+                .collect::<Result<Vec<_>, _>>()?
+                .join("/");
+            files.push((relative, path));
+        }
+    }
+    Ok(())
+}
+
+// Concatenates the files in a directory into out_file
+fn build_dir_data(dir: &Path, out_file: &Path) -> DynResult<Vec<DirFile>> {
+    let mut files = Vec::new();
+    collect_dir(dir, dir, &mut files)?;
+    files.sort();
+    let mut out = File::create(out_file)?;
+    let mut offset = 0;
+    let mut dir_files = Vec::new();
+    for (path, full_path) in files {
+        let data = std::fs::read(&full_path)?;
+        out.write_all(&data)?;
+        let len = u32::try_from(data.len())?;
+        dir_files.push(DirFile { path, offset, len });
+        offset += len;
+    }
+    Ok(dir_files)
+}
+
+impl Blob {
+    // Stored in the flash following the program
+    fn loaded(&self) -> bool {
+        !self.inline && !self.storage
+    }
+
+    // Absolute address of the blob. Storage blobs already have absolute
+    // addresses in the storage address space.
+    fn address(&self, origin: u32) -> u32 {
+        if self.storage {
+            self.start
+        } else {
+            self.start + origin
+        }
+    }
+
+    // Checksum used by generated code as a Rust expression
+    fn checksum_literal(&self, algorithm: ChecksumAlgorithm) -> String {
+        match algorithm {
+            ChecksumAlgorithm::Sha1 => {
+                format!("[{}]", self.checksum.map(|v| v.to_string()).join(", "))
+            }
+            ChecksumAlgorithm::Crc32 => format!("0x{:08x}", self.crc32),
+        }
+    }
+
+    // Doc comment describing the blob, starting with a newline
+    fn doc_comment(&self, title: &str, origin: u32) -> String {
+        let mut doc = format!("\n/// {}\n///", title);
+        doc += &format!("\n/// * Source: `{}`", self.source_name);
+        doc += &format!("\n/// * Size: {} bytes", self.size);
+        if self.compress != Compression::None {
+            doc += &format!(
+                "\n/// * Compressed: {:?}, {} bytes decompressed",
+                self.compress, self.decompressed_size
+            );
+        }
+        if let Some(encryption) = &self.encryption {
+            doc += &format!(
+                "\n/// * Encrypted: {} with key `{}`",
+                encryption.cipher, encryption.key_id
+            );
+        }
+        doc += &format!(
+            "\n/// * SHA-1: `{}`",
+            self.checksum
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        );
+        if let Some(littlefs) = &self.littlefs {
+            doc += &format!(
+                "\n/// * littlefs: {} blocks of {} bytes",
+                littlefs.block_count, littlefs.block_size
+            );
+        }
+        if let Some(version) = &self.version {
+            doc += &format!("\n/// * Version: {}", version);
+        }
+        if self.inline {
+            doc += "\n/// * Inline: included in the executable";
+        } else if self.storage {
+            doc += &format!(
+                "\n/// * Storage: read through BlobStorage at 0x{:x}",
+                self.start
+            );
+        } else {
+            doc += &format!(
+                "\n/// * Loaded: stored in flash at 0x{:x} by cargo load-blob",
+                self.address(origin)
+            );
+        }
+        doc
+    }
+
+    // Cipher of an encrypted blob
+    fn cipher(&self) -> Option<Cipher> {
+        Cipher::from_name(&self.encryption.as_ref()?.cipher)
+    }
+
+    // Name of the generated function returning the raw bytes
+    fn bytes_accessor(&self) -> String {
+        if self.type_name.is_some() || self.kind == BlobKind::Text {
+            format!("{}_bytes", self.name)
+        } else {
+            self.name.clone()
+        }
+    }
 }
 
 type DynResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+// Expression for a path in generated code. Paths below the manifest or
+// output directory are made relative to them, so that the generated code
+// doesn't depend on where the project is built.
+fn path_expr(path: &Path, top_dir: &Path, out_dir: &Path) -> DynResult<String> {
+    let relative = |base: &Path| -> Option<DynResult<String>> {
+        let rest = path.strip_prefix(base).ok()?;
+        Some(
+            rest.components()
+                .map(|c| {
+                    c.as_os_str()
+                        .to_str()
+                        .ok_or_else(|| "Filename can not be converted to UTF-8".into())
+                })
+                .collect::<DynResult<Vec<_>>>()
+                .map(|c| c.join("/")),
+        )
+    };
+    // OUT_DIR is usually below the manifest directory, so check it first
+    if let Some(rest) = relative(out_dir) {
+        return Ok(format!(
+            "concat!(env!(\"OUT_DIR\"), {:?})",
+            format!("/{}", rest?)
+        ));
+    }
+    if let Some(rest) = relative(top_dir) {
+        return Ok(format!(
+            "concat!(env!(\"CARGO_MANIFEST_DIR\"), {:?})",
+            format!("/{}", rest?)
+        ));
+    }
+    // Debug formatting escapes backslashes and quotes
+    Ok(format!(
+        "{:?}",
+        path.to_str()
+            .ok_or("Filename can not be converted to UTF-8")?
+    ))
+}
 const BLOB_FILE: &str = "Blobs.toml";
-fn read_blobs(release: bool) -> DynResult<(Vec<Blob>, ProbeInfo)> {
+fn read_blobs(
+    release: bool,
+    out_dir: &Path,
+) -> DynResult<(Vec<Blob>, ProbeInfo, GenerateParams, BlobInfoParams)> {
     let top_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR")?);
     let blob_file = top_dir.join(BLOB_FILE);
     let mut total_size = 0;
+    let mut storage_size = 0;
     let mut file = File::open(&blob_file)?;
     let mut buf = String::new();
     file.read_to_string(&mut buf).unwrap();
     let blob_config: BlobConfig = toml::from_str(&buf).unwrap();
+    blob_config
+        .probe
+        .validate()
+        .map_err(|e| format!("Invalid probe settings in {}: {}", BLOB_FILE, e))?;
+    for (name, probe) in &blob_config.blob_info.environments {
+        probe.validate().map_err(|e| {
+            format!(
+                "Invalid probe settings for environment {} in {}: {}",
+                name, BLOB_FILE, e
+            )
+        })?;
+    }
     let mut blobs = Vec::new();
     for (name, params) in blob_config.files {
         let mut cs = Sha1::new();
+        let mut crc = Crc32::new();
         let mut buf = [0u8; 1024];
         let mut file_size = 0;
-        let filename = top_dir.join(&params.filename);
-        let mut f = File::open(&params.filename)?;
+        let mut filename = top_dir.join(&params.filename);
+        let mut decompressed_size = None;
+        let mut decompressed_checksum = None;
+        let mut dir_files = None;
+        let compress = params.compress.unwrap_or(
+            if filename.is_dir()
+                || params.storage
+                || params.kind == BlobKind::Text
+                || params.type_name.is_some()
+                || params.encrypt.is_some()
+                || params.littlefs.is_some()
+            {
+                Compression::None
+            } else {
+                blob_config.flash.compress
+            },
+        );
+        let source = if filename.is_dir() {
+            None
+        } else {
+            Some(SourceInfo::of(&filename)?)
+        };
+        let source_path = filename.clone();
+        if let Some(littlefs) = &params.littlefs {
+            if !filename.is_dir() {
+                return Err(format!("littlefs blob {} is not a directory", name).into());
+            }
+            if params.inline == Some(true)
+                || params.kind == BlobKind::Text
+                || params.type_name.is_some()
+                || compress != Compression::None
+                || params.encrypt.is_some()
+            {
+                return Err(format!(
+                    "littlefs blob {} can not be inline, text, typed, compressed or encrypted",
+                    name
+                )
+                .into());
+            }
+            println!("cargo:rerun-if-changed={}", filename.display());
+            let image = littlefs::build_image(&filename, &littlefs.geometry())
+                .map_err(|e| format!("littlefs blob {}: {}", name, e))?;
+            filename = out_dir.join(format!("{}.littlefs", name));
+            File::create(&filename)?.write_all(&image)?;
+        }
+        if filename.is_dir() {
+            if params.storage
+                || params.kind == BlobKind::Text
+                || params.type_name.is_some()
+                || compress != Compression::None
+            {
+                return Err(format!(
+                    "Directory blob {} can not be storage, text, typed or compressed",
+                    name
+                )
+                .into());
+            }
+            println!("cargo:rerun-if-changed={}", filename.display());
+            let data_file = out_dir.join(format!("{}.dir", name));
+            dir_files = Some(build_dir_data(&filename, &data_file)?);
+            filename = data_file;
+        }
+        if let Some(codec) = compress.codec() {
+            if params.kind == BlobKind::Text || params.type_name.is_some() {
+                return Err(format!("Compressed blob {} can not be text or typed", name).into());
+            }
+            // The compressed data is what gets stored, so that is what
+            // the size and checksums are calculated from
+            let data = std::fs::read(&filename)?;
+            decompressed_size = Some(u32::try_from(data.len())?);
+            decompressed_checksum = Some(Sha1::from(&data).digest().bytes());
+            filename = out_dir.join(format!("{}.{}", name, codec.name()));
+            let compressed = codec.compress(&data);
+            File::create(&filename)?.write_all(&compressed)?;
+        }
+        let mut encryption = None;
+        if let Some(encrypt) = &params.encrypt {
+            if params.storage
+                || dir_files.is_some()
+                || params.kind == BlobKind::Text
+                || params.type_name.is_some()
+                || compress != Compression::None
+            {
+                return Err(format!(
+                    "Encrypted blob {} can not be storage, directory, text, typed or compressed",
+                    name
+                )
+                .into());
+            }
+            // Like with compression, the ciphertext is what gets stored
+            let key = read_encryption_key(&top_dir, &blob_config.encryption, encrypt)?;
+            let mut data = std::fs::read(&filename)?;
+            let nonce = encrypt.cipher.nonce(&key, &name, &data);
+            encrypt.cipher.apply(&key, &nonce, &mut data)?;
+            filename = out_dir.join(format!("{}.enc", name));
+            File::create(&filename)?.write_all(&data)?;
+            encryption = Some(EncryptionInfo {
+                cipher: encrypt.cipher.name().to_string(),
+                key_id: encrypt.key.clone(),
+                nonce,
+            });
+        }
+        // Text is kept while reading, to be checked as a whole
+        let mut text = (params.kind == BlobKind::Text).then(Vec::new);
+        let mut f = File::open(&filename)?;
         loop {
             let r = f.read(&mut buf)?;
             if r == 0 {
                 break;
             }
             cs.update(&buf[..r]);
+            crc.update(&buf[..r]);
+            if let Some(text) = &mut text {
+                text.extend_from_slice(&buf[..r]);
+            }
             file_size += r;
         }
-        let blob = Blob {
+        if let Some(text) = text {
+            if params.type_name.is_some() {
+                return Err(format!("Text blob {} can not have a type", name).into());
+            }
+            std::str::from_utf8(&text)
+                .map_err(|e| format!("Text blob {} is not valid UTF-8: {}", name, e))?;
+        }
+        // A filesystem starts at an erase block, which is also what its
+        // blocks are aligned to
+        let align = params
+            .align
+            .or(params.littlefs.as_ref().map(|l| l.block_size))
+            .unwrap_or(1);
+        if !align.is_power_of_two() {
+            return Err(format!("Alignment of blob {} is not a power of two", name).into());
+        }
+        // There's no memory to point into for storage blobs, so only bytes
+        // can be read
+        if params.storage
+            && (params.inline == Some(true)
+                || params.type_name.is_some()
+                || params.kind == BlobKind::Text
+                || compress != Compression::None)
+        {
+            return Err(format!(
+                "Storage blob {} can not be inline, typed, text or compressed",
+                name
+            )
+            .into());
+        }
+        let source_check = match &source {
+            Some(source) if filename != source_path => {
+                let data = std::fs::read(&source_path)?;
+                let mut crc = Crc32::new();
+                crc.update(&data);
+                Some(SourceCheck {
+                    path_expr: path_expr(&source_path, &top_dir, out_dir)?,
+                    size: u32::try_from(source.size)?,
+                    checksum: Sha1::from(&data).digest().bytes(),
+                    crc32: crc.value(),
+                })
+            }
+            _ => None,
+        };
+        let mut blob = Blob {
             name,
-            start: total_size,
+            start: if params.storage {
+                align_up(blob_config.storage.origin + storage_size, align)
+            } else {
+                align_up(total_size, align)
+            },
             size: u32::try_from(file_size)?,
             checksum: cs.digest().bytes(),
+            decompressed_checksum: decompressed_checksum.unwrap_or(cs.digest().bytes()),
+            crc32: crc.value(),
             filename: filename
                 .as_path()
                 .to_str()
                 .ok_or_else(|| "Filename can not be converted to UTF-8")?
                 .to_string(),
-            inline: params.inline.unwrap_or_else(|| {
-                if release {
-                    params.inline_release.unwrap_or(true)
-                } else {
-                    params.inline_dev.unwrap_or(false)
-                }
+            path_expr: path_expr(&filename, &top_dir, out_dir)?,
+            source_name: params.filename.clone(),
+            inline: !params.storage
+                && params.littlefs.is_none()
+                && params.inline.unwrap_or_else(|| {
+                    if release {
+                        params.inline_release.unwrap_or(true)
+                    } else {
+                        params.inline_dev.unwrap_or(false)
+                    }
+                }),
+            // The firmware writes to a filesystem, so it only matches the
+            // checksum until it is mounted
+            verify: params.verify.unwrap_or(if params.littlefs.is_some() {
+                VerifyMode::Never
+            } else {
+                blob_config.generate.verify
             }),
+            version: params.version,
+            align,
+            type_name: params.type_name,
+            kind: params.kind,
+            compress,
+            decompressed_size: decompressed_size.unwrap_or(u32::try_from(file_size)?),
+            storage: params.storage,
+            pre_read_barrier: None,
+            dir_files,
+            group: params.group,
+            external: None,
+            region: None,
+            source,
+            tags: params.tags,
+            notes: params.notes,
+            encryption,
+            littlefs: params.littlefs,
+            source_check,
         };
-        if !blob.inline {
+        if blob.storage {
+            let storage = &blob_config.storage;
+            blob.region = Some(storage.region.as_deref().unwrap_or("STORAGE").to_string());
+            blob.external = Some(ExternalFlash {
+                load_address: u64::from(
+                    blob.start - storage.origin + storage.mapped_address.unwrap_or(storage.origin),
+                ),
+                algorithm: storage.flash_algorithm.clone(),
+            });
+        }
+        if blob.loaded() {
+            blob.region = Some(FLASH_REGION.to_string());
+            blob.pre_read_barrier = blob_config.flash.pre_read_barrier.clone();
+        }
+        if blob.storage {
+            storage_size = blob.start + blob.size - blob_config.storage.origin;
+        } else if !blob.inline {
             // Only loaded blobs need space
-            total_size += u32::try_from(file_size)?;
+            total_size = blob.start + blob.size;
         }
         blobs.push(blob);
     }
-    Ok((blobs, blob_config.probe))
+    if blob_config.generate.blob_table {
+        // Make room for the table at the start of the region
+        let loaded = || blobs.iter().filter(|b| b.loaded());
+        let max_align = loaded().map(|b| b.align).max().unwrap_or(1);
+        let offset = align_up(blob_table::table_size(loaded().count()), max_align);
+        for blob in blobs.iter_mut().filter(|b| b.loaded()) {
+            blob.start += offset;
+        }
+    }
+    Ok((
+        blobs,
+        blob_config.probe,
+        blob_config.generate,
+        blob_config.blob_info,
+    ))
+}
+
+fn align_up(value: u32, align: u32) -> u32 {
+    (value + align - 1) & !(align - 1)
+}
+
+// Returns the expression deciding at runtime if a blob should be
+// verified, or None if verification is never done.
+fn verify_condition(mode: VerifyMode) -> Option<String> {
+    match mode {
+        VerifyMode::Never => None,
+        VerifyMode::DebugOnly => Some(format!(
+            "cfg!(all(debug_assertions, not(feature = \"{}\")))",
+            SKIP_VERIFY_FEATURE
+        )),
+        VerifyMode::Always => Some(format!("cfg!(not(feature = \"{}\"))", SKIP_VERIFY_FEATURE)),
+    }
 }
 
-fn build_source<F>(out_file: &mut F, blobs: &[Blob], origin: u32) -> DynResult<()>
+// Code calling the pre-read barrier of a blob the first time it's accessed
+fn pre_read_barrier(hook: Option<&str>) -> String {
+    match hook {
+        Some(hook) => format!(
+            r#"
+    static BARRIER_DONE: AtomicBool = AtomicBool::new(false);
+    if !BARRIER_DONE.load(Ordering::Relaxed) {{
+        {}();
+        BARRIER_DONE.store(true, Ordering::Relaxed);
+    }}"#,
+            hook
+        ),
+        None => String::new(),
+    }
+}
+
+// Code calculating the checksum of blob into actual
+fn checksum_calculation(algorithm: ChecksumAlgorithm) -> &'static str {
+    match algorithm {
+        ChecksumAlgorithm::Sha1 => {
+            r#"let mut m = Sha1::new();
+        m.update(blob);
+        let actual = m.digest().bytes();"#
+        }
+        ChecksumAlgorithm::Crc32 => "let actual = blob_crc32(blob);",
+    }
+}
+
+// Writes the code for the blobs in a group, or the blobs without a group
+// if group is None. Items describing the whole layout, and the types shared
+// by all groups, are only written for the latter.
+fn build_source<F>(
+    out_file: &mut F,
+    all_blobs: &[Blob],
+    origin: u32,
+    generate: &GenerateParams,
+    group: Option<&str>,
+) -> DynResult<()>
 where
     F: Write,
 {
-    out_file.write(
+    let blobs: Vec<Blob> = all_blobs
+        .iter()
+        .filter(|b| b.group.as_deref() == group)
+        .cloned()
+        .collect();
+    let blobs = &blobs[..];
+    let vis = generate.visibility();
+    // Group files are modules next to the file without a group, and use the
+    // types defined there
+    let module = group.or(generate.module.as_deref());
+    if let Some(module) = module {
+        out_file.write_all(
+            format!(
+                r#"
+{} mod {} {{
+#[allow(unused_imports)]
+use super::*;
+"#,
+                vis, module
+            )
+            .as_bytes(),
+        )?;
+        if let (Some(_), Some(parent)) = (group, &generate.module) {
+            out_file.write_all(
+                format!("#[allow(unused_imports)]\nuse super::{}::*;\n", parent).as_bytes(),
+            )?;
+        }
+    }
+    out_file.write_all(
         r#"
+#[allow(unused_imports)]
 use core::slice;
-use sha1_smol::Sha1;
+#[allow(unused_imports)]
+use core::sync::atomic::{AtomicBool, Ordering};
 "#
         .as_bytes(),
     )?;
+    if generate.checksum == ChecksumAlgorithm::Sha1 {
+        out_file.write_all(b"#[allow(unused_imports)]\nuse sha1_smol::Sha1;\n")?;
+    }
+    let return_type = match generate.errors {
+        ErrorMode::Panic | ErrorMode::Hook => "&'static [u8]",
+        ErrorMode::Result => "Result<&'static [u8], BlobError>",
+    };
+    if group.is_none() {
+        build_shared_items(out_file, all_blobs, return_type, generate)?;
+    }
     for blob in blobs {
-        if blob.inline {
-            out_file.write(
+        if blob.storage {
+            build_storage_accessor(out_file, blob, generate)?;
+            build_constants(out_file, blob, origin, generate)?;
+            build_littlefs_storage(out_file, blob, generate)?;
+            continue;
+        }
+        let (failure, ret) = match generate.errors {
+            ErrorMode::Panic => (
+                format!("panic!(\"Checksum check failed for {}\");", blob.name),
+                "blob",
+            ),
+            ErrorMode::Result => (
+                format!(
+                    "return Err(BlobError {{ name: \"{}\", expected: checksum, actual }});",
+                    blob.name
+                ),
+                "Ok(blob)",
+            ),
+            ErrorMode::Hook => (
+                format!("blob_integrity_failure(\"{}\");", blob.name),
+                "blob",
+            ),
+        };
+        let doc = blob.doc_comment(&format!("Data of blob `{}`", blob.name), origin);
+        out_file.write_all(doc.as_bytes())?;
+        if blob.inline && (blob.align > 1 || blob.type_name.is_some()) {
+            out_file.write_all(
                 format!(
                     r#"
-pub fn {0}() ->  &'static [u8] {{
-include_bytes!("{1}")
+{vis} fn {0}() -> {2} {{
+    #[repr(C, align({4}))]
+    struct Aligned<A, B: ?Sized> {{
+        _align: [A; 0],
+        bytes: B,
+    }}
+    static DATA: &Aligned<{6}, [u8; {5}]> = &Aligned {{
+        _align: [],
+        bytes: *include_bytes!({1}),
+    }};
+    let blob: &'static [u8] = &DATA.bytes;
+    {3}
 }}"#,
-                    blob.name, blob.filename,
+                    blob.bytes_accessor(),
+                    blob.path_expr,
+                    return_type,
+                    ret,
+                    blob.align,
+                    blob.size,
+                    blob.type_name.as_deref().unwrap_or("u8"),
+                    vis = vis,
                 )
                 .as_bytes(),
             )?;
-        } else {
-            out_file.write(
+        } else if blob.inline {
+            out_file.write_all(
                 format!(
                     r#"
-pub fn {0}() ->  &'static [u8] {{
-    let blob = unsafe{{slice::from_raw_parts(0x{1:x} as *const u8, {2})}}
-;
-    let mut m = Sha1::new();
-    let checksum:[u8;20] = [{3}];
-    m.update(blob);
-    if &m.digest().bytes() != &checksum {{
-        panic!("Checksum check failed for {0}");
-    }}
-    blob
+{vis} fn {0}() -> {2} {{
+    let blob: &'static [u8] = include_bytes!({1});
+    {3}
 }}"#,
-                    blob.name,
-                    blob.start + origin,
-                    blob.size,
-                    blob.checksum.map(|v| v.to_string()).join(","),
+                    blob.bytes_accessor(),
+                    blob.path_expr,
+                    return_type,
+                    ret,
+                    vis = vis,
                 )
                 .as_bytes(),
             )?;
+        } else {
+            // Loaded blobs are read from the original file when not running on the target
+            out_file.write_all(b"\n#[cfg(target_os = \"none\")]")?;
+            if let Some(condition) = verify_condition(blob.verify) {
+                out_file.write_all(
+                    format!(
+                        r#"
+{vis} fn {0}() -> {5} {{{barrier}
+    let blob = unsafe {{ slice::from_raw_parts(0x{1:x} as *const u8, {2}) }};
+    // Only verify on first access
+    static VERIFIED: AtomicBool = AtomicBool::new(false);
+    if {4} && !VERIFIED.load(Ordering::Relaxed) {{
+        #[cfg(feature = "defmt")]
+        defmt::trace!("Verifying blob {{=str}}", "{0}");
+        let checksum: BlobChecksum = {3};
+        {8}
+        if actual != checksum {{
+            #[cfg(feature = "defmt")]
+            defmt::error!("Checksum mismatch for blob {{=str}}", "{0}");
+            {6}
+        }}
+        VERIFIED.store(true, Ordering::Relaxed);
+    }}
+    {7}
+}}"#,
+                        blob.bytes_accessor(),
+                        blob.address(origin),
+                        blob.size,
+                        blob.checksum_literal(generate.checksum),
+                        condition,
+                        return_type,
+                        failure,
+                        ret,
+                        checksum_calculation(generate.checksum),
+                        vis = vis,
+                        barrier = pre_read_barrier(blob.pre_read_barrier.as_deref()),
+                    )
+                    .as_bytes(),
+                )?;
+            } else {
+                out_file.write_all(
+                    format!(
+                        r#"
+{vis} fn {0}() -> {3} {{{barrier}
+    let blob = unsafe {{ slice::from_raw_parts(0x{1:x} as *const u8, {2}) }};
+    {4}
+}}"#,
+                        blob.bytes_accessor(),
+                        blob.address(origin),
+                        blob.size,
+                        return_type,
+                        ret,
+                        vis = vis,
+                        barrier = pre_read_barrier(blob.pre_read_barrier.as_deref()),
+                    )
+                    .as_bytes(),
+                )?;
+            }
+            out_file.write_all(doc.as_bytes())?;
+            build_host_accessor(out_file, blob, return_type, ret, generate)?;
+        }
+        build_constants(out_file, blob, origin, generate)?;
+        build_views(out_file, blob, origin, generate)?;
+        if generate.arrays {
+            build_array_accessor(out_file, blob, generate)?;
+        }
+        if let Some(type_name) = &blob.type_name {
+            let doc = blob.doc_comment(&format!("Blob `{}` as `{}`", blob.name, type_name), origin);
+            build_typed_accessor(out_file, blob, origin, generate, &doc)?;
+        }
+        if blob.kind == BlobKind::Text {
+            let doc = blob.doc_comment(&format!("Text of blob `{}`", blob.name), origin);
+            build_text_accessor(out_file, blob, generate, &doc)?;
+        }
+        if blob.compress != Compression::None {
+            build_decompress_accessor(out_file, blob, generate)?;
+        }
+        if blob.encryption.is_some() {
+            build_decrypt_accessor(out_file, blob, generate)?;
+        }
+        if generate.embedded_storage || generate.embedded_io {
+            build_reader_accessor(out_file, blob, generate)?;
+        }
+        if blob.dir_files.is_some() {
+            build_dir(out_file, blob, generate)?;
         }
+        build_littlefs_storage(out_file, blob, generate)?;
+    }
+    if generate.descriptors || generate.registry {
+        build_descriptors(out_file, blobs, origin, generate)?;
+    }
+    if generate.registry {
+        build_registry(out_file, blobs, generate)?;
+    }
+    if generate.blob_table && group.is_none() {
+        build_table_reader(out_file, all_blobs, origin, generate)?;
+    }
+    if generate.lookup {
+        build_lookup(out_file, blobs, generate)?;
+    }
+    if generate.blob_index {
+        build_index_records(out_file, blobs, origin)?;
+    }
+    if generate.verify_all {
+        build_verify_all(out_file, blobs, origin, generate)?;
+    }
+    if generate.host_tests {
+        build_host_tests(out_file, blobs, generate)?;
+    }
+    if module.is_some() {
+        out_file.write_all(b"}\n")?;
     }
     Ok(())
 }
 
-fn build_link_script<I, O>(in_file: &mut I, out_file: &mut O, length: i64) -> DynResult<i64>
+// Writes the types and helpers shared by the blobs of all groups, which
+// the group files use from the file without a group
+fn build_shared_items<F>(
+    out_file: &mut F,
+    blobs: &[Blob],
+    return_type: &str,
+    generate: &GenerateParams,
+) -> DynResult<()>
 where
-    I: Read,
-    O: Write,
+    F: Write,
 {
-    let mut in_buf = String::new();
-    in_file.read_to_string(&mut in_buf)?;
-    let (after, (before, (name, attr, origin, flash_length))) =
-        link_script_parser::find_memory_def(&in_buf, "FLASH")
-            .map_err(|e| format!("Failed to parse link script: {}", e))?;
-    let mut out_buf = before.to_string();
-    out_buf += &format!(
-        "{} {}: ORIGIN = 0x{:x}, LENGTH = 0x{:x}",
-        name,
-        if let Some(attr) = attr {
-            format!("({})", attr)
-        } else {
-            "".to_string()
-        },
-        origin,
-        flash_length - length
-    );
-    out_buf += after;
-    out_file.write_all(out_buf.as_bytes())?;
-    Ok(origin + flash_length - length)
+    let vis = generate.visibility();
+    match generate.checksum {
+        ChecksumAlgorithm::Sha1 => {
+            out_file.write_all(format!("\n{} type BlobChecksum = [u8; 20];\n", vis).as_bytes())?;
+        }
+        ChecksumAlgorithm::Crc32 => {
+            out_file.write_all(format!("\n{} type BlobChecksum = u32;\n", vis).as_bytes())?;
+            if let Some(hook) = &generate.crc32_hook {
+                out_file.write_all(
+                    format!(
+                        r#"
+#[allow(dead_code)]
+pub(crate) fn blob_crc32(data: &[u8]) -> u32 {{
+    {}(data)
+}}
+"#,
+                        hook
+                    )
+                    .as_bytes(),
+                )?;
+            } else {
+                out_file.write_all(b"\n#[allow(dead_code)]")?;
+                out_file.write_all(CRC32_SOURCE.as_bytes())?;
+            }
+        }
+    }
+    match generate.errors {
+        ErrorMode::Panic => {}
+        ErrorMode::Result => {
+            out_file.write_all(
+                format!(
+                    r#"
+/// Returned by blob accessors when the checksum of a blob doesn't match
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+{} struct BlobError {{
+    pub name: &'static str,
+    pub expected: BlobChecksum,
+    pub actual: BlobChecksum,
+}}
+"#,
+                    vis
+                )
+                .as_bytes(),
+            )?;
+        }
+        ErrorMode::Hook => {
+            if let Some(hook) = &generate.failure_hook {
+                out_file.write_all(
+                    format!(
+                        r#"
+#[allow(dead_code)]
+pub(crate) fn blob_integrity_failure(name: &str) -> ! {{
+    {}(name)
+}}
+"#,
+                        hook
+                    )
+                    .as_bytes(),
+                )?;
+            } else {
+                out_file.write_all(
+                    r#"
+unsafe extern "Rust" {
+    /// Called when a blob doesn't match its checksum. Has to be defined
+    /// with #[no_mangle] by the user.
+    #[link_name = "blob_integrity_failure"]
+    fn blob_integrity_failure_extern(name: &str) -> !;
 }
 
-fn env_dir(var_name: &str) -> DynResult<PathBuf> {
-    Ok(PathBuf::from(env::var(var_name).map_err(|_| {
-        format!("Environment variable '{}' not found", var_name)
-    })?))
+#[allow(dead_code)]
+pub(crate) fn blob_integrity_failure(name: &str) -> ! {
+    unsafe { blob_integrity_failure_extern(name) }
+}
+"#
+                    .as_bytes(),
+                )?;
+            }
+        }
+    }
+    if blobs.iter().any(|b| b.compress != Compression::None) {
+        out_file.write_all(
+            format!(
+                r#"
+/// Returned when decompressing a blob fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+{} enum DecompressError {{
+    /// The buffer is smaller than the decompressed size
+    BufferTooSmall,
+    /// The compressed data is malformed
+    Corrupt,
+    /// The compressed data doesn't match its checksum
+    Checksum,
+}}
+"#,
+                vis
+            )
+            .as_bytes(),
+        )?;
+    }
+    if blobs.iter().any(|b| b.compress == Compression::Heatshrink) {
+        out_file.write_all(
+            format!("\n#[cfg(feature = \"{}\")]", Codec::Heatshrink.feature()).as_bytes(),
+        )?;
+        out_file.write_all(HEATSHRINK_SOURCE.as_bytes())?;
+    }
+    if blobs.iter().any(|b| b.encryption.is_some()) {
+        build_key_provider(out_file, generate)?;
+    }
+    if blobs.iter().any(|b| b.storage) {
+        build_storage_trait(out_file, generate)?;
+    }
+    if generate.embedded_storage || generate.embedded_io {
+        build_reader_type(out_file, generate)?;
+    }
+    if blobs.iter().any(|b| b.dir_files.is_some()) {
+        build_dir_type(out_file, return_type, generate)?;
+    }
+    if generate.descriptors || generate.registry {
+        build_descriptor_type(out_file, return_type, generate)?;
+    }
+    if generate.lookup {
+        build_lookup_type(out_file, return_type, generate)?;
+    }
+    if generate.blob_index {
+        build_index_record_type(out_file, generate)?;
+    }
+    if generate.verify_all {
+        build_verify_types(out_file, generate)?;
+    }
+    Ok(())
 }
 
-fn env_str(var_name: &str) -> DynResult<String> {
-    Ok(env::var(var_name).map_err(|_| format!("Environment variable '{}' not found", var_name))?)
+// Writes the trait the user implements for reading flash that is not
+// memory mapped, and the error returned when reading through it
+fn build_storage_trait<F>(out_file: &mut F, generate: &GenerateParams) -> DynResult<()>
+where
+    F: Write,
+{
+    out_file.write_all(
+        format!(
+            r#"
+/// Flash holding storage blobs that can't be accessed through pointers,
+/// e.g. external SPI flash. Implemented by the user.
+{0} trait BlobStorage {{
+    type Error;
+    /// Fills buf with the data starting at addr
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+}}
+{1}
+/// Returned when reading or verifying a storage blob
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+{0} enum BlobStorageError<E> {{
+    /// Reading from the storage failed
+    Storage(E),
+    /// The read extends past the end of the blob
+    OutOfRange,
+    /// The blob doesn't match its checksum
+    Checksum {{
+        name: &'static str,
+        expected: BlobChecksum,
+        actual: BlobChecksum,
+    }},
+}}
+"#,
+            generate.visibility(),
+            if generate.async_storage {
+                format!(
+                    r#"
+/// Async version of BlobStorage, for drivers that shouldn't block the
+/// executor while reading
+#[allow(async_fn_in_trait)]
+{} trait BlobStorageAsync {{
+    type Error;
+    /// Fills buf with the data starting at addr
+    async fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+}}
+"#,
+                    generate.visibility()
+                )
+            } else {
+                String::new()
+            }
+        )
+        .as_bytes(),
+    )?;
+    if generate.checksum == ChecksumAlgorithm::Crc32 {
+        // Always the built-in version since a hook can't be called in pieces
+        out_file.write_all(b"\n#[allow(dead_code)]")?;
+        out_file.write_all(CRC32_UPDATE_SOURCE.as_bytes())?;
+    }
+    Ok(())
 }
 
-fn build_blob_info<O>(out_file: &mut O, blobs: &[Blob], origin: u32, chip: &str) -> DynResult<()>
+// Writes functions reading and verifying a blob through BlobStorage, and
+// BlobStorageAsync if enabled
+fn build_storage_accessor<F>(
+    out_file: &mut F,
+    blob: &Blob,
+    generate: &GenerateParams,
+) -> DynResult<()>
 where
-    O: Write,
+    F: Write,
 {
-    let mut info = HashMap::<String, BlobInfo>::new();
-    for blob in blobs {
-        if !blob.inline {
-            info.insert(
-                blob.name.to_string(),
-                BlobInfo {
-                    size: blob.size,
-                    checksum: blob.checksum,
-                    start: blob.start + origin,
-                    filename: blob.filename.clone(),
-                },
-            );
-        }
+    let doc = blob.doc_comment(&format!("Reads from blob `{}`", blob.name), 0);
+    out_file.write_all(doc.as_bytes())?;
+    build_storage_functions(out_file, blob, generate, false)?;
+    if generate.async_storage {
+        out_file.write_all(
+            format!("\n/// Reads from blob `{}` without blocking", blob.name).as_bytes(),
+        )?;
+        build_storage_functions(out_file, blob, generate, true)?;
     }
-    let buf = toml::to_vec(&BlobInfoFile {
-        info,
-        probe: ProbeInfo {
-            chip: chip.to_string(),
-        },
-    })?;
-    out_file.write(&buf)?;
     Ok(())
 }
 
-pub fn prepare_blob() -> DynResult<()> {
-    let top_dir = env_dir("CARGO_MANIFEST_DIR")?;
+// Writes the read and verify functions of a storage blob. The checksum is
+// calculated in chunks so no buffer for the whole blob is needed.
+fn build_storage_functions<F>(
+    out_file: &mut F,
+    blob: &Blob,
+    generate: &GenerateParams,
+    is_async: bool,
+) -> DynResult<()>
+where
+    F: Write,
+{
+    let (async_kw, suffix, storage_trait, await_kw) = if is_async {
+        ("async ", "_async", "BlobStorageAsync", ".await")
+    } else {
+        ("", "", "BlobStorage", "")
+    };
+    let (init, update, finish) = match generate.checksum {
+        ChecksumAlgorithm::Sha1 => (
+            "let mut m = Sha1::new();",
+            "m.update(chunk);",
+            "\n    let actual = m.digest().bytes();",
+        ),
+        ChecksumAlgorithm::Crc32 => (
+            "let mut actual = 0;",
+            "actual = blob_crc32_update(actual, chunk);",
+            "",
+        ),
+    };
+    out_file.write_all(
+        format!(
+            r#"
+///
+/// Fills buf with the data starting at offset within the blob.
+{vis} {async_kw}fn {name}_read{suffix}<S: {storage_trait}>(
+    storage: &mut S,
+    offset: usize,
+    buf: &mut [u8],
+) -> Result<(), BlobStorageError<S::Error>> {{
+    if offset > {size} || buf.len() > {size} - offset {{
+        return Err(BlobStorageError::OutOfRange);
+    }}
+    storage
+        .read(0x{addr:x} + offset as u32, buf){await_kw}
+        .map_err(BlobStorageError::Storage)
+}}
+
+/// Checks blob `{name}` against its checksum by reading it through storage
+{vis} {async_kw}fn {name}_verify{suffix}<S: {storage_trait}>(
+    storage: &mut S,
+) -> Result<(), BlobStorageError<S::Error>> {{
+    let checksum: BlobChecksum = {checksum};
+    let mut buf = [0u8; 64];
+    let mut offset = 0;
+    {init}
+    while offset < {size} {{
+        let len = ({size} - offset).min(buf.len());
+        let chunk = &mut buf[..len];
+        {name}_read{suffix}(storage, offset, chunk){await_kw}?;
+        {update}
+        offset += len;
+    }}{finish}
+    if actual != checksum {{
+        return Err(BlobStorageError::Checksum {{
+            name: "{name}",
+            expected: checksum,
+            actual,
+        }});
+    }}
+    Ok(())
+}}
+"#,
+            vis = generate.visibility(),
+            name = blob.name,
+            size = blob.size,
+            addr = blob.start,
+            checksum = blob.checksum_literal(generate.checksum),
+            init = init,
+            update = update,
+            finish = finish,
+            async_kw = async_kw,
+            suffix = suffix,
+            storage_trait = storage_trait,
+            await_kw = await_kw,
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Writes Dir, giving access to the files of directory blobs
+fn build_dir_type<F>(
+    out_file: &mut F,
+    return_type: &str,
+    generate: &GenerateParams,
+) -> DynResult<()>
+where
+    F: Write,
+{
+    let (get_type, get) = match generate.errors {
+        ErrorMode::Panic | ErrorMode::Hook => (
+            "Option<&'static [u8]>",
+            r#"let file = self.get_file(path)?;
+        let data = (self.data)();
+        Some(&data[file.offset..file.offset + file.len])"#,
+        ),
+        ErrorMode::Result => (
+            "Result<Option<&'static [u8]>, BlobError>",
+            r#"let Some(file) = self.get_file(path) else {
+            return Ok(None);
+        };
+        let data = (self.data)()?;
+        Ok(Some(&data[file.offset..file.offset + file.len]))"#,
+        ),
+    };
+    out_file.write_all(
+        format!(
+            r#"
+/// File in a directory blob
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+{vis} struct DirFile {{
+    /// Path relative to the directory, with / as separator
+    pub path: &'static str,
+    pub offset: usize,
+    pub len: usize,
+}}
+
+/// Directory blob, with the files stored after each other
+#[derive(Clone, Copy)]
+{vis} struct Dir {{
+    pub(crate) files: &'static [DirFile],
+    pub(crate) data: fn() -> {ret},
+}}
+
+impl Dir {{
+    /// All files, sorted by path
+    pub fn files(&self) -> &'static [DirFile] {{
+        self.files
+    }}
+
+    pub fn get_file(&self, path: &str) -> Option<&'static DirFile> {{
+        let index = self.files.binary_search_by(|f| f.path.cmp(path)).ok()?;
+        Some(&self.files[index])
+    }}
+
+    /// Contents of the file with the given path, verified in the same
+    /// way as the blob accessor
+    pub fn get(&self, path: &str) -> {get_type} {{
+        {get}
+    }}
+}}
+"#,
+            vis = generate.visibility(),
+            ret = return_type,
+            get_type = get_type,
+            get = get,
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Writes the Dir of a directory blob
+fn build_dir<F>(out_file: &mut F, blob: &Blob, generate: &GenerateParams) -> DynResult<()>
+where
+    F: Write,
+{
+    let Some(files) = &blob.dir_files else {
+        return Ok(());
+    };
+    let files = files
+        .iter()
+        .map(|f| {
+            format!(
+                "\n        DirFile {{ path: {:?}, offset: {}, len: {} }},",
+                f.path, f.offset, f.len
+            )
+        })
+        .collect::<String>();
+    out_file.write_all(
+        format!(
+            r#"
+/// Files of directory blob `{4}`
+{3} static {1}_DIR: Dir = Dir {{
+    files: &[{2}
+    ],
+    data: {0},
+}};
+"#,
+            blob.bytes_accessor(),
+            blob.name.to_uppercase(),
+            files,
+            generate.visibility(),
+            blob.name
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Writes BlobReader, giving drivers that use the embedded-storage or
+// embedded-io traits access to blob data
+fn build_reader_type<F>(out_file: &mut F, generate: &GenerateParams) -> DynResult<()>
+where
+    F: Write,
+{
+    let vis = generate.visibility();
+    out_file.write_all(
+        format!(
+            r#"
+/// Reads the data of a blob through the embedded-storage and embedded-io
+/// traits
+#[derive(Debug, Clone, Copy)]
+{0} struct BlobReader {{
+    data: &'static [u8],
+    pos: usize,
+}}
+
+impl BlobReader {{
+    pub const fn new(data: &'static [u8]) -> BlobReader {{
+        BlobReader {{ data, pos: 0 }}
+    }}
+}}
+
+/// Returned by BlobReader when reading or seeking outside the blob
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+{0} struct BlobReadError;
+"#,
+            vis
+        )
+        .as_bytes(),
+    )?;
+    if generate.embedded_storage {
+        out_file.write_all(
+            br#"
+impl embedded_storage::ReadStorage for BlobReader {
+    type Error = BlobReadError;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), BlobReadError> {
+        let offset = offset as usize;
+        if offset > self.data.len() || bytes.len() > self.data.len() - offset {
+            return Err(BlobReadError);
+        }
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+"#,
+        )?;
+    }
+    if generate.embedded_io {
+        out_file.write_all(
+            br#"
+impl embedded_io::Error for BlobReadError {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        embedded_io::ErrorKind::InvalidInput
+    }
+}
+
+impl embedded_io::ErrorType for BlobReader {
+    type Error = BlobReadError;
+}
+
+impl embedded_io::Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, BlobReadError> {
+        let rest = self.data.get(self.pos..).unwrap_or_default();
+        let len = rest.len().min(buf.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl embedded_io::Seek for BlobReader {
+    fn seek(&mut self, pos: embedded_io::SeekFrom) -> Result<u64, BlobReadError> {
+        let pos = match pos {
+            embedded_io::SeekFrom::Start(offset) => Some(offset),
+            embedded_io::SeekFrom::End(offset) => {
+                (self.data.len() as u64).checked_add_signed(offset)
+            }
+            embedded_io::SeekFrom::Current(offset) => (self.pos as u64).checked_add_signed(offset),
+        };
+        // Seeking past the end is allowed, reads then return 0 bytes
+        self.pos = pos
+            .and_then(|pos| usize::try_from(pos).ok())
+            .ok_or(BlobReadError)?;
+        Ok(self.pos as u64)
+    }
+}
+"#,
+        )?;
+    }
+    Ok(())
+}
+
+// Writes a function returning a BlobReader for the blob
+fn build_reader_accessor<F>(
+    out_file: &mut F,
+    blob: &Blob,
+    generate: &GenerateParams,
+) -> DynResult<()>
+where
+    F: Write,
+{
+    if blob.storage {
+        return Ok(());
+    }
+    let (return_type, reader) = match generate.errors {
+        ErrorMode::Panic | ErrorMode::Hook => ("BlobReader", "BlobReader::new(blob)"),
+        ErrorMode::Result => ("Result<BlobReader, BlobError>", "blob.map(BlobReader::new)"),
+    };
+    out_file.write_all(
+        format!(
+            r#"
+/// Reader for the data of blob `{0}`, verified in the same way as {1}()
+{4} fn {0}_reader() -> {2} {{
+    let blob = {1}();
+    {3}
+}}
+"#,
+            blob.name,
+            blob.bytes_accessor(),
+            return_type,
+            reader,
+            generate.visibility()
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Writes a variant of the accessor for loaded blobs that is used when
+// not running on the target, e.g. for unit tests on the host
+fn build_host_accessor<F>(
+    out_file: &mut F,
+    blob: &Blob,
+    return_type: &str,
+    ret: &str,
+    generate: &GenerateParams,
+) -> DynResult<()>
+where
+    F: Write,
+{
+    let align = if let Some(type_name) = &blob.type_name {
+        format!(
+            "core::mem::align_of::<{0}>().max({1})",
+            type_name, blob.align
+        )
+    } else {
+        blob.align.to_string()
+    };
+    out_file.write_all(
+        format!(
+            r#"
+#[cfg(not(target_os = "none"))]
+{5} fn {0}() -> {2} {{
+    extern crate std;
+    static DATA: std::sync::OnceLock<&'static [u8]> = std::sync::OnceLock::new();
+    let blob: &'static [u8] = DATA.get_or_init(|| {{
+        let data = std::fs::read({1})
+            .expect(concat!("Failed to read blob file ", {1}));
+        // Copy to leaked memory with the same alignment as on the target
+        let layout = std::alloc::Layout::from_size_align(
+            data.len().max(1),
+            {4},
+        )
+        .unwrap();
+        unsafe {{
+            let ptr = std::alloc::alloc(layout);
+            assert!(!ptr.is_null());
+            ptr.copy_from_nonoverlapping(data.as_ptr(), data.len());
+            core::slice::from_raw_parts(ptr, data.len())
+        }}
+    }});
+    {3}
+}}"#,
+            blob.bytes_accessor(),
+            blob.path_expr,
+            return_type,
+            ret,
+            align,
+            generate.visibility()
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Writes constants describing the blob, so that they can be used without
+// calling the accessor
+fn build_constants<F>(
+    out_file: &mut F,
+    blob: &Blob,
+    origin: u32,
+    generate: &GenerateParams,
+) -> DynResult<()>
+where
+    F: Write,
+{
+    let vis = generate.visibility();
+    let const_name = blob.name.to_uppercase();
+    out_file.write_all(b"\n")?;
+    if !blob.inline {
+        out_file.write_all(
+            format!(
+                "{} const {}_ADDR: usize = 0x{:x};\n",
+                vis,
+                const_name,
+                blob.address(origin)
+            )
+            .as_bytes(),
+        )?;
+    }
+    out_file.write_all(
+        format!(
+            r#"{4} const {0}_SIZE: usize = {1};
+{4} const {0}_SHA1: [u8; 20] = [{2}];
+{4} const {0}_ALIGN: usize = {3};
+"#,
+            const_name,
+            blob.size,
+            blob.checksum.map(|v| v.to_string()).join(", "),
+            blob.align,
+            vis
+        )
+        .as_bytes(),
+    )?;
+    if generate.checksum == ChecksumAlgorithm::Crc32 {
+        out_file.write_all(
+            format!(
+                "{} const {}_CRC32: u32 = 0x{:08x};\n",
+                vis, const_name, blob.crc32
+            )
+            .as_bytes(),
+        )?;
+    }
+    if blob.compress != Compression::None {
+        out_file.write_all(
+            format!(
+                "{} const {}_DECOMPRESSED_SIZE: usize = {};\n",
+                vis, const_name, blob.decompressed_size
+            )
+            .as_bytes(),
+        )?;
+    }
+    if let Some(encryption) = &blob.encryption {
+        out_file.write_all(
+            format!(
+                "{0} const {1}_KEY_ID: &str = {2:?};\n{0} const {1}_NONCE: [u8; {3}] = [{4}];\n",
+                vis,
+                const_name,
+                encryption.key_id,
+                encryption.nonce.len(),
+                encryption
+                    .nonce
+                    .iter()
+                    .map(|b| format!("0x{:02x}", b))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+            .as_bytes(),
+        )?;
+    }
+    if let Some(littlefs) = &blob.littlefs {
+        out_file.write_all(
+            format!(
+                "{0} const {1}_BLOCK_SIZE: usize = {2};\n{0} const {1}_BLOCK_COUNT: usize = {3};\n",
+                vis, const_name, littlefs.block_size, littlefs.block_count
+            )
+            .as_bytes(),
+        )?;
+    }
+    Ok(())
+}
+
+// Writes a function decompressing the blob into a buffer. The
+// decompressor is only included when the corresponding feature is enabled.
+fn build_decompress_accessor<F>(
+    out_file: &mut F,
+    blob: &Blob,
+    generate: &GenerateParams,
+) -> DynResult<()>
+where
+    F: Write,
+{
+    let Some(codec) = blob.compress.codec() else {
+        return Ok(());
+    };
+    let decompress = match codec {
+        Codec::Lz4 => "lz4_flex::block::decompress_into(data, buf)",
+        Codec::Heatshrink => "blob_heatshrink_decompress(data, buf)",
+        Codec::Deflate => {
+            "miniz_oxide::inflate::decompress_slice_iter_to_slice(buf, core::iter::once(data), false, true)"
+        }
+    };
+    let cfg = format!("\n#[cfg(feature = \"{}\")]", codec.feature());
+    let data = match generate.errors {
+        ErrorMode::Panic | ErrorMode::Hook => format!("{}()", blob.bytes_accessor()),
+        ErrorMode::Result => format!(
+            "{}().map_err(|_| DecompressError::Checksum)?",
+            blob.bytes_accessor()
+        ),
+    };
+    out_file.write_all(
+        format!(
+            r#"{0}
+{5} fn {1}_into(buf: &mut [u8]) -> Result<&[u8], DecompressError> {{
+    let data = {2};
+    if buf.len() < {3}_DECOMPRESSED_SIZE {{
+        return Err(DecompressError::BufferTooSmall);
+    }}
+    let buf = &mut buf[..{3}_DECOMPRESSED_SIZE];
+    match {4} {{
+        Ok({3}_DECOMPRESSED_SIZE) => Ok(buf),
+        _ => Err(DecompressError::Corrupt),
+    }}
+}}
+"#,
+            cfg,
+            blob.name,
+            data,
+            blob.name.to_uppercase(),
+            decompress,
+            generate.visibility()
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Writes the trait the firmware supplies the keys of encrypted blobs
+// through, and the error returned when decrypting fails
+fn build_key_provider<F>(out_file: &mut F, generate: &GenerateParams) -> DynResult<()>
+where
+    F: Write,
+{
+    out_file.write_all(
+        format!(
+            r#"
+/// Supplies the keys of encrypted blobs, e.g. from OTP memory or a secure
+/// element. Implemented by the user.
+{0} trait BlobKeyProvider {{
+    /// Fills key with the key named key_id. Returns false if there is no
+    /// such key.
+    fn key(&self, key_id: &str, key: &mut [u8]) -> bool;
+}}
+
+/// Returned when decrypting a blob fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+{0} enum DecryptError {{
+    /// The buffer is smaller than the blob
+    BufferTooSmall,
+    /// The key provider doesn't have the key of the blob
+    NoKey,
+    /// The encrypted data doesn't match its checksum
+    Checksum,
+}}
+"#,
+            generate.visibility()
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Writes a function decrypting the blob into a buffer, with the key from a
+// BlobKeyProvider. The cipher is only included when the corresponding
+// feature is enabled.
+fn build_decrypt_accessor<F>(
+    out_file: &mut F,
+    blob: &Blob,
+    generate: &GenerateParams,
+) -> DynResult<()>
+where
+    F: Write,
+{
+    let Some(cipher) = blob.cipher() else {
+        return Ok(());
+    };
+    let (cipher_crate, cipher_type) = match cipher {
+        Cipher::Aes128Ctr => ("ctr", "ctr::Ctr128BE::<aes::Aes128>"),
+        Cipher::ChaCha20 => ("chacha20", "chacha20::ChaCha20"),
+    };
+    let data = match generate.errors {
+        ErrorMode::Panic | ErrorMode::Hook => format!("{}()", blob.bytes_accessor()),
+        ErrorMode::Result => format!(
+            "{}().map_err(|_| DecryptError::Checksum)?",
+            blob.bytes_accessor()
+        ),
+    };
+    out_file.write_all(
+        format!(
+            r#"
+#[cfg(feature = "{0}")]
+{1} fn {2}_decrypt_into<'a>(
+    keys: &impl BlobKeyProvider,
+    buf: &'a mut [u8],
+) -> Result<&'a [u8], DecryptError> {{
+    use {3}::cipher::{{KeyIvInit, StreamCipher}};
+    let data = {4};
+    let buf = buf.get_mut(..data.len()).ok_or(DecryptError::BufferTooSmall)?;
+    let mut key = [0u8; {5}];
+    if !keys.key({6}_KEY_ID, &mut key) {{
+        return Err(DecryptError::NoKey);
+    }}
+    buf.copy_from_slice(data);
+    {7}::new(&key.into(), &{6}_NONCE.into()).apply_keystream(buf);
+    Ok(buf)
+}}
+"#,
+            cipher.feature(),
+            generate.visibility(),
+            blob.name,
+            cipher_crate,
+            data,
+            cipher.key_len(),
+            blob.name.to_uppercase(),
+            cipher_type
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Writes a littlefs2 storage backend for the filesystem in a littlefs blob
+// that asks for one. It goes through a NorFlash driver, since littlefs
+// writes to the region.
+fn build_littlefs_storage<F>(
+    out_file: &mut F,
+    blob: &Blob,
+    generate: &GenerateParams,
+) -> DynResult<()>
+where
+    F: Write,
+{
+    let Some(littlefs) = blob.littlefs.as_ref().filter(|l| l.littlefs2) else {
+        return Ok(());
+    };
+    let geometry = littlefs.geometry();
+    out_file.write_all(
+        format!(
+            r#"
+/// littlefs2 storage for the filesystem in blob `{name}`, reading and
+/// writing through a NorFlash driver
+{vis} struct {type_name}<F> {{
+    {vis} flash: F,
+    /// Offset of the filesystem in the flash, as passed to the driver
+    {vis} offset: u32,
+}}
+
+impl<F: embedded_storage::nor_flash::NorFlash> {type_name}<F> {{
+    /// flash_origin is the address the offsets of the driver start at,
+    /// e.g. 0x0800_0000 for the internal flash of an STM32
+    {vis} fn new(flash: F, flash_origin: usize) -> Self {{
+        debug_assert!({prog_size} % F::WRITE_SIZE == 0 && {const_name}_BLOCK_SIZE % F::ERASE_SIZE == 0);
+        Self {{
+            flash,
+            offset: ({const_name}_ADDR - flash_origin) as u32,
+        }}
+    }}
+}}
+
+impl<F: embedded_storage::nor_flash::NorFlash> littlefs2::driver::Storage for {type_name}<F> {{
+    const READ_SIZE: usize = {prog_size};
+    const WRITE_SIZE: usize = {prog_size};
+    const BLOCK_SIZE: usize = {const_name}_BLOCK_SIZE;
+    const BLOCK_COUNT: usize = {const_name}_BLOCK_COUNT;
+    type CACHE_SIZE = littlefs2::consts::U{cache_size};
+    type LOOKAHEAD_SIZE = littlefs2::consts::U1;
+
+    fn read(&mut self, off: usize, buf: &mut [u8]) -> littlefs2::io::Result<usize> {{
+        embedded_storage::nor_flash::ReadNorFlash::read(&mut self.flash, self.offset + off as u32, buf)
+            .map_err(|_| littlefs2::io::Error::Io)?;
+        Ok(buf.len())
+    }}
+
+    fn write(&mut self, off: usize, data: &[u8]) -> littlefs2::io::Result<usize> {{
+        embedded_storage::nor_flash::NorFlash::write(&mut self.flash, self.offset + off as u32, data)
+            .map_err(|_| littlefs2::io::Error::Io)?;
+        Ok(data.len())
+    }}
+
+    fn erase(&mut self, off: usize, len: usize) -> littlefs2::io::Result<usize> {{
+        let from = self.offset + off as u32;
+        embedded_storage::nor_flash::NorFlash::erase(&mut self.flash, from, from + len as u32)
+            .map_err(|_| littlefs2::io::Error::Io)?;
+        Ok(len)
+    }}
+}}
+"#,
+            name = blob.name,
+            vis = generate.visibility(),
+            type_name = camel_case(&blob.name) + "LittleFs",
+            const_name = blob.name.to_uppercase(),
+            prog_size = geometry.prog_size,
+            cache_size = geometry.cache_size,
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Type name for a blob name, e.g. web_assets becomes WebAssets
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|c| c.to_ascii_uppercase().to_string() + chars.as_str())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+// Writes accessors viewing the data as u16 or u32 slices for
+// sufficiently aligned blobs
+fn build_views<F>(
+    out_file: &mut F,
+    blob: &Blob,
+    origin: u32,
+    generate: &GenerateParams,
+) -> DynResult<()>
+where
+    F: Write,
+{
+    if blob.compress != Compression::None || blob.encryption.is_some() {
+        return Ok(());
+    }
+    for (elem, elem_size) in [("u16", 2), ("u32", 4)] {
+        if blob.align < elem_size || !blob.size.is_multiple_of(elem_size) {
+            continue;
+        }
+        if !blob.inline {
+            out_file.write_all(
+                format!(
+                    "const _: () = assert!(0x{:x} % {} == 0);\n",
+                    blob.address(origin),
+                    elem_size
+                )
+                .as_bytes(),
+            )?;
+        }
+        let (return_type, view) = match generate.errors {
+            ErrorMode::Panic | ErrorMode::Hook => (format!("&'static [{}]", elem), "view(blob)"),
+            ErrorMode::Result => (
+                format!("Result<&'static [{}], BlobError>", elem),
+                "blob.map(view)",
+            ),
+        };
+        out_file.write_all(
+            format!(
+                r#"
+{6} fn {0}_as_{1}_slice() -> {2} {{
+    fn view(blob: &'static [u8]) -> &'static [{1}] {{
+        debug_assert!(blob.as_ptr() as usize % {3} == 0);
+        unsafe {{ slice::from_raw_parts(blob.as_ptr() as *const {1}, blob.len() / {3}) }}
+    }}
+    let blob = {5}();
+    {4}
+}}
+"#,
+                blob.name,
+                elem,
+                return_type,
+                elem_size,
+                view,
+                blob.bytes_accessor(),
+                generate.visibility()
+            )
+            .as_bytes(),
+        )?;
+    }
+    Ok(())
+}
+
+// Writes an accessor returning the blob as a reference to an array of its
+// size, for APIs that need the length at compile time
+fn build_array_accessor<F>(
+    out_file: &mut F,
+    blob: &Blob,
+    generate: &GenerateParams,
+) -> DynResult<()>
+where
+    F: Write,
+{
+    let array_type = format!("&'static [u8; {}_SIZE]", blob.name.to_uppercase());
+    let (return_type, to_array) = match generate.errors {
+        ErrorMode::Panic | ErrorMode::Hook => (array_type.clone(), "to_array(blob)"),
+        ErrorMode::Result => (
+            format!("Result<{}, BlobError>", array_type),
+            "blob.map(to_array)",
+        ),
+    };
+    out_file.write_all(
+        format!(
+            r#"
+/// Data of blob `{0}` as an array
+{5} fn {0}_array() -> {1} {{
+    fn to_array(blob: &'static [u8]) -> {2} {{
+        // The accessor always returns a slice of the blob size
+        unsafe {{ &*(blob.as_ptr() as *const [u8; {3}_SIZE]) }}
+    }}
+    let blob = {4}();
+    {6}
+}}
+"#,
+            blob.name,
+            return_type,
+            array_type,
+            blob.name.to_uppercase(),
+            blob.bytes_accessor(),
+            generate.visibility(),
+            to_array
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Writes an accessor returning the blob as a reference to the type
+// given in the manifest. The type has to implement zerocopy::FromBytes and
+// its size and alignment are checked at compile time.
+fn build_typed_accessor<F>(
+    out_file: &mut F,
+    blob: &Blob,
+    origin: u32,
+    generate: &GenerateParams,
+    doc: &str,
+) -> DynResult<()>
+where
+    F: Write,
+{
+    let Some(type_name) = &blob.type_name else {
+        return Ok(());
+    };
+    out_file.write_all(
+        format!(
+            r#"
+const _: () = {{
+    fn assert_from_bytes<T: zerocopy::FromBytes>() {{}}
+    let _ = assert_from_bytes::<{0}>;
+    assert!(core::mem::size_of::<{0}>() == {1});
+}};
+"#,
+            type_name, blob.size
+        )
+        .as_bytes(),
+    )?;
+    if !blob.inline {
+        out_file.write_all(
+            format!(
+                "const _: () = assert!(0x{:x} % core::mem::align_of::<{}>() == 0);\n",
+                blob.address(origin),
+                type_name
+            )
+            .as_bytes(),
+        )?;
+    }
+    let (return_type, cast) = match generate.errors {
+        ErrorMode::Panic | ErrorMode::Hook => (format!("&'static {}", type_name), "cast(blob)"),
+        ErrorMode::Result => (
+            format!("Result<&'static {}, BlobError>", type_name),
+            "blob.map(cast)",
+        ),
+    };
+    out_file.write_all(doc.as_bytes())?;
+    out_file.write_all(
+        format!(
+            r#"
+{5} fn {0}() -> {1} {{
+    fn cast(blob: &'static [u8]) -> &'static {2} {{
+        // Size, alignment and FromBytes are checked at compile time
+        unsafe {{ &*(blob.as_ptr() as *const {2}) }}
+    }}
+    let blob = {3}();
+    {4}
+}}
+"#,
+            blob.name,
+            return_type,
+            type_name,
+            blob.bytes_accessor(),
+            cast,
+            generate.visibility()
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Writes an accessor returning the blob as a string slice. The data was
+// validated as UTF-8 when building and is protected by the checksum.
+fn build_text_accessor<F>(
+    out_file: &mut F,
+    blob: &Blob,
+    generate: &GenerateParams,
+    doc: &str,
+) -> DynResult<()>
+where
+    F: Write,
+{
+    // The data was validated as UTF-8 by the build script, but that only
+    // holds for the data in flash once it has been checked against the
+    // checksum. Otherwise it is validated again, since erased or stale
+    // flash would make the str invalid.
+    let (return_type, to_str, failure) = match generate.errors {
+        ErrorMode::Panic => (
+            "&'static str",
+            "to_str(blob)",
+            format!("panic!(\"Checksum check failed for {}\")", blob.name),
+        ),
+        ErrorMode::Hook => (
+            "&'static str",
+            "to_str(blob)",
+            format!("blob_integrity_failure(\"{}\")", blob.name),
+        ),
+        ErrorMode::Result => (
+            "Result<&'static str, BlobError>",
+            "blob.and_then(to_str)",
+            format!(
+                r#"{{
+            let checksum: BlobChecksum = {};
+            {}
+            Err(BlobError {{ name: "{}", expected: checksum, actual }})
+        }}"#,
+                blob.checksum_literal(generate.checksum),
+                checksum_calculation(generate.checksum),
+                blob.name
+            ),
+        ),
+    };
+    let ok = |text: &str| match generate.errors {
+        ErrorMode::Panic | ErrorMode::Hook => text.to_string(),
+        ErrorMode::Result => format!("Ok({})", text),
+    };
+    let unchecked = ok("unsafe { core::str::from_utf8_unchecked(blob) }");
+    // The build script validated the data as UTF-8, but the data in flash
+    // is only known to be the same once it has been checked against the
+    // checksum. Otherwise it's validated again, since erased or stale
+    // flash would make an invalid str.
+    let body = if blob.inline {
+        format!(
+            "// Validated as UTF-8 by the build script\n        {}",
+            unchecked
+        )
+    } else {
+        let checked = verify_condition(blob.verify)
+            .map(|condition| {
+                format!(
+                    r#"if cfg!(target_os = "none") && {} {{
+            // Checked against the checksum of the validated data
+            return {};
+        }}
+        "#,
+                    condition, unchecked
+                )
+            })
+            .unwrap_or_default();
+        format!(
+            r#"{}match core::str::from_utf8(blob) {{
+            Ok(text) => {},
+            Err(_) => {}
+        }}"#,
+            checked,
+            ok("text"),
+            failure
+        )
+    };
+    out_file.write_all(doc.as_bytes())?;
+    out_file.write_all(
+        format!(
+            r#"
+{4} fn {0}() -> {1} {{
+    fn to_str(blob: &'static [u8]) -> {1} {{
+        {5}
+    }}
+    let blob = {2}();
+    {3}
+}}
+"#,
+            blob.name,
+            return_type,
+            blob.bytes_accessor(),
+            to_str,
+            generate.visibility(),
+            body
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Writes the descriptor type, shared by the blobs of all groups
+fn build_descriptor_type<F>(
+    out_file: &mut F,
+    return_type: &str,
+    generate: &GenerateParams,
+) -> DynResult<()>
+where
+    F: Write,
+{
+    out_file.write_all(
+        format!(
+            r#"
+
+/// Metadata for a blob, available without reading or verifying the data
+#[derive(Debug, Clone, Copy)]
+{1} struct Blob {{
+    pub name: &'static str,
+    /// Address in flash, None for blobs included in the executable
+    pub addr: Option<usize>,
+    pub len: usize,
+    pub checksum: BlobChecksum,
+    pub version: Option<&'static str>,
+    pub inline: bool,
+    pub(crate) accessor: fn() -> {0},
+}}
+
+impl Blob {{
+    /// Blob data, verified in the same way as the accessor function
+    pub fn data(&self) -> {0} {{
+        (self.accessor)()
+    }}
+
+    pub const fn len(&self) -> usize {{
+        self.len
+    }}
+
+    pub const fn is_empty(&self) -> bool {{
+        self.len == 0
+    }}
+
+    pub const fn address(&self) -> Option<usize> {{
+        self.addr
+    }}
+}}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Blob {{
+    fn format(&self, f: defmt::Formatter) {{
+        defmt::write!(
+            f,
+            "Blob {{{{ name: {{=str}}, addr: {{}}, len: {{}}, version: {{}}, inline: {{}} }}}}",
+            self.name,
+            self.addr,
+            self.len,
+            self.version,
+            self.inline
+        )
+    }}
+}}
+"#,
+            return_type,
+            generate.visibility()
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+fn build_descriptors<F>(
+    out_file: &mut F,
+    blobs: &[Blob],
+    origin: u32,
+    generate: &GenerateParams,
+) -> DynResult<()>
+where
+    F: Write,
+{
+    for blob in blobs.iter().filter(|b| !b.storage) {
+        out_file.write_all(
+            format!(
+                r#"
+{8} const {0}: Blob = Blob {{
+    name: "{1}",
+    addr: {2},
+    len: {3},
+    checksum: {4},
+    version: {5},
+    inline: {6},
+    accessor: {7},
+}};
+"#,
+                blob.name.to_uppercase(),
+                blob.name,
+                if blob.inline {
+                    "None".to_string()
+                } else {
+                    format!("Some(0x{:x})", blob.address(origin))
+                },
+                blob.size,
+                blob.checksum_literal(generate.checksum),
+                if let Some(version) = &blob.version {
+                    format!("Some({:?})", version)
+                } else {
+                    "None".to_string()
+                },
+                blob.inline,
+                blob.bytes_accessor(),
+                generate.visibility()
+            )
+            .as_bytes(),
+        )?;
+    }
+    Ok(())
+}
+
+fn build_registry<F>(out_file: &mut F, blobs: &[Blob], generate: &GenerateParams) -> DynResult<()>
+where
+    F: Write,
+{
+    out_file.write_all(
+        format!(
+            r#"
+{1} type BlobDescriptor = Blob;
+
+/// Descriptors of all inline and loaded blobs
+{1} static ALL_BLOBS: &[BlobDescriptor] = &[{0}];
+
+/// Look up a blob descriptor by name
+{1} fn find(name: &str) -> Option<&'static BlobDescriptor> {{
+    ALL_BLOBS.iter().find(|b| b.name == name)
+}}
+"#,
+            blobs
+                .iter()
+                .filter(|b| !b.storage)
+                .map(|b| b.name.to_uppercase())
+                .collect::<Vec<_>>()
+                .join(", "),
+            generate.visibility()
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Writes the failure type and the report markers of verify_all(), shared by
+// the blobs of all groups
+fn build_verify_types<F>(out_file: &mut F, generate: &GenerateParams) -> DynResult<()>
+where
+    F: Write,
+{
+    out_file.write_all(
+        format!(
+            r#"
+/// Blob that didn't match its checksum
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+{vis} struct BlobVerifyFailure {{
+    pub name: &'static str,
+    pub expected: BlobChecksum,
+    pub actual: BlobChecksum,
+}}
+
+/// Start of a line reporting that verify_all succeeded, for cargo load-blob
+/// --rtt
+{vis} const BLOB_VERIFY_OK: &str = "{ok_marker}";
+/// Start of a line reporting that verify_all failed, for cargo load-blob
+/// --rtt
+{vis} const BLOB_VERIFY_FAIL: &str = "{fail_marker}";
+"#,
+            vis = generate.visibility(),
+            ok_marker = blob_info::VERIFY_OK_MARKER,
+            fail_marker = blob_info::VERIFY_FAIL_MARKER,
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Writes verify_all(), checking all loaded blobs regardless of their verify
+// mode and reporting every mismatch instead of stopping at the first
+fn build_verify_all<F>(
+    out_file: &mut F,
+    blobs: &[Blob],
+    origin: u32,
+    generate: &GenerateParams,
+) -> DynResult<()>
+where
+    F: Write,
+{
+    let loaded: Vec<&Blob> = blobs.iter().filter(|b| b.loaded()).collect();
+    let host_data = |b: &Blob| match generate.errors {
+        ErrorMode::Panic | ErrorMode::Hook => format!("{}()", b.bytes_accessor()),
+        // The host accessors don't verify, so this never fails
+        ErrorMode::Result => format!("{}().ok().unwrap_or_default()", b.bytes_accessor()),
+    };
+    let checks = loaded
+        .iter()
+        .map(|b| {
+            format!(
+                r#"
+    #[cfg(target_os = "none")]
+    let data = unsafe {{ slice::from_raw_parts(0x{addr:x} as *const u8, {size}) }};
+    #[cfg(not(target_os = "none"))]
+    let data = {host};
+    check("{name}", data, {checksum});"#,
+                addr = b.address(origin),
+                size = b.size,
+                host = host_data(b),
+                name = b.name,
+                checksum = b.checksum_literal(generate.checksum),
+            )
+        })
+        .collect::<String>();
+    out_file.write_all(
+        format!(
+            r#"
+/// Returned by verify_all when one or more blobs don't match
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+{vis} struct BlobVerifyReport {{
+    failures: [Option<BlobVerifyFailure>; {count}],
+}}
+
+impl BlobVerifyReport {{
+    pub fn failures(&self) -> impl Iterator<Item = &BlobVerifyFailure> {{
+        self.failures.iter().flatten()
+    }}
+}}
+
+/// Checks all loaded blobs against their checksums
+#[allow(unused_mut, unused_variables)]
+{vis} fn verify_all() -> Result<(), BlobVerifyReport> {{{barrier}
+    let mut report = BlobVerifyReport {{
+        failures: [None; {count}],
+    }};
+    let mut failed = 0;
+    let mut check = |name: &'static str, blob: &[u8], checksum: BlobChecksum| {{
+        {calculation}
+        if actual != checksum {{
+            report.failures[failed] = Some(BlobVerifyFailure {{
+                name,
+                expected: checksum,
+                actual,
+            }});
+            failed += 1;
+        }}
+    }};{checks}
+    if failed == 0 {{
+        Ok(())
+    }} else {{
+        Err(report)
+    }}
+}}
+"#,
+            vis = generate.visibility(),
+            count = loaded.len(),
+            barrier = match loaded.first().and_then(|b| b.pre_read_barrier.as_deref()) {
+                // The hook is only called on the target
+                Some(hook) => format!(
+                    "\n    #[cfg(target_os = \"none\")]\n    {{{}\n    }}",
+                    pre_read_barrier(Some(hook))
+                ),
+                None => String::new(),
+            },
+            calculation = checksum_calculation(generate.checksum),
+            checks = checks,
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Writes the type of BLOBS_BY_NAME and the hash function it uses, shared by
+// the blobs of all groups
+fn build_lookup_type<F>(
+    out_file: &mut F,
+    return_type: &str,
+    generate: &GenerateParams,
+) -> DynResult<()>
+where
+    F: Write,
+{
+    out_file.write_all(perfect_hash::SEEDED_HASH_SOURCE.as_bytes())?;
+    out_file.write_all(
+        format!(
+            r#"
+/// Blob accessors looked up by name using a perfect hash
+{vis} struct BlobsByName {{
+    pub(crate) displacements: &'static [u32],
+    pub(crate) slots: &'static [Option<(&'static str, fn() -> {ret})>],
+}}
+
+impl BlobsByName {{
+    /// Accessor of the blob with the given name
+    pub fn get(&self, name: &str) -> Option<fn() -> {ret}> {{
+        let bucket = blob_seeded_hash(name, 0) as usize % self.displacements.len();
+        let seed = self.displacements[bucket];
+        match self.slots[blob_seeded_hash(name, seed) as usize % self.slots.len()] {{
+            Some((n, accessor)) if n == name => Some(accessor),
+            _ => None,
+        }}
+    }}
+}}
+"#,
+            vis = generate.visibility(),
+            ret = return_type,
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Writes the blob! macro that maps names to accessors at compile time and
+// BLOBS_BY_NAME for looking up accessors by name at runtime
+fn build_lookup<F>(out_file: &mut F, blobs: &[Blob], generate: &GenerateParams) -> DynResult<()>
+where
+    F: Write,
+{
+    let prefix = match &generate.lookup_path {
+        Some(path) => format!("{}::", path),
+        None => String::new(),
+    };
+    let rules = blobs
+        .iter()
+        .map(|b| {
+            let accessor = if b.storage {
+                format!("{}_read", b.name)
+            } else {
+                b.bytes_accessor()
+            };
+            format!("\n    ({:?}) => {{ {}{} }};", b.name, prefix, accessor)
+        })
+        .collect::<String>();
+    out_file.write_all(
+        format!(
+            r#"
+/// Resolves to the accessor of the blob with the given name, e.g.
+/// `blob!("logo")()`. Unknown names are a compile error.
+#[allow(unused_macros)]
+macro_rules! blob {{{}
+    ($name:literal) => {{ compile_error!(concat!("Unknown blob ", $name)) }};
+}}
+#[allow(unused_imports)]
+pub(crate) use blob;
+"#,
+            rules
+        )
+        .as_bytes(),
+    )?;
+
+    // Storage blobs are read through a generic function, so they can't be
+    // in the map
+    let mapped: Vec<&Blob> = blobs.iter().filter(|b| !b.storage).collect();
+    let names: Vec<&str> = mapped.iter().map(|b| b.name.as_str()).collect();
+    let hash = perfect_hash::build(&names)?;
+    let slots = hash
+        .slots
+        .iter()
+        .map(|slot| match slot {
+            Some(i) => format!(
+                "\n        Some(({:?}, {})),",
+                mapped[*i].name,
+                mapped[*i].bytes_accessor()
+            ),
+            None => "\n        None,".to_string(),
+        })
+        .collect::<String>();
+    out_file.write_all(
+        format!(
+            r#"
+{vis} static BLOBS_BY_NAME: BlobsByName = BlobsByName {{
+    displacements: &[{displacements}],
+    slots: &[{slots}
+    ],
+}};
+"#,
+            vis = generate.visibility(),
+            displacements = hash
+                .displacements
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            slots = slots,
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Writes the type of the .blob_index records, shared by the blobs of all
+// groups, see blob_index.rs for the layout
+fn build_index_record_type<F>(out_file: &mut F, generate: &GenerateParams) -> DynResult<()>
+where
+    F: Write,
+{
+    out_file.write_all(
+        format!(
+            r#"
+/// Record describing a blob in the {section} section, so that tools can
+/// find out which blobs the program expects
+#[repr(C)]
+{vis} struct BlobIndexRecord {{
+    pub magic: u32,
+    pub name_hash: u32,
+    pub addr: u32,
+    pub size: u32,
+    pub flags: u32,
+    pub checksum: [u8; 20],
+    pub name: [u8; {name_size}],
+}}
+
+const _: () = assert!(core::mem::size_of::<BlobIndexRecord>() == {record_size});
+"#,
+            vis = generate.visibility(),
+            section = blob_index::SECTION,
+            name_size = blob_index::NAME_SIZE,
+            record_size = blob_index::RECORD_SIZE,
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Writes a record for each blob to the .blob_index section
+fn build_index_records<F>(out_file: &mut F, blobs: &[Blob], origin: u32) -> DynResult<()>
+where
+    F: Write,
+{
+    for blob in blobs {
+        let flags = if blob.inline {
+            blob_index::FLAG_INLINE
+        } else if blob.storage {
+            blob_index::FLAG_STORAGE
+        } else {
+            0
+        };
+        let addr = if blob.inline { 0 } else { blob.address(origin) };
+        let record =
+            blob_index::IndexRecord::new(&blob.name, addr, blob.size, flags, blob.checksum);
+        out_file.write_all(
+            format!(
+                r#"
+#[used]
+#[cfg_attr(target_os = "none", link_section = "{section}")]
+static {const_name}_INDEX_RECORD: BlobIndexRecord = BlobIndexRecord {{
+    magic: 0x{magic:08x},
+    name_hash: 0x{hash:08x},
+    addr: 0x{addr:x},
+    size: {size},
+    flags: {flags},
+    checksum: [{checksum}],
+    name: [{name}],
+}};
+"#,
+                section = blob_index::SECTION,
+                const_name = blob.name.to_uppercase(),
+                magic = blob_index::MAGIC,
+                hash = record.name_hash,
+                addr = record.addr,
+                size = record.size,
+                flags = record.flags,
+                checksum = record.checksum.map(|v| v.to_string()).join(", "),
+                name = record.name_bytes().map(|v| v.to_string()).join(", "),
+            )
+            .as_bytes(),
+        )?;
+    }
+    Ok(())
+}
+
+// Writes code reading the blob table that the loader writes at the start
+// of the blob region
+fn build_table_reader<F>(
+    out_file: &mut F,
+    blobs: &[Blob],
+    origin: u32,
+    generate: &GenerateParams,
+) -> DynResult<()>
+where
+    F: Write,
+{
+    let mut loaded: Vec<&Blob> = blobs.iter().filter(|b| b.loaded()).collect();
+    loaded.sort_by(|a, b| a.name.cmp(&b.name));
+    let entries = blob_table::encode_entries(
+        loaded
+            .iter()
+            .map(|b| (b.name.as_str(), b.address(origin), b.size, &b.checksum)),
+    );
+    let expected = loaded
+        .iter()
+        .map(|b| {
+            format!(
+                r#"
+    BlobTableEntry {{
+        name_hash: 0x{:08x},
+        start: 0x{:x},
+        size: {},
+        checksum: [{}],
+    }},"#,
+                blob_table::name_hash(&b.name),
+                b.address(origin),
+                b.size,
+                b.checksum.map(|v| v.to_string()).join(", ")
+            )
+        })
+        .collect::<String>();
+    out_file.write_all(blob_table::NAME_HASH_SOURCE.as_bytes())?;
+    out_file.write_all(
+        format!(
+            r#"
+{vis} const BLOB_TABLE_ADDR: usize = 0x{addr:x};
+{vis} const BLOB_TABLE_MAGIC: u32 = 0x{magic:08x};
+/// Identifies the layout of blobs this firmware was built for
+{vis} const BLOB_LAYOUT_FINGERPRINT: u32 = 0x{fingerprint:08x};
+
+/// Entry in the blob table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+{vis} struct BlobTableEntry {{
+    pub name_hash: u32,
+    pub start: u32,
+    pub size: u32,
+    pub checksum: [u8; 20],
+}}
+
+/// The table this firmware was built for
+{vis} const EXPECTED_BLOB_TABLE: [BlobTableEntry; {count}] = [{expected}
+];
+const EXPECTED_BLOB_NAMES: [&str; {count}] = [{names}];
+
+/// Returns the number of entries in the blob table, or None if there's
+/// no valid table in flash
+#[cfg(target_os = "none")]
+{vis} fn blob_table_len() -> Option<usize> {{{barrier}
+    let header = unsafe {{ slice::from_raw_parts(BLOB_TABLE_ADDR as *const u8, {header}) }};
+    let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    if magic != BLOB_TABLE_MAGIC {{
+        return None;
+    }}
+    let count = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    // Reject obviously corrupt tables
+    if count > 1024 {{
+        return None;
+    }}
+    Some(count)
+}}
+
+/// Reads an entry from the blob table in flash
+#[cfg(target_os = "none")]
+{vis} fn blob_table_entry(index: usize) -> Option<BlobTableEntry> {{
+    if index >= blob_table_len()? {{
+        return None;
+    }}
+    let entry = unsafe {{
+        slice::from_raw_parts(
+            (BLOB_TABLE_ADDR + {header} + index * {entry}) as *const u8,
+            {entry},
+        )
+    }};
+    let word = |i: usize| u32::from_le_bytes([entry[i], entry[i + 1], entry[i + 2], entry[i + 3]]);
+    let mut checksum = [0u8; 20];
+    checksum.copy_from_slice(&entry[12..32]);
+    Some(BlobTableEntry {{
+        name_hash: word(0),
+        start: word(4),
+        size: word(8),
+        checksum,
+    }})
+}}
+
+/// There's no flash on the host, so it behaves as if the expected table
+/// was written
+#[cfg(not(target_os = "none"))]
+{vis} fn blob_table_len() -> Option<usize> {{
+    Some(EXPECTED_BLOB_TABLE.len())
+}}
+
+#[cfg(not(target_os = "none"))]
+{vis} fn blob_table_entry(index: usize) -> Option<BlobTableEntry> {{
+    EXPECTED_BLOB_TABLE.get(index).copied()
+}}
+
+/// Checks if the blobs in flash were loaded for the same layout as this
+/// firmware was built for
+#[cfg(target_os = "none")]
+{vis} fn blobs_compatible() -> bool {{
+    if blob_table_len().is_none() {{
+        return false;
+    }}
+    let header = unsafe {{ slice::from_raw_parts(BLOB_TABLE_ADDR as *const u8, {header}) }};
+    u32::from_le_bytes([header[8], header[9], header[10], header[11]]) == BLOB_LAYOUT_FINGERPRINT
+}}
+
+#[cfg(not(target_os = "none"))]
+{vis} fn blobs_compatible() -> bool {{
+    true
+}}
+
+/// Finds the entry for a blob in the table in flash
+{vis} fn blob_table_lookup(name: &str) -> Option<BlobTableEntry> {{
+    let hash = blob_name_hash(name);
+    (0..blob_table_len()?)
+        .filter_map(blob_table_entry)
+        .find(|e| e.name_hash == hash)
+}}
+
+/// Returned by blob_table_check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+{vis} enum BlobTableError {{
+    /// No valid table in flash
+    NoTable,
+    /// The blob isn't in the table
+    Missing(&'static str),
+    /// The table entry has a different location or checksum
+    Mismatch(&'static str),
+}}
+
+/// Checks that the table in flash matches the blobs this firmware was
+/// built for
+{vis} fn blob_table_check() -> Result<(), BlobTableError> {{
+    let len = blob_table_len().ok_or(BlobTableError::NoTable)?;
+    for (expected, name) in EXPECTED_BLOB_TABLE.iter().zip(EXPECTED_BLOB_NAMES) {{
+        match (0..len)
+            .filter_map(blob_table_entry)
+            .find(|e| e.name_hash == expected.name_hash)
+        {{
+            None => return Err(BlobTableError::Missing(name)),
+            Some(found) if found != *expected => return Err(BlobTableError::Mismatch(name)),
+            Some(_) => {{}}
+        }}
+    }}
+    Ok(())
+}}
+"#,
+            vis = generate.visibility(),
+            addr = origin,
+            magic = blob_table::MAGIC,
+            fingerprint = blob_table::fingerprint(&entries),
+            count = loaded.len(),
+            expected = expected,
+            names = loaded
+                .iter()
+                .map(|b| format!("{:?}", b.name))
+                .collect::<Vec<_>>()
+                .join(", "),
+            header = blob_table::HEADER_SIZE,
+            entry = blob_table::ENTRY_SIZE,
+            barrier = pre_read_barrier(loaded.first().and_then(|b| b.pre_read_barrier.as_deref())),
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Writes tests that run on the host as part of cargo test, checking that
+// the blob files still match the sizes and checksums compiled into the
+// program. Only Blobs.toml triggers a rebuild, so changed blob files would
+// otherwise go unnoticed until run on the target. Compressed and encrypted
+// blobs are checked by the file they are built from.
+fn build_host_tests<F>(out_file: &mut F, blobs: &[Blob], generate: &GenerateParams) -> DynResult<()>
+where
+    F: Write,
+{
+    out_file.write_all(
+        br#"
+#[cfg(test)]
+mod blob_tests {
+    extern crate std;
+    #[allow(unused_imports)]
+    use super::*;
+"#,
+    )?;
+    if generate.checksum == ChecksumAlgorithm::Crc32 {
+        // The hook may use hardware that isn't available on the host
+        out_file.write_all(CRC32_SOURCE.as_bytes())?;
+    }
+    out_file.write_all(
+        format!(
+            r#"
+    fn check(name: &str, filename: &str, size: usize, checksum: BlobChecksum) {{
+        let data = std::fs::read(filename)
+            .unwrap_or_else(|e| panic!("Failed to read blob {{}} from {{}}: {{}}", name, filename, e));
+        assert_eq!(data.len(), size, "Size of blob {{}} has changed", name);
+        let blob = &data[..];
+        {}
+        assert_eq!(actual, checksum, "Checksum of blob {{}} has changed", name);
+    }}
+"#,
+            checksum_calculation(generate.checksum)
+        )
+        .as_bytes(),
+    )?;
+    for blob in blobs {
+        // The file of a directory blob is built from the directory, so
+        // checking it would only compare it with itself
+        if blob.source.is_none() {
+            continue;
+        }
+        let (path_expr, size, checksum) = match &blob.source_check {
+            Some(source) => (
+                &source.path_expr,
+                source.size,
+                match generate.checksum {
+                    ChecksumAlgorithm::Sha1 => {
+                        format!("[{}]", source.checksum.map(|v| v.to_string()).join(", "))
+                    }
+                    ChecksumAlgorithm::Crc32 => format!("0x{:08x}", source.crc32),
+                },
+            ),
+            None => (
+                &blob.path_expr,
+                blob.size,
+                blob.checksum_literal(generate.checksum),
+            ),
+        };
+        out_file.write_all(
+            format!(
+                r#"
+    #[test]
+    fn {0}_matches_file() {{
+        check("{0}", {1}, {2}, {3});
+    }}
+"#,
+                blob.name, path_expr, size, checksum
+            )
+            .as_bytes(),
+        )?;
+    }
+    out_file.write_all(b"}\n")?;
+    Ok(())
+}
+
+/// Blob as seen by user templates
+#[derive(Serialize)]
+struct TemplateBlob<'a> {
+    name: &'a str,
+    address: Option<u32>, // None for inline blobs
+    size: u32,
+    sha1: String, // Hex string
+    sha1_bytes: [u8; 20],
+    crc32: u32,
+    filename: &'a str,
+    inline: bool,
+    storage: bool,
+    align: u32,
+    version: Option<&'a str>,
+    type_name: Option<&'a str>,
+    text: bool,
+    compressed: bool,
+    decompressed_size: u32,
+    key_id: Option<&'a str>, // Set for encrypted blobs
+    group: Option<&'a str>,
+}
+
+/// Variables available to user templates
+#[derive(Serialize)]
+struct TemplateContext<'a> {
+    blobs: Vec<TemplateBlob<'a>>,
+    blob_start: u32,   // Start of the region of loaded blobs
+    checksum: &'a str, // "sha1" or "crc32"
+    visibility: &'a str,
+}
+
+// Renders blob.rs from a user template
+fn build_from_template<F>(
+    out_file: &mut F,
+    template: &Path,
+    blobs: &[Blob],
+    origin: u32,
+    generate: &GenerateParams,
+) -> DynResult<()>
+where
+    F: Write,
+{
+    let source = std::fs::read_to_string(template)
+        .map_err(|e| format!("Failed to read template {}: {}", template.display(), e))?;
+    let mut env = minijinja::Environment::new();
+    env.add_template("blob.rs", &source)?;
+    let context = TemplateContext {
+        blobs: blobs
+            .iter()
+            .map(|b| TemplateBlob {
+                name: &b.name,
+                address: if b.inline {
+                    None
+                } else {
+                    Some(b.address(origin))
+                },
+                size: b.size,
+                sha1: b.checksum.iter().map(|v| format!("{:02x}", v)).collect(),
+                sha1_bytes: b.checksum,
+                crc32: b.crc32,
+                filename: &b.filename,
+                inline: b.inline,
+                storage: b.storage,
+                align: b.align,
+                version: b.version.as_deref(),
+                type_name: b.type_name.as_deref(),
+                text: b.kind == BlobKind::Text,
+                compressed: b.compress != Compression::None,
+                decompressed_size: b.decompressed_size,
+                key_id: b.encryption.as_ref().map(|e| e.key_id.as_str()),
+                group: b.group.as_deref(),
+            })
+            .collect(),
+        blob_start: origin,
+        checksum: match generate.checksum {
+            ChecksumAlgorithm::Sha1 => "sha1",
+            ChecksumAlgorithm::Crc32 => "crc32",
+        },
+        visibility: generate.visibility(),
+    };
+    let rendered = env.get_template("blob.rs")?.render(&context)?;
+    out_file.write_all(rendered.as_bytes())?;
+    Ok(())
+}
+
+// Shrinks FLASH by length bytes. Returns the start and the new end of FLASH.
+fn build_link_script<I, O>(in_file: &mut I, out_file: &mut O, length: i64) -> DynResult<(i64, i64)>
+where
+    I: Read,
+    O: Write,
+{
+    let mut in_buf = String::new();
+    in_file.read_to_string(&mut in_buf)?;
+    let (after, (before, (name, attr, origin, flash_length))) =
+        link_script_parser::find_memory_def(&in_buf, FLASH_REGION)
+            .map_err(|e| format!("Failed to parse link script: {}", e))?;
+    let mut out_buf = before.to_string();
+    out_buf += &format!(
+        "{} {}: ORIGIN = 0x{:x}, LENGTH = 0x{:x}",
+        name,
+        if let Some(attr) = attr {
+            format!("({})", attr)
+        } else {
+            "".to_string()
+        },
+        origin,
+        flash_length - length
+    );
+    out_buf += after;
+    out_file.write_all(out_buf.as_bytes())?;
+    Ok((origin, origin + flash_length - length))
+}
+
+// Adds the .blob_index section to the link script. Nothing in the program
+// refers to the records, so without KEEP the linker would drop them. The
+// section isn't loaded, tools read it from the ELF file.
+fn build_index_section<O>(out_file: &mut O) -> DynResult<()>
+where
+    O: Write,
+{
+    out_file.write_all(
+        format!(
+            r#"
+/* Generated by blob_loader */
+SECTIONS
+{{
+  {section} (INFO) :
+  {{
+    KEEP(*({section}));
+  }}
+}}
+INSERT AFTER .rodata;
+"#,
+            section = blob_index::SECTION
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+// Writes a linker script defining the start and size of each loaded blob,
+// for use by C and assembly code linked into the program
+fn build_symbol_file<O>(out_file: &mut O, blobs: &[Blob], origin: u32) -> DynResult<()>
+where
+    O: Write,
+{
+    out_file.write_all(b"/* Generated by blob_loader */\n")?;
+    for blob in blobs.iter().filter(|b| b.loaded()) {
+        out_file.write_all(
+            format!(
+                "__blob_{0}_start = 0x{1:x};\n__blob_{0}_size = 0x{2:x};\n",
+                blob.name,
+                blob.address(origin),
+                blob.size
+            )
+            .as_bytes(),
+        )?;
+    }
+    Ok(())
+}
+
+// Formats a generated file with rustfmt, or the one given by RUSTFMT. The
+// templates produce readable code on their own, so it's not an error if
+// rustfmt isn't installed.
+fn format_source(path: &Path) {
+    let rustfmt = env::var("RUSTFMT").unwrap_or_else(|_| "rustfmt".to_string());
+    match Command::new(&rustfmt)
+        .arg("--edition=2021")
+        .arg(path)
+        .status()
+    {
+        Ok(status) if !status.success() => {
+            println!("cargo:warning=Failed to format {}", path.display())
+        }
+        _ => {}
+    }
+}
+
+fn env_dir(var_name: &str) -> DynResult<PathBuf> {
+    Ok(PathBuf::from(env::var(var_name).map_err(|_| {
+        format!("Environment variable '{}' not found", var_name)
+    })?))
+}
+
+fn env_str(var_name: &str) -> DynResult<String> {
+    Ok(env::var(var_name).map_err(|_| format!("Environment variable '{}' not found", var_name))?)
+}
+
+fn build_blob_info(
+    blobs: &[Blob],
+    origin: u32,
+    probe: &ProbeInfo,
+    table: Option<TableInfo>,
+    app: AppInfo,
+    build: BuildInfo,
+    fill: u8,
+) -> BlobInfoFile {
+    let mut info = BTreeMap::<String, BlobInfo>::new();
+    for blob in blobs {
+        if !blob.inline {
+            info.insert(
+                blob.name.to_string(),
+                BlobInfo {
+                    size: blob.size.into(),
+                    checksum: Checksum::sha1(blob.checksum),
+                    start: blob.address(origin).into(),
+                    filename: blob.filename.clone(),
+                    region: blob.region.clone(),
+                    tags: blob.tags.clone(),
+                    notes: blob.notes.clone(),
+                    external: blob.external.clone(),
+                    compression: blob.compress.codec().map(|codec| CompressionInfo {
+                        algorithm: codec.name().to_string(),
+                        uncompressed_size: blob.decompressed_size,
+                        uncompressed_checksum: Checksum::sha1(blob.decompressed_checksum),
+                    }),
+                    encryption: blob.encryption.clone(),
+                    padding: None,
+                    source: blob.source.clone(),
+                    extra: UnknownFields::new(),
+                },
+            );
+        }
+    }
+    // The loaded blobs follow the blob table, and the region is padded to
+    // the largest alignment. The storage region ends with its last blob.
+    let loaded: Vec<&Blob> = blobs.iter().filter(|b| b.loaded()).collect();
+    let storage: Vec<&Blob> = blobs.iter().filter(|b| b.storage).collect();
+    let max_align = loaded.iter().map(|b| b.align).max().unwrap_or(1);
+    let loaded_start = table.as_ref().map_or(origin, |t| t.start + t.size);
+    let loaded_end = origin
+        + align_up(
+            loaded.iter().map(|b| b.start + b.size).max().unwrap_or(0),
+            max_align,
+        );
+    let storage_start = storage.iter().map(|b| b.start).min().unwrap_or(0);
+    let storage_end = storage.iter().map(|b| b.start + b.size).max().unwrap_or(0);
+    for (mut region, start, end) in [
+        (loaded, loaded_start, loaded_end),
+        (storage, storage_start, storage_end),
+    ] {
+        region.sort_by_key(|b| b.start);
+        let mut previous_end = start;
+        for (index, blob) in region.iter().enumerate() {
+            let address = blob.address(origin);
+            let last = index + 1 == region.len();
+            if let Some(blob_info) = info.get_mut(&blob.name) {
+                blob_info.padding = Some(Padding {
+                    align: blob.align,
+                    before: address.saturating_sub(previous_end),
+                    after: if last {
+                        end.saturating_sub(address + blob.size)
+                    } else {
+                        0
+                    },
+                    fill,
+                });
+            }
+            previous_end = address + blob.size;
+        }
+    }
+    let mut file = BlobInfoFile {
+        format_version: format!("{}.{}", FORMAT_VERSION.0, FORMAT_VERSION.1),
+        info,
+        probe: probe.clone(),
+        environments: BTreeMap::new(),
+        table,
+        app: Some(app),
+        build: Some(build),
+        layout: None,
+        base_dir: None,
+        geometry: None,
+        artifacts: None,
+        extra: UnknownFields::new(),
+    };
+    // Relative names let the files be flashed from another machine
+    file.make_relative();
+    file
+}
+
+/// Erase sectors of the chip from the target descriptions built into
+/// probe-rs. None for chips it doesn't know, e.g. ones described in a file
+/// given to the loader.
+fn flash_geometry(chip: &str) -> Option<FlashGeometry> {
+    let target = Registry::from_builtin_families()
+        .get_target_by_name(chip)
+        .ok()?;
+    Some(FlashGeometry {
+        chip: chip.to_string(),
+        flash: flash::sector_maps(&target),
+    })
+}
+
+/// Appends the build id to the generated source, and returns the digests of
+/// the generated files. The id is of the files before it's added, so that
+/// it can be in the source.
+fn add_build_id(
+    out_dir: &Path,
+    generate: &GenerateParams,
+    group_files: &[String],
+) -> DynResult<ArtifactInfo> {
+    let source_path = out_dir.join(ArtifactInfo::SOURCE);
+    let link_script = std::fs::read(out_dir.join(ArtifactInfo::LINK_SCRIPT))?;
+    let mut source = std::fs::read(&source_path)?;
+    // The group files are not changed, only covered by the build id
+    let mut groups = BTreeMap::new();
+    for name in group_files {
+        groups.insert(name.clone(), std::fs::read(out_dir.join(name))?);
+    }
+    let build_id = ArtifactInfo::build_id(
+        &link_script,
+        &source,
+        &groups.values().map(Vec::as_slice).collect::<Vec<_>>(),
+    );
+    // Outside any module, and kept by the linker so the loader finds it in
+    // the ELF file
+    source.extend_from_slice(
+        format!(
+            r#"
+/// Identifies the build, the same as in the blob info file
+#[used]
+#[unsafe(no_mangle)]
+{vis} static {symbol}: [u8; {len}] = [{id}];
+"#,
+            vis = generate.visibility(),
+            symbol = blob_info::BUILD_ID_SYMBOL,
+            len = build_id.digest.len(),
+            id = build_id
+                .digest
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        )
+        .as_bytes(),
+    );
+    std::fs::write(&source_path, &source)?;
+    Ok(ArtifactInfo {
+        out_dir: out_dir.to_path_buf(),
+        link_script: Checksum::of(DigestAlgorithm::Sha1, &link_script),
+        source: Checksum::of(DigestAlgorithm::Sha1, &source),
+        group_files: groups
+            .into_iter()
+            .map(|(name, data)| (name, Checksum::of(DigestAlgorithm::Sha1, &data)))
+            .collect(),
+        build_id,
+    })
+}
+
+/// Provenance of the build. The git description is only as current as the
+/// last run of the build script.
+fn build_info(top_dir: &Path, profile: &str, params: &BlobInfoParams) -> DynResult<BuildInfo> {
+    // Not all builds are from a git checkout
+    let git = Command::new("git")
+        .args(["describe", "--always", "--dirty"])
+        .current_dir(top_dir)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|describe| describe.trim().to_string())
+        .filter(|describe| !describe.is_empty());
+    let timestamp = if params.timestamp {
+        println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+        Some(match env::var("SOURCE_DATE_EPOCH") {
+            Ok(epoch) => epoch
+                .parse()
+                .map_err(|_| format!("Invalid SOURCE_DATE_EPOCH '{}'", epoch))?,
+            Err(_) => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        })
+    } else {
+        None
+    };
+    Ok(BuildInfo {
+        package: env_str("CARGO_PKG_NAME")?,
+        version: env_str("CARGO_PKG_VERSION")?,
+        profile: profile.to_string(),
+        git,
+        timestamp,
+    })
+}
+
+/// Reads the key for signing the blob info files, from the environment or
+/// the key file in the configuration. None if they aren't to be signed.
+fn read_signing_key(top_dir: &Path, params: &BlobInfoParams) -> DynResult<Option<SigningKey>> {
+    println!("cargo:rerun-if-env-changed={}", signing::SIGNING_KEY_ENV);
+    let text = match (env::var(signing::SIGNING_KEY_ENV), &params.signing_key) {
+        (Ok(text), _) => text,
+        (Err(_), Some(file)) => {
+            println!("cargo:rerun-if-changed={}", file);
+            std::fs::read_to_string(top_dir.join(file))
+                .map_err(|e| format!("Failed to read signing key '{}': {}", file, e))?
+        }
+        (Err(_), None) => return Ok(None),
+    };
+    Ok(Some(signing::signing_key(&text)?))
+}
+
+/// Reads the key of an encrypted blob, from the environment or the key file
+/// in the encryption section
+fn read_encryption_key(
+    top_dir: &Path,
+    params: &EncryptionParams,
+    encrypt: &EncryptParams,
+) -> DynResult<Vec<u8>> {
+    let env_name = encrypt::key_env(&encrypt.key);
+    println!("cargo:rerun-if-env-changed={}", env_name);
+    let text = match (env::var(&env_name), params.keys.get(&encrypt.key)) {
+        (Ok(text), _) => text,
+        (Err(_), Some(file)) => {
+            println!("cargo:rerun-if-changed={}", file);
+            std::fs::read_to_string(top_dir.join(file))
+                .map_err(|e| format!("Failed to read encryption key '{}': {}", file, e))?
+        }
+        (Err(_), None) => {
+            return Err(format!(
+                "No key '{}': set {} or add it to [encryption.keys]",
+                encrypt.key, env_name
+            )
+            .into())
+        }
+    };
+    Ok(encrypt::parse_key(&text, encrypt.cipher)
+        .map_err(|e| format!("Encryption key '{}': {}", encrypt.key, e))?)
+}
+
+/// Finds the target directory, the same one as reported by cargo metadata.
+/// Cargo doesn't tell build scripts, but marks the directory with a
+/// CACHEDIR.TAG file, and OUT_DIR is inside it.
+fn target_dir(top_dir: &Path, out_dir: &Path) -> PathBuf {
+    if let Ok(dir) = env_dir("CARGO_TARGET_DIR") {
+        return dir;
+    }
+    out_dir
+        .ancestors()
+        .find(|dir| dir.join("CACHEDIR.TAG").is_file())
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| top_dir.join("target"))
+}
+
+pub fn prepare_blob() -> DynResult<()> {
+    let top_dir = env_dir("CARGO_MANIFEST_DIR")?;
     let out_dir = env_dir("OUT_DIR")?;
-    let target_dir = env_dir("CARGO_TARGET_DIR").unwrap_or_else(|_| top_dir.join("target"));
+    let target_dir = target_dir(&top_dir, &out_dir);
     let profile = env_str("PROFILE")?;
-    let (blobs, probe) = read_blobs(profile == "release")?;
-    let last_blob = blobs.last().ok_or_else(|| "No blobs defined")?;
-    let total_size = last_blob.start + last_blob.size;
-    let mut link_out = File::create(out_dir.join("memory.x"))?;
+    let (blobs, probe, generate, info_params) = read_blobs(profile == "release", &out_dir)?;
+    if blobs.is_empty() {
+        return Err("No blobs defined".into());
+    }
+    let loaded = || blobs.iter().filter(|b| b.loaded());
+    // Make the size a multiple of the largest alignment so that all blobs
+    // are aligned as long as the end of flash is
+    let max_align = loaded().map(|b| b.align).max().unwrap_or(1);
+    // The blob table is at the start of the region, and needs the space even
+    // if no blob is loaded
+    let table_end = if generate.blob_table {
+        blob_table::table_size(loaded().count())
+    } else {
+        0
+    };
+    let total_size = align_up(
+        loaded()
+            .map(|b| b.start + b.size)
+            .max()
+            .unwrap_or(0)
+            .max(table_end),
+        max_align,
+    );
+    let mut link_out = File::create(out_dir.join(ArtifactInfo::LINK_SCRIPT))?;
     let mut link_in = File::open(top_dir.join("memory.x"))?;
 
-    let flash_end = build_link_script(&mut link_in, &mut link_out, i64::from(total_size))?;
+    let (flash_start, flash_end) =
+        build_link_script(&mut link_in, &mut link_out, i64::from(total_size))?;
+    if generate.blob_index {
+        build_index_section(&mut link_out)?;
+    }
     // Tell the compiler where to find memory.x
     println!("cargo:rustc-link-search={}", out_dir.display());
     println!("cargo:rerun-if-changed=memory.x");
     println!("cargo:rerun-if-changed={}", BLOB_FILE);
+    let mut features = vec![SKIP_VERIFY_FEATURE, DEFMT_FEATURE];
+    features.extend(
+        blobs
+            .iter()
+            .filter_map(|b| b.compress.codec())
+            .map(Codec::feature),
+    );
+    features.extend(blobs.iter().filter_map(|b| Some(b.cipher()?.feature())));
+    features.sort();
+    features.dedup();
+    println!(
+        "cargo:rustc-check-cfg=cfg(feature, values({}))",
+        features
+            .iter()
+            .map(|f| format!("\"{}\"", f))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
 
-    let mut info_file = File::create(target_dir.join("BlobInfo.toml"))?;
     let blob_start = u32::try_from(flash_end)?;
-    build_blob_info(&mut info_file, &blobs, blob_start, &probe.chip)?;
+    for blob in loaded() {
+        if !(blob_start + blob.start).is_multiple_of(blob.align) {
+            return Err(format!(
+                "Blob {} at 0x{:x} is not aligned to {} bytes",
+                blob.name,
+                blob_start + blob.start,
+                blob.align
+            )
+            .into());
+        }
+    }
+    let table = if generate.blob_table {
+        Some(TableInfo {
+            start: blob_start,
+            size: blob_table::table_size(loaded().count()),
+        })
+    } else {
+        None
+    };
+    let app_window = Window {
+        start: u32::try_from(flash_start)?,
+        size: u32::try_from(flash_end - flash_start)?,
+    };
+    let app = AppInfo {
+        start: app_window.start,
+        size: app_window.size,
+    };
+    let build = build_info(&top_dir, &profile, &info_params)?;
+    let mut blob_info = build_blob_info(
+        &blobs,
+        blob_start,
+        &probe,
+        table,
+        app,
+        build,
+        info_params.fill,
+    );
+    let used: Vec<Range<u64>> = blob_info.regions().into_iter().map(|(_, r)| r).collect();
+    blob_info.layout = Some(LayoutInfo::new(
+        Window {
+            start: app_window.start,
+            size: app_window.size + total_size,
+        },
+        app_window,
+        Window {
+            start: blob_start,
+            size: total_size,
+        },
+        &used,
+    ));
+    blob_info.environments = info_params.environments.clone();
+    blob_info.geometry = flash_geometry(&probe.chip);
+    blob_info
+        .validate()
+        .map_err(|e| format!("Invalid blob layout: {}", e))?;
+    // Found through the link search path, e.g. with INCLUDE blob_symbols.x
+    // in memory.x
+    let mut symbols = File::create(out_dir.join("blob_symbols.x"))?;
+    build_symbol_file(&mut symbols, &blobs, blob_start)?;
 
-    let mut source = File::create(out_dir.join("blob.rs"))?;
-    build_source(&mut source, &blobs, blob_start)?;
+    let source_file = out_dir.join(ArtifactInfo::SOURCE);
+    let mut source = File::create(&source_file)?;
+    if let Some(template) = &generate.template {
+        println!("cargo:rerun-if-changed={}", template);
+        build_from_template(
+            &mut source,
+            &top_dir.join(template),
+            &blobs,
+            blob_start,
+            &generate,
+        )?;
+    } else {
+        build_source(&mut source, &blobs, blob_start, &generate, None)?;
+    }
+    drop(source);
+    if generate.format() {
+        format_source(&source_file);
+    }
+    let mut groups: Vec<&str> = blobs.iter().filter_map(|b| b.group.as_deref()).collect();
+    groups.sort();
+    groups.dedup();
+    let mut group_files = Vec::new();
+    for group in groups {
+        // Each group gets its own module, which uses the shared items from
+        // blob.rs
+        let group_file = out_dir.join(generate.group_file(group));
+        let mut source = File::create(&group_file)?;
+        build_source(&mut source, &blobs, blob_start, &generate, Some(group))?;
+        drop(source);
+        if generate.format() {
+            format_source(&group_file);
+        }
+        group_files.push(generate.group_file(group));
+    }
+    blob_info.artifacts = Some(add_build_id(&out_dir, &generate, &group_files)?);
+
+    // Written last, so that if the build fails before, the loader can tell
+    // that the earlier blob info file doesn't match the generated files
+    let mut formats = Vec::new();
+    if info_params.format != BlobInfoFormat::Json {
+        formats.push(FileFormat::Toml);
+    }
+    if info_params.format != BlobInfoFormat::Toml {
+        formats.push(FileFormat::Json);
+    }
+    let mut outputs = Vec::new();
+    for format in formats {
+        outputs.push((format.extension(), blob_info.to_vec(format)?));
+    }
+    let signing_key = read_signing_key(&top_dir, &info_params)?;
+    // A signature left from an earlier build would not match
+    let write_signed = |path: PathBuf, data: &[u8]| -> DynResult<()> {
+        std::fs::write(&path, data)?;
+        let signature_path = signing::signature_path(&path);
+        match &signing_key {
+            Some(key) => std::fs::write(signature_path, signing::sign(key, data))?,
+            None if signature_path.exists() => std::fs::remove_file(signature_path)?,
+            None => {}
+        }
+        Ok(())
+    };
+    // The loader uses the copy in the profile directory, named after the
+    // package, to tell several packages and profiles apart. The one in the
+    // target directory is from the latest build.
+    for (extension, data) in &outputs {
+        write_signed(target_dir.join(format!("BlobInfo.{}", extension)), data)?;
+        if let Some(profile_dir) = out_dir.ancestors().nth(3) {
+            let package = env_str("CARGO_PKG_NAME")?;
+            write_signed(
+                profile_dir.join(format!("BlobInfo-{}.{}", package, extension)),
+                data,
+            )?;
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+fn test_blob(name: &str, start: u32, group: Option<&str>) -> Blob {
+    Blob {
+        name: name.to_string(),
+        start,
+        size: 4,
+        checksum: [1; 20],
+        crc32: 0x1234_5678,
+        filename: format!("/blobs/{}.bin", name),
+        path_expr: format!("\"/blobs/{}.bin\"", name),
+        source_name: format!("{}.bin", name),
+        inline: false,
+        verify: VerifyMode::Always,
+        version: None,
+        align: 1,
+        type_name: None,
+        kind: BlobKind::Binary,
+        compress: Compression::None,
+        decompressed_size: 0,
+        decompressed_checksum: [0; 20],
+        storage: false,
+        pre_read_barrier: None,
+        dir_files: None,
+        group: group.map(str::to_string),
+        external: None,
+        region: None,
+        source: None,
+        tags: Vec::new(),
+        notes: None,
+        encryption: None,
+        littlefs: None,
+        source_check: None,
+    }
+}
+
+#[cfg(test)]
+fn test_source(errors: ErrorMode, group: Option<&str>) -> String {
+    let blobs = [
+        test_blob("font", 0, None),
+        test_blob("icons", 4, Some("ui")),
+    ];
+    let generate = GenerateParams {
+        errors,
+        ..GenerateParams::default()
+    };
+    let mut out = Vec::new();
+    build_source(&mut out, &blobs, 0x1000, &generate, group).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn test_build_source_panic() {
+    let source = test_source(ErrorMode::Panic, None);
+    assert!(source.contains("pub fn font() -> &'static [u8] {"));
+    assert!(source.contains("slice::from_raw_parts(0x1000 as *const u8, 4)"));
+    assert!(source.contains("panic!(\"Checksum check failed for font\")"));
+    assert!(source.contains("pub const FONT_ADDR: usize = 0x1000;"));
+    assert!(source.contains("pub type BlobChecksum = [u8; 20];"));
+    assert!(!source.contains("BlobError"));
+    // Blobs in a group go in the group file
+    assert!(!source.contains("fn icons()"));
+}
+
+#[test]
+fn test_build_source_result() {
+    let source = test_source(ErrorMode::Result, None);
+    assert!(source.contains("pub fn font() -> Result<&'static [u8], BlobError> {"));
+    assert!(source.contains("pub struct BlobError {"));
+    assert!(
+        source.contains("return Err(BlobError { name: \"font\", expected: checksum, actual });")
+    );
+    assert!(source.contains("Ok(blob)"));
+    assert!(!source.contains("panic!"));
+}
+
+#[test]
+fn test_build_source_hook() {
+    let source = test_source(ErrorMode::Hook, None);
+    assert!(source.contains("pub fn font() -> &'static [u8] {"));
+    assert!(source.contains("#[link_name = \"blob_integrity_failure\"]"));
+    assert!(source.contains("blob_integrity_failure(\"font\");"));
+    assert!(!source.contains("panic!"));
+    assert!(!source.contains("BlobError"));
+}
+
+#[test]
+fn test_build_source_group() {
+    let source = test_source(ErrorMode::Result, Some("ui"));
+    assert!(source.contains("pub mod ui {"));
+    assert!(source.contains("use super::*;"));
+    assert!(source.contains("pub fn icons() -> Result<&'static [u8], BlobError> {"));
+    assert!(source.contains("slice::from_raw_parts(0x1004 as *const u8, 4)"));
+    // The shared items are only in the file without a group
+    assert!(!source.contains("pub struct BlobError {"));
+    assert!(!source.contains("pub type BlobChecksum"));
+    assert!(!source.contains("fn font()"));
+}
@@ -0,0 +1,243 @@
+// Compression of blob data. The builder compresses blobs with these codecs
+// and the loader decompresses them to check what it programs. Generated
+// code has no_std decompressors for the same formats.
+
+use serde_derive::Deserialize;
+
+/// Bits of the heatshrink window size, the largest distance a match can be at
+pub const HEATSHRINK_WINDOW_BITS: u32 = 8;
+/// Bits of the heatshrink lookahead size, the longest a match can be
+pub const HEATSHRINK_LOOKAHEAD_BITS: u32 = 4;
+
+/// Compression algorithm
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Codec {
+    /// LZ4 block format, without a frame. Fast to decompress, decompressed
+    /// with lz4_flex in generated code.
+    Lz4,
+    /// Heatshrink with the window and lookahead sizes above. Compresses
+    /// small blobs well and decompresses with no memory besides the output.
+    Heatshrink,
+    /// Raw deflate, without zlib or gzip header. Compresses best,
+    /// decompressed with miniz_oxide in generated code.
+    Deflate,
+}
+
+impl Codec {
+    pub const ALL: [Codec; 3] = [Codec::Lz4, Codec::Heatshrink, Codec::Deflate];
+
+    /// Name in the blob info file
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Lz4 => "lz4",
+            Codec::Heatshrink => "heatshrink",
+            Codec::Deflate => "deflate",
+        }
+    }
+
+    /// Codec recorded in the blob info file, None if it isn't known
+    pub fn from_name(name: &str) -> Option<Codec> {
+        Codec::ALL.into_iter().find(|c| c.name() == name)
+    }
+
+    /// Cargo feature enabling the decompressor in generated code. The
+    /// heatshrink decompressor is generated rather than a dependency, but
+    /// is behind a feature like the others.
+    pub fn feature(self) -> &'static str {
+        match self {
+            Codec::Lz4 => "blob-lz4",
+            Codec::Heatshrink => "blob-heatshrink",
+            Codec::Deflate => "blob-deflate",
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Codec::Lz4 => lz4_flex::block::compress(data),
+            Codec::Heatshrink => heatshrink_compress(data),
+            Codec::Deflate => miniz_oxide::deflate::compress_to_vec(data, 10),
+        }
+    }
+
+    /// Decompresses data that should decompress to size bytes
+    pub fn decompress(self, data: &[u8], size: usize) -> Result<Vec<u8>, String> {
+        let decompressed = match self {
+            Codec::Lz4 => lz4_flex::block::decompress(data, size).map_err(|e| e.to_string())?,
+            Codec::Heatshrink => heatshrink_decompress(data, size)?,
+            Codec::Deflate => miniz_oxide::inflate::decompress_to_vec_with_limit(data, size)
+                .map_err(|e| e.to_string())?,
+        };
+        if decompressed.len() != size {
+            return Err(format!(
+                "Decompressed to {} bytes instead of {}",
+                decompressed.len(),
+                size
+            ));
+        }
+        Ok(decompressed)
+    }
+}
+
+// Bits are written most significant first
+struct BitWriter {
+    data: Vec<u8>,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn push(&mut self, value: usize, count: u32) {
+        for i in (0..count).rev() {
+            if self.bits.is_multiple_of(8) {
+                self.data.push(0);
+            }
+            if (value >> i) & 1 != 0 {
+                *self.data.last_mut().unwrap() |= 0x80 >> (self.bits % 8);
+            }
+            self.bits += 1;
+        }
+    }
+}
+
+// Each item is a 1 bit followed by a literal byte, or a 0 bit followed by
+// the distance minus one and the length minus one of a match. A match is
+// only used if it is shorter than the literals it replaces.
+fn heatshrink_compress(data: &[u8]) -> Vec<u8> {
+    let window = 1 << HEATSHRINK_WINDOW_BITS;
+    let lookahead = 1 << HEATSHRINK_LOOKAHEAD_BITS;
+    let break_even = (1 + HEATSHRINK_WINDOW_BITS + HEATSHRINK_LOOKAHEAD_BITS) as usize / 8;
+    let mut out = BitWriter {
+        data: Vec::new(),
+        bits: 0,
+    };
+    let mut pos = 0;
+    while pos < data.len() {
+        let max_len = lookahead.min(data.len() - pos);
+        let (mut best_len, mut best_distance) = (0, 0);
+        for distance in 1..=window.min(pos) {
+            let len = (0..max_len)
+                .take_while(|&i| data[pos - distance + i] == data[pos + i])
+                .count();
+            if len > best_len {
+                (best_len, best_distance) = (len, distance);
+            }
+        }
+        if best_len > break_even {
+            out.push(0, 1);
+            out.push(best_distance - 1, HEATSHRINK_WINDOW_BITS);
+            out.push(best_len - 1, HEATSHRINK_LOOKAHEAD_BITS);
+            pos += best_len;
+        } else {
+            out.push(1, 1);
+            out.push(usize::from(data[pos]), 8);
+            pos += 1;
+        }
+    }
+    out.data
+}
+
+// Same as the generated decompressor. The padding at the end is too short
+// for an item, which is where decompression stops.
+fn heatshrink_decompress(data: &[u8], size: usize) -> Result<Vec<u8>, String> {
+    let mut bit = 0;
+    let mut read = |count: u32| -> Option<usize> {
+        let mut value = 0;
+        for _ in 0..count {
+            let byte = data.get(bit / 8)?;
+            value = value << 1 | usize::from(byte >> (7 - bit % 8) & 1);
+            bit += 1;
+        }
+        Some(value)
+    };
+    let mut out = Vec::with_capacity(size);
+    while let Some(tag) = read(1) {
+        if tag == 1 {
+            let Some(byte) = read(8) else { break };
+            out.push(byte as u8);
+        } else {
+            let (Some(index), Some(count)) = (
+                read(HEATSHRINK_WINDOW_BITS),
+                read(HEATSHRINK_LOOKAHEAD_BITS),
+            ) else {
+                break;
+            };
+            let start = out
+                .len()
+                .checked_sub(index + 1)
+                .ok_or("Match before the start of the data")?;
+            for i in 0..=count {
+                out.push(out[start + i]);
+            }
+        }
+        if out.len() > size {
+            return Err(format!("Decompressed to more than {} bytes", size));
+        }
+    }
+    Ok(out)
+}
+
+/// Source of a function with the signature
+/// fn(&[u8], &mut [u8]) -> Result<usize, ()> decompressing heatshrink data
+/// into a buffer and returning the decompressed length
+pub const HEATSHRINK_SOURCE: &str = r#"
+pub(crate) fn blob_heatshrink_decompress(data: &[u8], buf: &mut [u8]) -> Result<usize, ()> {
+    let mut bit = 0;
+    let mut read = |count: u32| -> Option<usize> {
+        let mut value = 0;
+        for _ in 0..count {
+            let byte = data.get(bit / 8)?;
+            value = value << 1 | (byte >> (7 - bit % 8) & 1) as usize;
+            bit += 1;
+        }
+        Some(value)
+    };
+    let mut len = 0;
+    while let Some(tag) = read(1) {
+        if tag == 1 {
+            let Some(byte) = read(8) else { break };
+            *buf.get_mut(len).ok_or(())? = byte as u8;
+            len += 1;
+        } else {
+            let (Some(index), Some(count)) = (read(8), read(4)) else {
+                break;
+            };
+            let start = len.checked_sub(index + 1).ok_or(())?;
+            if len + count >= buf.len() {
+                return Err(());
+            }
+            for i in 0..=count {
+                buf[len + i] = buf[start + i];
+            }
+            len += count + 1;
+        }
+    }
+    Ok(len)
+}
+"#;
+
+#[test]
+fn test_heatshrink() {
+    // 'a' as a literal, then a match of 3 at distance 1
+    assert_eq!(heatshrink_compress(b"aaaa"), [0xb0, 0x80, 0x08]);
+    assert_eq!(
+        heatshrink_decompress(&[0xb0, 0x80, 0x08], 4).unwrap(),
+        b"aaaa"
+    );
+    assert!(heatshrink_decompress(&[0x00, 0x80], 4).is_err());
+    assert!(Codec::Heatshrink
+        .decompress(&[0xb0, 0x80, 0x08], 3)
+        .is_err());
+    assert_eq!(heatshrink_compress(b""), b"");
+}
+
+#[test]
+fn test_codecs() {
+    let mut data = b"Blob loader ".repeat(40);
+    data.extend((0..=255u8).cycle().take(1000));
+    for codec in Codec::ALL {
+        let compressed = codec.compress(&data);
+        assert_eq!(codec.decompress(&compressed, data.len()).unwrap(), data);
+        assert_eq!(Codec::from_name(codec.name()), Some(codec));
+    }
+    assert_eq!(Codec::from_name("zstd"), None);
+}
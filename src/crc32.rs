@@ -0,0 +1,79 @@
+// Table-less CRC-32 (IEEE 802.3), the same algorithm as the one included
+// in generated code.
+
+const POLYNOMIAL: u32 = 0xedb88320;
+
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32 { crc: 0xffffffff }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.crc ^= u32::from(b);
+            for _ in 0..8 {
+                let mask = (self.crc & 1).wrapping_neg();
+                self.crc = (self.crc >> 1) ^ (POLYNOMIAL & mask);
+            }
+        }
+    }
+
+    pub fn value(&self) -> u32 {
+        !self.crc
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32::new()
+    }
+}
+
+/// Source of a function with the signature fn(&[u8]) -> u32 calculating
+/// the checksum.
+pub const CRC32_SOURCE: &str = r#"
+pub(crate) fn blob_crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb88320 & mask);
+        }
+    }
+    !crc
+}
+"#;
+
+/// Source of a function with the signature fn(u32, &[u8]) -> u32 for
+/// calculating the checksum in pieces. The first call is passed 0 and the
+/// following ones the result of the previous call.
+pub const CRC32_UPDATE_SOURCE: &str = r#"
+pub(crate) fn blob_crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb88320 & mask);
+        }
+    }
+    !crc
+}
+"#;
+
+#[test]
+fn test_crc32() {
+    let mut crc = Crc32::new();
+    crc.update(b"123456789");
+    assert_eq!(crc.value(), 0xcbf43926);
+    let mut crc = Crc32::new();
+    crc.update(b"1234");
+    crc.update(b"56789");
+    assert_eq!(crc.value(), 0xcbf43926);
+    assert_eq!(Crc32::new().value(), 0);
+}
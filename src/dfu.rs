@@ -0,0 +1,374 @@
+// Writing an image over USB DFU using the DfuSe extensions used by the STM32
+// ROM bootloaders.
+//
+// The memory layout is described by the name of each alternate setting, e.g.
+// "@Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg", which is a list
+// of sector counts, sizes and attributes following a start address.
+
+use crate::blob_image::BlobImage;
+use crate::blob_info::{SectorMap, SectorSize};
+use nusb::descriptors::language_id::US_ENGLISH;
+use nusb::transfer::{ControlIn, ControlOut, ControlType, Recipient};
+use nusb::{Interface, MaybeFuture};
+use std::time::Duration;
+
+type DynResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+const DFU_CLASS: u8 = 0xfe;
+const DFU_SUBCLASS: u8 = 0x01;
+const DFU_PROTOCOL_DFU_MODE: u8 = 0x02;
+const DFU_FUNCTIONAL_DESCRIPTOR: u8 = 0x21;
+
+const DFU_DNLOAD: u8 = 1;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_CLRSTATUS: u8 = 4;
+const DFU_ABORT: u8 = 6;
+
+const STATE_IDLE: u8 = 2;
+const STATE_DNBUSY: u8 = 4;
+const STATE_ERROR: u8 = 10;
+
+const DFUSE_SET_ADDRESS: u8 = 0x21;
+const DFUSE_ERASE: u8 = 0x41;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DfuSector {
+    pub start: u32,
+    pub size: u32,
+    pub erasable: bool,
+    pub writable: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DfuSegment {
+    pub name: String,
+    pub sectors: Vec<DfuSector>,
+}
+
+impl DfuSegment {
+    pub fn contains(&self, start: u32, end: u32) -> bool {
+        let first = self.sectors.first().map(|s| s.start).unwrap_or(0);
+        let last = self.sectors.last().map(|s| s.start + s.size).unwrap_or(0);
+        start >= first && end <= last
+    }
+
+    /// The sectors as a map, for the checks of what erasing them affects
+    pub fn sector_map(&self) -> SectorMap {
+        let start = self.sectors.first().map(|s| s.start).unwrap_or(0) as u64;
+        let end = self.sectors.last().map(|s| s.start + s.size).unwrap_or(0) as u64;
+        SectorMap {
+            start,
+            end,
+            sectors: self
+                .sectors
+                .iter()
+                .map(|s| SectorSize {
+                    offset: s.start as u64 - start,
+                    size: s.size as u64,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Parses a DfuSe memory layout string. Returns None if the string isn't
+/// one.
+pub fn parse_layout(layout: &str) -> Option<DfuSegment> {
+    let layout = layout.strip_prefix('@')?;
+    let mut parts = layout.split('/');
+    let name = parts.next()?.trim().to_string();
+    let mut sectors = Vec::new();
+    while let Some(addr) = parts.next() {
+        let addr = addr.trim();
+        let addr = addr
+            .strip_prefix("0x")
+            .or_else(|| addr.strip_prefix("0X"))?;
+        let mut start = u32::from_str_radix(addr, 16).ok()?;
+        for group in parts.next()?.split(',') {
+            let (count, rest) = group.trim().split_once('*')?;
+            let count: u32 = count.parse().ok()?;
+            let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+            let size: u32 = rest[..digits].parse().ok()?;
+            let mut suffix = rest[digits..].chars();
+            let size = match suffix.next()? {
+                'K' => size.checked_mul(1024)?,
+                'M' => size.checked_mul(1024 * 1024)?,
+                ' ' => size,
+                _ => return None,
+            };
+            // a-g are combinations of readable (1), erasable (2) and
+            // writable (4)
+            let attributes = (suffix.next()? as u32).checked_sub('a' as u32)? + 1;
+            if attributes > 7 {
+                return None;
+            }
+            for _ in 0..count {
+                sectors.push(DfuSector {
+                    start,
+                    size,
+                    erasable: attributes & 2 != 0,
+                    writable: attributes & 4 != 0,
+                });
+                start = start.checked_add(size)?;
+            }
+        }
+    }
+    Some(DfuSegment { name, sectors })
+}
+
+struct DfuStatus {
+    status: u8,
+    poll_timeout: Duration,
+    state: u8,
+}
+
+pub struct DfuDevice {
+    interface: Interface,
+    transfer_size: usize,
+    segment: DfuSegment,
+}
+
+impl DfuDevice {
+    /// Opens the device in DFU mode and selects the alternate setting whose
+    /// memory covers start..end. If there is no vid_pid there must be
+    /// exactly one DFU device.
+    pub fn open(vid_pid: Option<(u16, u16)>, start: u32, end: u32) -> DynResult<DfuDevice> {
+        let is_dfu = |class: u8, subclass: u8, protocol: u8| {
+            class == DFU_CLASS && subclass == DFU_SUBCLASS && protocol == DFU_PROTOCOL_DFU_MODE
+        };
+        let devices: Vec<_> = nusb::list_devices()
+            .wait()?
+            .filter(|d| {
+                vid_pid
+                    .map(|(vid, pid)| d.vendor_id() == vid && d.product_id() == pid)
+                    .unwrap_or(true)
+            })
+            .filter(|d| {
+                d.interfaces()
+                    .any(|i| is_dfu(i.class(), i.subclass(), i.protocol()))
+            })
+            .collect();
+        let info = match devices.as_slice() {
+            [] => return Err("No device in DFU mode found".into()),
+            [info] => info,
+            _ => {
+                return Err(
+                    "Several devices in DFU mode found, select one with --dfu-device".into(),
+                )
+            }
+        };
+        let device = info.open().wait()?;
+        let config = device.active_configuration()?;
+        for alt in config.interface_alt_settings() {
+            if !is_dfu(alt.class(), alt.subclass(), alt.protocol()) {
+                continue;
+            }
+            let Some(index) = alt.string_index() else {
+                continue;
+            };
+            let name = device
+                .get_string_descriptor(index, US_ENGLISH, TIMEOUT)
+                .wait()?;
+            let Some(segment) = parse_layout(&name) else {
+                continue;
+            };
+            if !segment.contains(start, end) {
+                continue;
+            }
+            let transfer_size = alt
+                .descriptors()
+                .find(|d| d.descriptor_type() == DFU_FUNCTIONAL_DESCRIPTOR && d.len() >= 7)
+                .map(|d| u16::from_le_bytes([d[5], d[6]]) as usize)
+                .unwrap_or(1024);
+            let interface = device.claim_interface(alt.interface_number()).wait()?;
+            interface.set_alt_setting(alt.alternate_setting()).wait()?;
+            let mut dfu = DfuDevice {
+                interface,
+                transfer_size,
+                segment,
+            };
+            dfu.reset_state()?;
+            return Ok(dfu);
+        }
+        Err(format!(
+            "No DfuSe memory segment covers 0x{:08x}-0x{:08x}",
+            start,
+            end.saturating_sub(1)
+        )
+        .into())
+    }
+
+    pub fn segment(&self) -> &DfuSegment {
+        &self.segment
+    }
+
+    fn download(&mut self, block: u16, data: &[u8]) -> DynResult<()> {
+        let index = self.interface.interface_number() as u16;
+        self.interface
+            .control_out(
+                ControlOut {
+                    control_type: ControlType::Class,
+                    recipient: Recipient::Interface,
+                    request: DFU_DNLOAD,
+                    value: block,
+                    index,
+                    data,
+                },
+                TIMEOUT,
+            )
+            .wait()?;
+        Ok(())
+    }
+
+    fn request(&mut self, request: u8) -> DynResult<()> {
+        let index = self.interface.interface_number() as u16;
+        self.interface
+            .control_out(
+                ControlOut {
+                    control_type: ControlType::Class,
+                    recipient: Recipient::Interface,
+                    request,
+                    value: 0,
+                    index,
+                    data: &[],
+                },
+                TIMEOUT,
+            )
+            .wait()?;
+        Ok(())
+    }
+
+    fn get_status(&mut self) -> DynResult<DfuStatus> {
+        let index = self.interface.interface_number() as u16;
+        let data = self
+            .interface
+            .control_in(
+                ControlIn {
+                    control_type: ControlType::Class,
+                    recipient: Recipient::Interface,
+                    request: DFU_GETSTATUS,
+                    value: 0,
+                    index,
+                    length: 6,
+                },
+                TIMEOUT,
+            )
+            .wait()?;
+        if data.len() < 6 {
+            return Err("Short DFU status".into());
+        }
+        Ok(DfuStatus {
+            status: data[0],
+            poll_timeout: Duration::from_millis(
+                u32::from_le_bytes([data[1], data[2], data[3], 0]) as u64
+            ),
+            state: data[4],
+        })
+    }
+
+    /// Brings the device to the idle state
+    fn reset_state(&mut self) -> DynResult<()> {
+        let status = self.get_status()?;
+        if status.state == STATE_ERROR {
+            self.request(DFU_CLRSTATUS)?;
+        } else if status.state != STATE_IDLE {
+            self.request(DFU_ABORT)?;
+        }
+        Ok(())
+    }
+
+    /// Waits for a download request to complete
+    fn wait_done(&mut self) -> DynResult<()> {
+        loop {
+            let status = self.get_status()?;
+            if status.status != 0 {
+                self.request(DFU_CLRSTATUS)?;
+                return Err(format!("DFU request failed with status {}", status.status).into());
+            }
+            if status.state != STATE_DNBUSY {
+                return Ok(());
+            }
+            std::thread::sleep(status.poll_timeout);
+        }
+    }
+
+    fn dfuse_command(&mut self, command: u8, addr: u32) -> DynResult<()> {
+        let mut data = vec![command];
+        data.extend_from_slice(&addr.to_le_bytes());
+        self.download(0, &data)?;
+        self.wait_done()
+    }
+
+    /// Erases the sectors covered by the image and writes it. Progress is
+    /// called with the number of bytes written so far.
+    pub fn write(&mut self, image: &BlobImage, progress: &mut dyn FnMut(usize)) -> DynResult<()> {
+        let start = image.base;
+        let end = start + image.data.len() as u32;
+        let sectors: Vec<DfuSector> = self
+            .segment
+            .sectors
+            .iter()
+            .filter(|s| s.start < end && s.start + s.size > start)
+            .cloned()
+            .collect();
+        // Check every sector before erasing any of them
+        if let Some(sector) = sectors.iter().find(|s| !s.writable) {
+            return Err(format!("Sector at 0x{:08x} isn't writable", sector.start).into());
+        }
+        for sector in sectors.iter().filter(|s| s.erasable) {
+            self.dfuse_command(DFUSE_ERASE, sector.start)?;
+        }
+        let mut written = 0;
+        for chunk in image.data.chunks(self.transfer_size) {
+            // Block numbers from 2 are relative to the address pointer
+            self.dfuse_command(DFUSE_SET_ADDRESS, start + written as u32)?;
+            self.download(2, chunk)?;
+            self.wait_done()?;
+            written += chunk.len();
+            progress(written);
+        }
+        self.request(DFU_ABORT)?;
+        Ok(())
+    }
+
+    /// Leaves DFU mode and starts the application
+    pub fn leave(mut self) -> DynResult<()> {
+        let start = self.segment.sectors.first().map(|s| s.start).unwrap_or(0);
+        self.dfuse_command(DFUSE_SET_ADDRESS, start)?;
+        self.download(0, &[])?;
+        // The device may reset before answering
+        let _ = self.get_status();
+        Ok(())
+    }
+}
+
+#[test]
+fn test_parse_layout() {
+    let segment = parse_layout("@Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg").unwrap();
+    assert_eq!(segment.name, "Internal Flash");
+    assert_eq!(segment.sectors.len(), 12);
+    assert_eq!(
+        segment.sectors[4],
+        DfuSector {
+            start: 0x08010000,
+            size: 0x10000,
+            erasable: true,
+            writable: true
+        }
+    );
+    assert_eq!(segment.sectors[11].start, 0x080e0000);
+    assert!(segment.contains(0x08080000, 0x08100000));
+    assert!(!segment.contains(0x08080000, 0x08100001));
+    let sectors = segment.sector_map().sectors();
+    assert_eq!(sectors.len(), 12);
+    assert_eq!(sectors[4], 0x08010000..0x08020000);
+    assert_eq!(sectors[11], 0x080e0000..0x08100000);
+    let segment = parse_layout("@Option Bytes  /0x1FFFC000/01*016 e").unwrap();
+    assert_eq!(segment.sectors[0].size, 16);
+    assert!(!segment.sectors[0].erasable);
+    assert_eq!(parse_layout("Internal Flash"), None);
+    assert_eq!(parse_layout("@Flash/0x08000000/1*8192Mg"), None);
+    assert_eq!(parse_layout("@Flash/0xFFFF0000/2*64Kg"), None);
+}
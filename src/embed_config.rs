@@ -0,0 +1,177 @@
+// Probe settings from the Embed.toml files of cargo-embed, so they don't
+// have to be repeated in the blob configuration.
+
+use crate::blob_info::ProbeInfo;
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+type DynResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// Files read by cargo-embed, later ones overriding earlier ones
+pub const FILES: [&str; 4] = [
+    "Embed.toml",
+    ".embed.toml",
+    "Embed.local.toml",
+    ".embed.local.toml",
+];
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct General {
+    chip: Option<String>,
+    connect_under_reset: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Probe {
+    /// VID:PID or VID:PID:SERIAL
+    probe: Option<String>,
+    // Older way of selecting the probe
+    usb_vid: Option<String>,
+    usb_pid: Option<String>,
+    serial: Option<String>,
+    protocol: Option<String>,
+    speed: Option<u32>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Profile {
+    general: General,
+    probe: Probe,
+}
+
+/// Settings found in the files, None where not set
+#[derive(Debug, Default, PartialEq)]
+pub struct EmbedSettings {
+    pub chip: Option<String>,
+    pub selector: Option<String>,
+    pub protocol: Option<String>,
+    pub speed_khz: Option<u32>,
+    pub connect_under_reset: Option<bool>,
+}
+
+impl EmbedSettings {
+    /// Applies the settings of one file, first from the default profile and
+    /// then from the named one
+    pub fn apply_str(&mut self, text: &str, profile: &str) -> Result<(), toml::de::Error> {
+        let mut profiles: HashMap<String, Profile> = toml::from_str(text)?;
+        for name in ["default", profile] {
+            let Some(p) = profiles.remove(name) else {
+                continue;
+            };
+            if p.general.chip.is_some() {
+                self.chip = p.general.chip;
+            }
+            if p.general.connect_under_reset.is_some() {
+                self.connect_under_reset = p.general.connect_under_reset;
+            }
+            if let Some(probe) = p.probe.probe {
+                self.selector = Some(probe);
+            } else if let (Some(vid), Some(pid)) = (p.probe.usb_vid, p.probe.usb_pid) {
+                self.selector = Some(match p.probe.serial {
+                    Some(serial) => format!("{}:{}:{}", vid, pid, serial),
+                    None => format!("{}:{}", vid, pid),
+                });
+            }
+            if p.probe.protocol.is_some() {
+                self.protocol = p.probe.protocol;
+            }
+            if p.probe.speed.is_some() {
+                self.speed_khz = p.probe.speed;
+            }
+        }
+        Ok(())
+    }
+
+    /// Overrides the settings from the blob info file
+    pub fn apply_to(&self, probe: &mut ProbeInfo) {
+        if let Some(chip) = &self.chip {
+            probe.chip = chip.clone();
+        }
+        if self.selector.is_some() {
+            probe.selector = self.selector.clone();
+        }
+        if self.protocol.is_some() {
+            probe.protocol = self.protocol.clone();
+        }
+        if self.speed_khz.is_some() {
+            probe.speed_khz = self.speed_khz;
+        }
+        if let Some(connect_under_reset) = self.connect_under_reset {
+            probe.connect_under_reset = connect_under_reset;
+        }
+    }
+}
+
+/// Reads the Embed.toml files in dir. Returns None if there are none.
+pub fn read(dir: &Path, profile: &str) -> DynResult<Option<EmbedSettings>> {
+    let mut settings = EmbedSettings::default();
+    let mut found = false;
+    for file in FILES {
+        let path = dir.join(file);
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(format!("Failed to read '{}': {}", path.display(), e).into()),
+        };
+        settings
+            .apply_str(&text, profile)
+            .map_err(|e| format!("Failed to parse '{}': {}", path.display(), e))?;
+        found = true;
+    }
+    Ok(found.then_some(settings))
+}
+
+#[test]
+fn test_apply_str() {
+    let mut settings = EmbedSettings::default();
+    settings
+        .apply_str(
+            r#"
+[default.general]
+chip = "STM32F411CEUx"
+[default.probe]
+protocol = "Swd"
+speed = 4000
+[default.rtt]
+enabled = true
+[slow.probe]
+speed = 400
+usb_vid = "0483"
+usb_pid = "374b"
+"#,
+            "slow",
+        )
+        .unwrap();
+    assert_eq!(
+        settings,
+        EmbedSettings {
+            chip: Some("STM32F411CEUx".to_string()),
+            selector: Some("0483:374b".to_string()),
+            protocol: Some("Swd".to_string()),
+            speed_khz: Some(400),
+            connect_under_reset: None,
+        }
+    );
+    let mut probe = ProbeInfo {
+        chip: "nRF52840_xxAA".to_string(),
+        selector: None,
+        protocol: Some("jtag".to_string()),
+        speed_khz: None,
+        connect_under_reset: true,
+        reset_before_attach: false,
+        core: None,
+        ap: None,
+        extra: Default::default(),
+    };
+    settings.apply_to(&mut probe);
+    assert_eq!(probe.chip, "STM32F411CEUx");
+    assert_eq!(probe.protocol.as_deref(), Some("Swd"));
+    assert_eq!(probe.speed_khz, Some(400));
+    assert!(probe.connect_under_reset);
+}
@@ -0,0 +1,177 @@
+// Encryption of blob data at rest. The builder encrypts blobs with a key
+// that never leaves the build machine, and the loader programs the
+// ciphertext as it is. Generated code decrypts with a key the firmware
+// gets from a key provider, e.g. OTP memory or a secure element.
+
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+
+/// Prefix of the environment variables with the keys, followed by the key
+/// id in upper case
+pub const KEY_ENV_PREFIX: &str = "BLOB_ENCRYPTION_KEY_";
+
+/// Stream cipher. Both encrypt and decrypt by XORing with a key stream, so
+/// the ciphertext is as long as the plaintext.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Cipher {
+    /// AES-128 in counter mode, with a 128 bit big endian counter. Fast
+    /// where the MCU has an AES peripheral.
+    Aes128Ctr,
+    /// ChaCha20 with a 96 bit nonce. Fast in software.
+    #[serde(rename = "chacha20")]
+    ChaCha20,
+}
+
+impl Cipher {
+    pub const ALL: [Cipher; 2] = [Cipher::Aes128Ctr, Cipher::ChaCha20];
+
+    /// Name in the blob info file
+    pub fn name(self) -> &'static str {
+        match self {
+            Cipher::Aes128Ctr => "aes-128-ctr",
+            Cipher::ChaCha20 => "chacha20",
+        }
+    }
+
+    /// Cipher recorded in the blob info file, None if it isn't known
+    pub fn from_name(name: &str) -> Option<Cipher> {
+        Cipher::ALL.into_iter().find(|c| c.name() == name)
+    }
+
+    /// Cargo feature enabling the decryption in generated code
+    pub fn feature(self) -> &'static str {
+        match self {
+            Cipher::Aes128Ctr => "blob-aes",
+            Cipher::ChaCha20 => "blob-chacha20",
+        }
+    }
+
+    pub fn key_len(self) -> usize {
+        match self {
+            Cipher::Aes128Ctr => 16,
+            Cipher::ChaCha20 => 32,
+        }
+    }
+
+    pub fn nonce_len(self) -> usize {
+        match self {
+            Cipher::Aes128Ctr => 16,
+            Cipher::ChaCha20 => 12,
+        }
+    }
+
+    /// Encrypts or decrypts data in place
+    pub fn apply(self, key: &[u8], nonce: &[u8], data: &mut [u8]) -> Result<(), String> {
+        if key.len() != self.key_len() {
+            return Err(format!(
+                "A {} key has {} bytes, not {}",
+                self.name(),
+                self.key_len(),
+                key.len()
+            ));
+        }
+        let invalid = |_| format!("A {} nonce has {} bytes", self.name(), self.nonce_len());
+        match self {
+            Cipher::Aes128Ctr => Aes128Ctr::new_from_slices(key, nonce)
+                .map_err(invalid)?
+                .apply_keystream(data),
+            Cipher::ChaCha20 => chacha20::ChaCha20::new_from_slices(key, nonce)
+                .map_err(invalid)?
+                .apply_keystream(data),
+        }
+        Ok(())
+    }
+
+    /// Nonce for encrypting a blob. A key stream must never be used twice,
+    /// so the nonce is derived from the key, the blob name and the
+    /// plaintext. Building the same data gives the same ciphertext, which
+    /// keeps builds reproducible and only shows that the data is the same.
+    pub fn nonce(self, key: &[u8], name: &str, plaintext: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        for part in [key, name.as_bytes(), plaintext] {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
+        hasher.finalize()[..self.nonce_len()].to_vec()
+    }
+}
+
+/// Environment variable overriding the key file of a key id. Characters
+/// that can't be in a variable name are replaced by underscores.
+pub fn key_env(key_id: &str) -> String {
+    KEY_ENV_PREFIX.to_string()
+        + &key_id
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>()
+}
+
+/// Parses a key for cipher, given in hex
+pub fn parse_key(text: &str, cipher: Cipher) -> Result<Vec<u8>, String> {
+    let text = text.trim();
+    if text.len() != cipher.key_len() * 2 {
+        return Err(format!(
+            "A {} key has {} hex digits",
+            cipher.name(),
+            cipher.key_len() * 2
+        ));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| {
+            text.get(i..i + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| "Invalid hex digits in key".to_string())
+        })
+        .collect()
+}
+
+#[test]
+fn test_aes_128_ctr() {
+    // F.5.1 of NIST SP 800-38A
+    let key = parse_key("2b7e151628aed2a6abf7158809cf4f3c", Cipher::Aes128Ctr).unwrap();
+    let counter: Vec<u8> = (0xf0..=0xff).collect();
+    let mut data = [
+        0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93, 0x17,
+        0x2a,
+    ];
+    Cipher::Aes128Ctr.apply(&key, &counter, &mut data).unwrap();
+    assert_eq!(
+        data,
+        [
+            0x87, 0x4d, 0x61, 0x91, 0xb6, 0x20, 0xe3, 0x26, 0x1b, 0xef, 0x68, 0x64, 0x99, 0x0d,
+            0xb6, 0xce
+        ]
+    );
+}
+
+#[test]
+fn test_cipher() {
+    let plaintext = b"Licensed typeface".to_vec();
+    for cipher in Cipher::ALL {
+        let key = vec![0x5c; cipher.key_len()];
+        let nonce = cipher.nonce(&key, "font", &plaintext);
+        assert_eq!(nonce.len(), cipher.nonce_len());
+        let mut data = plaintext.clone();
+        cipher.apply(&key, &nonce, &mut data).unwrap();
+        assert_ne!(data, plaintext);
+        cipher.apply(&key, &nonce, &mut data).unwrap();
+        assert_eq!(data, plaintext);
+        assert!(cipher.apply(&key[1..], &nonce, &mut data).is_err());
+        assert!(cipher.apply(&key, &nonce[1..], &mut data).is_err());
+        assert_eq!(Cipher::from_name(cipher.name()), Some(cipher));
+    }
+    assert!(parse_key(&"0g".repeat(16), Cipher::Aes128Ctr).is_err());
+    assert!(parse_key(&"00".repeat(16), Cipher::ChaCha20).is_err());
+    assert_eq!(key_env("assets-2024"), "BLOB_ENCRYPTION_KEY_ASSETS_2024");
+}
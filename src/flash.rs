@@ -0,0 +1,1191 @@
+// Flashing blobs through a debug probe with probe-rs. cargo-load-blob is a
+// command line interface to this, other tools can use Flasher directly.
+
+use crate::blob_info::{BlobInfo, BlobInfoFile, Checksum, ModifiedFile, SectorMap, SectorSize};
+use crate::blob_table;
+use probe_rs::flashing::{
+    DownloadOptions, ElfLoader, ElfOptions, FlashProgress, ProgressEvent, ProgressOperation,
+};
+use probe_rs::{Core, MemoryInterface, Session, Target};
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+type DynResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// Largest table read back from flash
+const MAX_TABLE_ENTRIES: usize = 1024;
+
+/// A blob file or the application ELF file couldn't be read
+#[derive(Debug)]
+pub struct MissingFile {
+    pub path: PathBuf,
+    pub error: io::Error,
+}
+
+impl fmt::Display for MissingFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Failed to read '{}': {}",
+            self.path.display(),
+            self.error
+        )
+    }
+}
+
+impl std::error::Error for MissingFile {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Flashing was refused because it would erase more than the blobs, the
+/// application or data outside the blob region
+#[derive(Debug)]
+pub struct EraseRefused(pub String);
+
+impl fmt::Display for EraseRefused {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for EraseRefused {}
+
+/// How blobs are flashed
+#[derive(Default, Clone)]
+pub struct FlashOptions {
+    /// Flash all blobs, also those already present in flash
+    pub force: bool,
+    /// Read back every blob to find those that changed, instead of trusting
+    /// the blob table in flash
+    pub full_check: bool,
+    /// Erase the whole chip before flashing, implies force
+    pub chip_erase: bool,
+    /// Don't erase sectors before writing
+    pub skip_erase: bool,
+    /// Restore the parts of erased sectors that aren't written
+    pub restore_unwritten: bool,
+    /// Program the parts of erased sectors that aren't written to this value
+    pub fill: Option<u8>,
+    /// Read back and compare the written data after flashing
+    pub verify: bool,
+    /// Flash algorithms to use where the target has several for a region
+    pub flash_algorithms: Vec<String>,
+    /// Flash blob files that don't match their checksum with a warning,
+    /// instead of failing with ModifiedFile
+    pub allow_modified: bool,
+    /// Reattach and retry a blob up to this many times after a probe error.
+    /// Each blob is then flashed separately. Needs Flasher::reattach_with.
+    pub retries: u32,
+}
+
+/// Why a blob is read back from flash
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadKind {
+    /// To find out if it has to be flashed
+    Check,
+    Verify,
+    Dump,
+}
+
+/// Progress reported to the callback given to Flasher::on_progress
+#[derive(Debug)]
+pub enum Progress<'a> {
+    /// Started reading back a blob
+    ReadStarted {
+        kind: ReadKind,
+        name: &'a str,
+        size: u64,
+    },
+    /// Bytes read of the blob
+    Read(u32),
+    ReadFinished,
+    /// Progress of a flash loader commit
+    Flash(ProgressEvent),
+}
+
+/// Result of comparing a blob, or the blob table, with flash
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verified {
+    Match,
+    Mismatch,
+    ReadFailed(String),
+}
+
+/// Time spent in the operations of flash loader commits
+#[derive(Default, Clone, Copy, Debug)]
+pub struct OperationTimes {
+    pub fill: Duration,
+    pub erase: Duration,
+    pub program: Duration,
+    pub verify: Duration,
+}
+
+impl OperationTimes {
+    fn get_mut(&mut self, operation: ProgressOperation) -> &mut Duration {
+        match operation {
+            ProgressOperation::Fill => &mut self.fill,
+            ProgressOperation::Erase => &mut self.erase,
+            ProgressOperation::Program => &mut self.program,
+            ProgressOperation::Verify => &mut self.verify,
+        }
+    }
+
+    pub fn add(&mut self, other: &OperationTimes) {
+        self.fill += other.fill;
+        self.erase += other.erase;
+        self.program += other.program;
+        self.verify += other.verify;
+    }
+
+    pub fn total(&self) -> Duration {
+        self.fill + self.erase + self.program + self.verify
+    }
+}
+
+#[derive(Debug)]
+pub struct BlobTiming {
+    pub name: String,
+    pub size: u64,
+    /// Reading back the checksum
+    pub read: Duration,
+    pub written: bool,
+    /// Only known if the blob was written in a commit of its own
+    pub operations: Option<OperationTimes>,
+}
+
+/// Timing of a flash run
+#[derive(Default, Debug)]
+pub struct FlashTimings {
+    pub blobs: Vec<BlobTiming>,
+    /// All commits
+    pub operations: OperationTimes,
+    pub elapsed: Duration,
+}
+
+/// KiB per second, or 0 if no time was spent
+pub fn kib_per_sec(bytes: u64, time: Duration) -> f64 {
+    if time.is_zero() {
+        0.0
+    } else {
+        bytes as f64 / 1024.0 / time.as_secs_f64()
+    }
+}
+
+impl FlashTimings {
+    pub fn written_bytes(&self) -> u64 {
+        self.blobs
+            .iter()
+            .filter(|b| b.written)
+            .map(|b| b.size)
+            .sum()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let ops_json = |ops: &OperationTimes| {
+            serde_json::json!({
+                "fill_s": ops.fill.as_secs_f64(),
+                "erase_s": ops.erase.as_secs_f64(),
+                "program_s": ops.program.as_secs_f64(),
+                "verify_s": ops.verify.as_secs_f64(),
+            })
+        };
+        let blobs: Vec<serde_json::Value> = self
+            .blobs
+            .iter()
+            .map(|blob| {
+                serde_json::json!({
+                    "name": blob.name,
+                    "size": blob.size,
+                    "written": blob.written,
+                    "read_s": blob.read.as_secs_f64(),
+                    "operations": blob.operations.as_ref().map(ops_json),
+                    "kib_per_s": blob
+                        .operations
+                        .as_ref()
+                        .map(|ops| kib_per_sec(blob.size, ops.total())),
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "blobs": blobs,
+            "total": {
+                "written": self.written_bytes(),
+                "read_s": self.blobs.iter().map(|b| b.read).sum::<Duration>().as_secs_f64(),
+                "operations": ops_json(&self.operations),
+                "elapsed_s": self.elapsed.as_secs_f64(),
+                "kib_per_s": kib_per_sec(self.written_bytes(), self.elapsed),
+            },
+        })
+    }
+}
+
+/// What has to be written to flash
+pub struct FlashPlan<'a> {
+    pub blobs: Vec<&'a String>,
+    pub table: Option<Vec<u8>>,
+    /// Some blobs are already present, and may share sectors with the ones
+    /// being written
+    pub skipped: bool,
+}
+
+type ProgressFn<'a> = Box<dyn FnMut(Progress) + 'a>;
+type ReattachFn<'a> = Box<dyn FnMut() -> DynResult<Session> + 'a>;
+type ConfirmFn<'a> = Box<dyn FnMut(&str) -> DynResult<()> + 'a>;
+
+/// Flashes, verifies and reads back blobs through a probe-rs session
+pub struct Flasher<'a> {
+    // None while reattaching
+    session: Option<Session>,
+    core: usize,
+    options: FlashOptions,
+    progress: ProgressFn<'a>,
+    reattach: Option<ReattachFn<'a>>,
+    confirm: Option<ConfirmFn<'a>>,
+}
+
+impl<'a> Flasher<'a> {
+    pub fn new(session: Session) -> Flasher<'a> {
+        Flasher {
+            session: Some(session),
+            core: 0,
+            options: FlashOptions::default(),
+            progress: Box::new(|_| {}),
+            reattach: None,
+            confirm: None,
+        }
+    }
+
+    /// Core used to access the flash, as an index in the target description
+    pub fn with_core(mut self, core: usize) -> Self {
+        self.core = core;
+        self
+    }
+
+    pub fn with_options(mut self, options: FlashOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn on_progress(mut self, progress: impl FnMut(Progress) + 'a) -> Self {
+        self.progress = Box::new(progress);
+        self
+    }
+
+    /// Opens a new session when retrying after a probe error. The old
+    /// session is closed first.
+    pub fn reattach_with(mut self, reattach: impl FnMut() -> DynResult<Session> + 'a) -> Self {
+        self.reattach = Some(Box::new(reattach));
+        self
+    }
+
+    /// Asks for confirmation before erasing outside the blob region, see
+    /// check_erase. The callback fails if not confirmed. Without it such
+    /// flashing is refused.
+    pub fn confirm_with(mut self, confirm: impl FnMut(&str) -> DynResult<()> + 'a) -> Self {
+        self.confirm = Some(Box::new(confirm));
+        self
+    }
+
+    pub fn core(&self) -> usize {
+        self.core
+    }
+
+    pub fn options(&self) -> &FlashOptions {
+        &self.options
+    }
+
+    pub fn session(&mut self) -> DynResult<&mut Session> {
+        self.session
+            .as_mut()
+            .ok_or_else(|| "Not attached to target".into())
+    }
+
+    pub fn into_session(self) -> Option<Session> {
+        self.session
+    }
+
+    /// Finds the blobs to flash. Unless forced, blobs whose checksum already
+    /// matches the contents of flash are skipped. If there is a valid blob
+    /// table in flash, its checksums are compared instead of reading back
+    /// the blobs. Returns None if there is nothing to flash.
+    pub fn plan<'b>(
+        &mut self,
+        blob_info: &'b BlobInfoFile,
+        timings: &mut FlashTimings,
+    ) -> DynResult<Option<FlashPlan<'b>>> {
+        let options = &self.options;
+        let progress = &mut self.progress;
+        let session = self.session.as_mut().ok_or("Not attached to target")?;
+        let mut changed = Vec::new();
+        {
+            let mut core = session.core(self.core)?;
+            let table = match &blob_info.table {
+                Some(table) if !options.full_check && !options.force && !options.chip_erase => {
+                    read_table(&mut core, table.start)
+                }
+                _ => None,
+            };
+            for (name, blob) in &blob_info.info {
+                let mut timing = BlobTiming {
+                    name: name.clone(),
+                    size: blob.size,
+                    read: Duration::ZERO,
+                    written: true,
+                    operations: None,
+                };
+                if options.force || options.chip_erase {
+                    changed.push(name);
+                    timings.blobs.push(timing);
+                    continue;
+                }
+                let started = Instant::now();
+                let present = if let Some(entries) = &table {
+                    // The table in flash is written together with the blobs,
+                    // so a matching entry means the blob is present
+                    entries.iter().any(|e| {
+                        e.name_hash == blob_table::name_hash(name)
+                            && u64::from(e.start) == blob.start
+                            && u64::from(e.size) == blob.size
+                            && e.checksum == blob.checksum.table_bytes()
+                    })
+                } else {
+                    // A failed read just means the blob has to be flashed
+                    read_checksum(&mut core, name, blob, ReadKind::Check, progress)
+                        .map(|c| c == blob.checksum)
+                        .unwrap_or(false)
+                };
+                timing.read = started.elapsed();
+                if present {
+                    info!("Skipping {}, already present", name);
+                    timing.written = false;
+                } else {
+                    debug!("{} changed", name);
+                    changed.push(name);
+                }
+                timings.blobs.push(timing);
+            }
+        }
+        let table_data = blob_info
+            .table
+            .as_ref()
+            .map(|_| blob_table::encode(blob_info));
+        let table_changed = match (&blob_info.table, &table_data) {
+            (Some(table), Some(data))
+                if !options.force && !options.chip_erase && changed.is_empty() =>
+            {
+                let mut core = session.core(self.core)?;
+                let mut current = vec![0u8; data.len()];
+                core.read(table.start as u64, &mut current).is_err() || current != *data
+            }
+            (Some(_), _) => true,
+            (None, _) => false,
+        };
+        if changed.is_empty() && !table_changed {
+            info!("All blobs already present");
+            return Ok(None);
+        }
+        Ok(Some(FlashPlan {
+            skipped: changed.len() < blob_info.info.len(),
+            blobs: changed,
+            table: table_data,
+        }))
+    }
+
+    /// Checks what flashing the blobs would erase besides them, see
+    /// check_erase. Called by flash.
+    pub fn check_erase(&mut self, blob_info: &BlobInfoFile, app_flashed: bool) -> DynResult<()> {
+        let maps = sector_maps(self.session()?.target());
+        let confirm = &mut self.confirm;
+        check_erase(
+            &maps,
+            blob_info,
+            &self.options,
+            app_flashed,
+            &mut |message: &str| match confirm {
+                Some(confirm) => confirm(message),
+                None => Err(format!("{}, which has to be confirmed", message).into()),
+            },
+        )
+    }
+
+    /// Flashes the blobs that have changed, and the application ELF file if
+    /// given. With retries set in the options, each blob is flashed
+    /// separately and retried after a failure. Fails with EraseRefused if
+    /// flashing would erase the application, or erase outside the blob
+    /// region without confirmation.
+    pub fn flash(
+        &mut self,
+        blob_info: &BlobInfoFile,
+        elf: Option<&Path>,
+        timings: &mut FlashTimings,
+    ) -> DynResult<()> {
+        self.check_erase(blob_info, elf.is_some())?;
+        if self.options.retries > 0 {
+            if elf.is_some() {
+                return Err("Flashing an ELF file can't be retried".into());
+            }
+            return self.flash_retrying(blob_info, timings);
+        }
+        let started = Instant::now();
+        let plan = self.plan(blob_info, timings)?;
+        let times = match (&plan, elf) {
+            (Some(plan), _) => self.write(
+                blob_info,
+                &plan.blobs,
+                plan.table.as_deref(),
+                elf,
+                plan.skipped,
+            )?,
+            // The application is flashed even if the blobs are unchanged
+            (None, Some(_)) => self.write(blob_info, &[], None, elf, true)?,
+            (None, None) => {
+                info!("Nothing to flash");
+                OperationTimes::default()
+            }
+        };
+        timings.operations.add(&times);
+        timings.elapsed += started.elapsed();
+        Ok(())
+    }
+
+    /// Like flash, but flashes one blob at a time. After a failure the probe
+    /// is reattached and the blob retried, up to the number of retries in
+    /// the options, with increasing delays.
+    fn flash_retrying(
+        &mut self,
+        blob_info: &BlobInfoFile,
+        timings: &mut FlashTimings,
+    ) -> DynResult<()> {
+        let started = Instant::now();
+        let Some(plan) = self.plan(blob_info, timings)? else {
+            info!("Nothing to flash");
+            timings.elapsed += started.elapsed();
+            return Ok(());
+        };
+        let retries = self.options.retries;
+        // Each blob is written separately, followed by the blob table
+        let mut units: Vec<_> = plan
+            .blobs
+            .iter()
+            .map(|name| (name.as_str(), vec![*name], None))
+            .collect();
+        if let Some(table) = &plan.table {
+            units.push(("blob table", Vec::new(), Some(table.as_slice())));
+        }
+        let mut retried = Vec::new();
+        for (name, blobs, table) in units {
+            let mut attempt = 0;
+            loop {
+                // Neighbouring blobs may share sectors
+                match self.write(blob_info, &blobs, table, None, true) {
+                    Ok(times) => {
+                        timings.operations.add(&times);
+                        if let Some(timing) = timings.blobs.iter_mut().find(|t| t.name == name) {
+                            timing.operations = Some(times);
+                        }
+                        break;
+                    }
+                    Err(e) if attempt < retries && self.reattach.is_some() => {
+                        attempt += 1;
+                        let delay = Duration::from_millis(100 << attempt.min(6));
+                        warn!(
+                            "Flashing {} failed: {}, retrying in {} ms ({}/{})",
+                            name,
+                            e,
+                            delay.as_millis(),
+                            attempt,
+                            retries
+                        );
+                        // The probe has to be released before attaching again
+                        drop(self.session.take());
+                        std::thread::sleep(delay);
+                        if let Some(reattach) = &mut self.reattach {
+                            self.session = reattach()
+                                .map_err(|e| warn!("Failed to reattach: {}", e))
+                                .ok();
+                        }
+                    }
+                    Err(e) => {
+                        return Err(format!(
+                            "Flashing {} failed after {} retries: {}",
+                            name, attempt, e
+                        )
+                        .into())
+                    }
+                }
+            }
+            if attempt > 0 {
+                retried.push((name, attempt));
+            }
+        }
+        for (name, attempts) in retried {
+            info!("{} needed {} retries", name, attempts);
+        }
+        timings.elapsed += started.elapsed();
+        Ok(())
+    }
+
+    /// Writes the named blobs, the blob table and the application ELF file
+    /// in a single flash loader commit. If keep is set, bytes of erased
+    /// sectors outside the written data are restored. Returns the time spent
+    /// in each operation. Fails if the layout puts a blob or the blob table
+    /// outside the blob window.
+    fn write(
+        &mut self,
+        blob_info: &BlobInfoFile,
+        names: &[&String],
+        table: Option<&[u8]>,
+        elf: Option<&Path>,
+        keep: bool,
+    ) -> DynResult<OperationTimes> {
+        let options = &self.options;
+        let progress = &mut self.progress;
+        let session = self.session.as_mut().ok_or("Not attached to target")?;
+        let mut loader = session.target().flash_loader();
+        if let Some(path) = elf {
+            debug!("Reading {}", path.display());
+            let mut file = File::open(path).map_err(|error| MissingFile {
+                path: path.to_path_buf(),
+                error,
+            })?;
+            loader.load_image(session, &mut file, ElfLoader(ElfOptions::default()), None)?;
+        }
+        for name in names {
+            let blob = &blob_info.info[*name];
+            debug!("Reading {} at 0x{:x}", name, blob.load_address());
+            // One contiguous block per blob keeps the flash builder from
+            // splitting it into many small ranges
+            let path = blob_info.blob_path(blob);
+            let data = std::fs::read(&path).map_err(|error| MissingFile {
+                path: path.clone(),
+                error,
+            })?;
+            if data.len() != blob.size as usize {
+                return Err(format!(
+                    "Size of '{}' for blob {} has changed since the build",
+                    path.display(),
+                    name
+                )
+                .into());
+            }
+            blob.check_encrypted(&data)
+                .and_then(|()| blob.check_compressed(&data))
+                .map_err(|e| format!("'{}' for blob {}: {}", path.display(), name, e))?;
+            if !blob.check_data(&data) {
+                let modified = ModifiedFile {
+                    path,
+                    blob: name.to_string(),
+                };
+                if !options.allow_modified {
+                    return Err(modified.into());
+                }
+                warn!("{}", modified);
+            }
+            let start = blob.load_address();
+            if let Some(layout) = &blob_info.layout {
+                layout.check_write(name, &(start..start + data.len() as u64))?;
+            }
+            loader.add_data(start, &data)?;
+        }
+        if let (Some(table), Some(data)) = (&blob_info.table, table) {
+            debug!("Adding blob table at 0x{:x}", table.start);
+            if let Some(layout) = &blob_info.layout {
+                let start = table.start as u64;
+                layout.check_write("the blob table", &(start..start + data.len() as u64))?;
+            }
+            loader.add_data(table.start as u64, data)?;
+        }
+        if let Some(fill) = options.fill {
+            let written: Vec<Range<u64>> = loader
+                .data()
+                .map(|(addr, data)| addr..addr + data.len() as u64)
+                .collect();
+            for gap in unwritten_in_sectors(&sector_maps(session.target()), &written) {
+                debug!("Filling 0x{:x}-0x{:x}", gap.start, gap.end - 1);
+                loader.add_data(gap.start, &vec![fill; (gap.end - gap.start) as usize])?;
+            }
+        }
+        let times = RefCell::new(OperationTimes::default());
+        let mut started: Vec<(ProgressOperation, Instant)> = Vec::new();
+        let mut download = DownloadOptions::default();
+        download.progress = FlashProgress::new(|event| {
+            match &event {
+                ProgressEvent::Started(operation) => started.push((*operation, Instant::now())),
+                ProgressEvent::Finished(operation) => {
+                    let kind = std::mem::discriminant(operation);
+                    if let Some(pos) = started
+                        .iter()
+                        .position(|(o, _)| std::mem::discriminant(o) == kind)
+                    {
+                        let (_, start) = started.remove(pos);
+                        *times.borrow_mut().get_mut(*operation) += start.elapsed();
+                    }
+                }
+                _ => {}
+            }
+            progress(Progress::Flash(event));
+        });
+        download.keep_unwritten_bytes = options.restore_unwritten || keep;
+        download.skip_erase = options.skip_erase;
+        download.do_chip_erase = options.chip_erase;
+        // Skipped blobs have already been compared by checksum
+        download.verify = options.verify;
+        download.preferred_algos = options.flash_algorithms.clone();
+        for name in names {
+            if let Some(algorithm) = blob_info.info[*name]
+                .external
+                .as_ref()
+                .and_then(|e| e.algorithm.as_ref())
+            {
+                if !download.preferred_algos.contains(algorithm) {
+                    download.preferred_algos.push(algorithm.clone());
+                }
+            }
+        }
+        info!("Flashing");
+        loader.commit(session, download)?;
+        Ok(times.into_inner())
+    }
+
+    /// Reads back each blob, and the blob table if there is one, and
+    /// compares them with the blob info. Nothing is written to the target.
+    /// The blob table is named "blob table".
+    pub fn verify(&mut self, blob_info: &BlobInfoFile) -> DynResult<Vec<(String, Verified)>> {
+        let progress = &mut self.progress;
+        let session = self.session.as_mut().ok_or("Not attached to target")?;
+        let mut core = session.core(self.core)?;
+        let mut results = Vec::new();
+        for (name, blob) in &blob_info.info {
+            let result = match read_checksum(&mut core, name, blob, ReadKind::Verify, progress) {
+                Ok(checksum) if checksum == blob.checksum => Verified::Match,
+                Ok(_) => Verified::Mismatch,
+                Err(e) => Verified::ReadFailed(e.to_string()),
+            };
+            results.push((name.clone(), result));
+        }
+        if let Some(table) = &blob_info.table {
+            let expected = blob_table::encode(blob_info);
+            let mut data = vec![0u8; expected.len()];
+            let result = match core.read(table.start as u64, &mut data) {
+                Ok(()) if data == expected => Verified::Match,
+                Ok(()) => Verified::Mismatch,
+                Err(e) => Verified::ReadFailed(e.to_string()),
+            };
+            results.push(("blob table".to_string(), result));
+        }
+        Ok(results)
+    }
+
+    /// Reads a blob from flash
+    pub fn read_blob(&mut self, name: &str, blob: &BlobInfo) -> DynResult<Vec<u8>> {
+        let progress = &mut self.progress;
+        let session = self.session.as_mut().ok_or("Not attached to target")?;
+        let mut core = session.core(self.core)?;
+        progress(Progress::ReadStarted {
+            kind: ReadKind::Dump,
+            name,
+            size: blob.size,
+        });
+        let mut data = vec![0u8; blob.size as usize];
+        let result = data
+            .chunks_mut(1024)
+            .enumerate()
+            .try_for_each(|(index, chunk)| {
+                core.read(blob.load_address() + index as u64 * 1024, chunk)?;
+                progress(Progress::Read(chunk.len() as u32));
+                Ok::<_, probe_rs::Error>(())
+            });
+        progress(Progress::ReadFinished);
+        result?;
+        Ok(data)
+    }
+
+    /// Erases the sectors in ranges, as returned by erase_ranges
+    pub fn erase(&mut self, ranges: &[Range<u64>]) -> DynResult<()> {
+        let progress = &mut self.progress;
+        let session = self.session.as_mut().ok_or("Not attached to target")?;
+        for range in ranges {
+            info!("Erasing 0x{:08x}-0x{:08x}", range.start, range.end - 1);
+            probe_rs::flashing::erase(
+                session,
+                &mut FlashProgress::new(|event| progress(Progress::Flash(event))),
+                range.start,
+                range.end,
+                false,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Resets all cores, leaving them halted if requested
+    pub fn reset(&mut self, halt: bool) -> DynResult<()> {
+        let session = self.session()?;
+        for (index, _) in session.list_cores() {
+            let mut core = session.core(index)?;
+            if halt {
+                core.reset_and_halt(Duration::from_millis(500))?;
+            } else {
+                core.reset()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the blob table at addr. Returns None if there is no valid table.
+fn read_table(core: &mut Core, addr: u32) -> Option<Vec<blob_table::TableEntry>> {
+    let mut header = [0u8; blob_table::HEADER_SIZE as usize];
+    core.read(addr as u64, &mut header).ok()?;
+    let count = blob_table::decode_header(&header)?;
+    if count > MAX_TABLE_ENTRIES {
+        return None;
+    }
+    let mut data = vec![0u8; blob_table::table_size(count) as usize];
+    core.read(addr as u64, &mut data).ok()?;
+    let entries = blob_table::decode(&data);
+    if entries.is_none() {
+        debug!("No valid blob table at 0x{:08x}", addr);
+    }
+    entries
+}
+
+/// Checksum of a blob in flash, with the algorithm of its expected checksum
+fn read_checksum(
+    core: &mut Core,
+    name: &str,
+    blob: &BlobInfo,
+    kind: ReadKind,
+    progress: &mut ProgressFn,
+) -> DynResult<Checksum> {
+    progress(Progress::ReadStarted {
+        kind,
+        name,
+        size: blob.size,
+    });
+    let mut buf = [0u8; 1024];
+    let mut hasher = blob.checksum.algorithm.hasher();
+    let start = blob.load_address();
+    let mut pos = 0;
+    let result = loop {
+        if pos >= blob.size {
+            break Ok(hasher.finish());
+        }
+        let len = (blob.size - pos).min(buf.len() as u64) as usize;
+        if let Err(e) = core.read(start + pos, &mut buf[..len]) {
+            break Err(e.into());
+        }
+        hasher.update(&buf[..len]);
+        pos += len as u64;
+        progress(Progress::Read(len as u32));
+    };
+    progress(Progress::ReadFinished);
+    result
+}
+
+/// Erase sectors of each flash of the target, as described by its flash
+/// algorithms. Where several algorithms cover the same flash, the first
+/// one is used.
+pub fn sector_maps(target: &Target) -> Vec<SectorMap> {
+    let mut maps: Vec<SectorMap> = Vec::new();
+    for algo in &target.flash_algorithms {
+        let props = &algo.flash_properties;
+        if maps.iter().any(|m| m.contains(props.address_range.start)) {
+            continue;
+        }
+        maps.push(SectorMap {
+            start: props.address_range.start,
+            end: props.address_range.end,
+            sectors: props
+                .sectors
+                .iter()
+                .map(|s| SectorSize {
+                    offset: s.address,
+                    size: s.size,
+                })
+                .collect(),
+        });
+    }
+    maps
+}
+
+/// All sectors of the flash containing addr, or None if no map covers it
+pub fn flash_sectors(maps: &[SectorMap], addr: u64) -> Option<Vec<Range<u64>>> {
+    Some(maps.iter().find(|m| m.contains(addr))?.sectors())
+}
+
+/// Flash sectors overlapping start..end as a range of addresses and the
+/// number of sectors, or None if no map covers it
+pub fn erase_sectors(maps: &[SectorMap], start: u64, end: u64) -> Option<(Range<u64>, usize)> {
+    let touched: Vec<Range<u64>> = flash_sectors(maps, start)?
+        .into_iter()
+        .filter(|s| s.start < end && s.end > start)
+        .collect();
+    Some((touched.first()?.start..touched.last()?.end, touched.len()))
+}
+
+/// Parts of the flash sectors overlapping the written ranges that aren't
+/// written
+pub fn unwritten_in_sectors(maps: &[SectorMap], written: &[Range<u64>]) -> Vec<Range<u64>> {
+    let mut sectors: Vec<Range<u64>> = written
+        .iter()
+        .filter_map(|w| erase_sectors(maps, w.start, w.end).map(|(s, _)| s))
+        .collect();
+    sectors.sort_by_key(|s| s.start);
+    let mut merged: Vec<Range<u64>> = Vec::new();
+    for sector in sectors {
+        match merged.last_mut() {
+            Some(last) if sector.start <= last.end => last.end = last.end.max(sector.end),
+            _ => merged.push(sector),
+        }
+    }
+    let mut written = written.to_vec();
+    written.sort_by_key(|w| w.start);
+    let mut gaps = Vec::new();
+    for sector in merged {
+        let mut pos = sector.start;
+        for w in written
+            .iter()
+            .filter(|w| w.start < sector.end && w.end > sector.start)
+        {
+            if w.start > pos {
+                gaps.push(pos..w.start);
+            }
+            pos = pos.max(w.end);
+        }
+        if pos < sector.end {
+            gaps.push(pos..sector.end);
+        }
+    }
+    gaps
+}
+
+/// Parts of the sectors erased for the blobs and the blob table that are
+/// outside the blob region, e.g. because the layout isn't sector aligned.
+/// Blobs in external flash have a region of their own.
+pub fn erased_outside_region(maps: &[SectorMap], blob_info: &BlobInfoFile) -> Vec<Range<u64>> {
+    let (external, internal): (Vec<_>, Vec<_>) =
+        blob_info.regions().into_iter().partition(|(name, _)| {
+            blob_info
+                .info
+                .get(*name)
+                .is_some_and(|b| b.external.is_some())
+        });
+    let mut outside = Vec::new();
+    for regions in [internal, external] {
+        let (Some(first), Some(last)) = (
+            regions.iter().map(|(_, r)| r.start).min(),
+            regions.iter().map(|(_, r)| r.end).max(),
+        ) else {
+            continue;
+        };
+        for (_, region) in &regions {
+            let Some((sectors, _)) = erase_sectors(maps, region.start, region.end) else {
+                continue;
+            };
+            if sectors.start < first {
+                outside.push(sectors.start..first);
+            }
+            if sectors.end > last {
+                outside.push(last..sectors.end);
+            }
+        }
+    }
+    outside.sort_by_key(|r| r.start);
+    outside.dedup();
+    outside
+}
+
+/// Ranges of addresses as text, e.g. "0x08000000-0x08003fff"
+pub fn format_ranges(ranges: &[Range<u64>]) -> String {
+    ranges
+        .iter()
+        .map(|r| format!("0x{:08x}-0x{:08x}", r.start, r.end - 1))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Checks what flashing the blobs with options erases besides them.
+/// Erasing sectors shared with the application fails with EraseRefused,
+/// unless their contents are restored or the application is flashed too.
+/// Erasing the whole chip, or sectors outside the blob region, has to be
+/// confirmed. confirm gets a description and fails if not confirmed.
+pub fn check_erase(
+    maps: &[SectorMap],
+    blob_info: &BlobInfoFile,
+    options: &FlashOptions,
+    app_flashed: bool,
+    confirm: &mut dyn FnMut(&str) -> DynResult<()>,
+) -> DynResult<()> {
+    let mut confirm = |message: &str| -> DynResult<()> {
+        confirm(message).map_err(|e| EraseRefused(e.to_string()).into())
+    };
+    if options.chip_erase {
+        return confirm("This erases the whole chip, also everything that isn't a blob");
+    }
+    if options.skip_erase {
+        return Ok(());
+    }
+    if !app_flashed {
+        for (name, shared) in app_shared_sectors(maps, blob_info) {
+            let range = format_ranges(std::slice::from_ref(&shared));
+            if !options.restore_unwritten {
+                return Err(EraseRefused(format!(
+                    "The sectors of {} also hold {} of the application, restore the \
+                     unwritten bytes to keep it or align the blobs to sectors",
+                    name, range
+                ))
+                .into());
+            }
+            warn!(
+                "The sectors of {} also hold {} of the application, it is restored after erasing",
+                name, range
+            );
+        }
+    }
+    if options.restore_unwritten {
+        return Ok(());
+    }
+    let outside = erased_outside_region(maps, blob_info);
+    if outside.is_empty() {
+        return Ok(());
+    }
+    confirm(&format!(
+        "Erasing the sectors of the blobs also erases {} outside the blob region",
+        format_ranges(&outside)
+    ))
+}
+
+/// Parts of the application region in the sectors of the blobs and the
+/// blob table, which erasing them would also erase
+pub fn app_shared_sectors<'a>(
+    maps: &[SectorMap],
+    blob_info: &'a BlobInfoFile,
+) -> Vec<(&'a str, Range<u64>)> {
+    let Some(app) = &blob_info.app else {
+        return Vec::new();
+    };
+    let app = app.start as u64..app.start as u64 + app.size as u64;
+    blob_info
+        .regions()
+        .into_iter()
+        .filter_map(|(name, region)| {
+            let (sectors, _) = erase_sectors(maps, region.start, region.end)?;
+            let shared = sectors.start.max(app.start)..sectors.end.min(app.end);
+            (shared.start < shared.end).then_some((name, shared))
+        })
+        .collect()
+}
+
+/// Sectors to erase for the named blobs, or for all blobs and the blob
+/// table if names is empty, merged into contiguous ranges. Fails if a
+/// sector is shared with the application.
+pub fn erase_ranges(
+    maps: &[SectorMap],
+    blob_info: &BlobInfoFile,
+    names: &[String],
+) -> DynResult<Vec<Range<u64>>> {
+    for name in names {
+        if !blob_info.info.contains_key(name) {
+            return Err(format!("No blob named '{}'", name).into());
+        }
+    }
+    let selected = |name: &str| names.is_empty() || names.iter().any(|n| n == name);
+    for (name, shared) in app_shared_sectors(maps, blob_info) {
+        if selected(name) {
+            return Err(format!(
+                "Erasing {} would also erase 0x{:08x}-0x{:08x} of the application",
+                name,
+                shared.start,
+                shared.end - 1
+            )
+            .into());
+        }
+    }
+    let regions = blob_info.regions();
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+    for (name, region) in &regions {
+        if !selected(name) || region.is_empty() {
+            continue;
+        }
+        let (sectors, _) = erase_sectors(maps, region.start, region.end)
+            .ok_or_else(|| format!("No flash at 0x{:08x}", region.start))?;
+        for (other, other_region) in &regions {
+            if !selected(other)
+                && other_region.start < sectors.end
+                && other_region.end > sectors.start
+            {
+                warn!("Erasing {} also erases part of {}", name, other);
+            }
+        }
+        ranges.push(sectors);
+    }
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<u64>> = Vec::new();
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    Ok(merged)
+}
+
+/// Four 16 KiB sectors followed by a 64 KiB sector. The application ends
+/// in the second sector, which font shares with it. logo doesn't start or
+/// end at a sector boundary, and there is a gap between it and icons in
+/// the third sector.
+#[cfg(test)]
+fn test_layout(app: bool) -> (Vec<SectorMap>, BlobInfoFile) {
+    use crate::blob_info::{AppInfo, BlobInfoFileBuilder, ProbeInfo, TableInfo};
+    let maps = vec![SectorMap {
+        start: 0x0800_0000,
+        end: 0x0802_0000,
+        sectors: vec![
+            SectorSize {
+                offset: 0,
+                size: 0x4000,
+            },
+            SectorSize {
+                offset: 0x1_0000,
+                size: 0x1_0000,
+            },
+        ],
+    }];
+    let blob = |start, size| BlobInfo::new(start, size, Checksum::sha1([0; 20]), "blob.bin");
+    let mut builder = BlobInfoFileBuilder::new(ProbeInfo::new("STM32F401RETx"))
+        .with_blob("font", blob(0x0800_6000, 0x1000))
+        .with_blob("logo", blob(0x0800_8100, 0x700))
+        .with_blob("icons", blob(0x0800_9000, 0x1000))
+        .with_table(TableInfo {
+            start: 0x0801_0000,
+            size: 0x100,
+        });
+    if app {
+        builder = builder.with_app(AppInfo {
+            start: 0x0800_0000,
+            size: 0x6000,
+        });
+    }
+    (maps, builder.build())
+}
+
+#[test]
+fn test_erase_sectors() {
+    let (maps, _) = test_layout(true);
+    assert_eq!(flash_sectors(&maps, 0x0800_0000).unwrap().len(), 5);
+    assert_eq!(flash_sectors(&maps, 0x0802_0000), None);
+    assert_eq!(
+        erase_sectors(&maps, 0x0800_8100, 0x0800_8800),
+        Some((0x0800_8000..0x0800_c000, 1))
+    );
+    assert_eq!(
+        erase_sectors(&maps, 0x0800_4000, 0x0800_8000),
+        Some((0x0800_4000..0x0800_8000, 1))
+    );
+    assert_eq!(
+        erase_sectors(&maps, 0x0800_f000, 0x0801_0100),
+        Some((0x0800_c000..0x0802_0000, 2))
+    );
+    assert_eq!(erase_sectors(&maps, 0x0900_0000, 0x0900_0100), None);
+}
+
+#[test]
+fn test_unwritten_in_sectors() {
+    let (maps, _) = test_layout(true);
+    // The gap between logo and icons, and the sector edges around them
+    assert_eq!(
+        unwritten_in_sectors(&maps, &[0x0800_9000..0x0800_a000, 0x0800_8100..0x0800_8800]),
+        [
+            0x0800_8000..0x0800_8100,
+            0x0800_8800..0x0800_9000,
+            0x0800_a000..0x0800_c000
+        ]
+    );
+    // Sectors written completely leave nothing
+    assert_eq!(
+        unwritten_in_sectors(&maps, &[0x0800_4000..0x0800_6000, 0x0800_6000..0x0800_8000]),
+        []
+    );
+    let table = 0x0801_0000..0x0801_0100;
+    let rest = 0x0801_0100..0x0802_0000;
+    assert_eq!(unwritten_in_sectors(&maps, &[table]), [rest]);
+}
+
+#[test]
+fn test_erased_outside_region() {
+    let (maps, blob_info) = test_layout(true);
+    // The region reaches from font to the end of the blob table
+    assert_eq!(
+        erased_outside_region(&maps, &blob_info),
+        [0x0800_4000..0x0800_6000, 0x0801_0100..0x0802_0000]
+    );
+}
+
+#[test]
+fn test_app_shared_sectors() {
+    let (maps, blob_info) = test_layout(true);
+    assert_eq!(
+        app_shared_sectors(&maps, &blob_info),
+        [("font", 0x0800_4000..0x0800_6000)]
+    );
+    let (maps, blob_info) = test_layout(false);
+    assert!(app_shared_sectors(&maps, &blob_info).is_empty());
+}
+
+#[test]
+fn test_erase_ranges() {
+    let (maps, blob_info) = test_layout(true);
+    let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+    // font can't be erased without the application
+    assert!(erase_ranges(&maps, &blob_info, &[]).is_err());
+    assert!(erase_ranges(&maps, &blob_info, &names(&["font"])).is_err());
+    assert!(erase_ranges(&maps, &blob_info, &names(&["sound"])).is_err());
+    // Erasing only logo also erases icons in the same sector
+    let sector = 0x0800_8000..0x0800_c000;
+    assert_eq!(
+        erase_ranges(&maps, &blob_info, &names(&["logo"])).unwrap(),
+        std::slice::from_ref(&sector)
+    );
+    assert_eq!(
+        erase_ranges(&maps, &blob_info, &names(&["logo", "icons"])).unwrap(),
+        [sector]
+    );
+    let (maps, blob_info) = test_layout(false);
+    assert_eq!(
+        erase_ranges(&maps, &blob_info, &[]).unwrap(),
+        [0x0800_4000..0x0800_c000, 0x0801_0000..0x0802_0000]
+    );
+}
+
+#[test]
+fn test_check_erase() {
+    let (maps, blob_info) = test_layout(true);
+    let mut asked = Vec::new();
+    let mut confirm = |message: &str| -> DynResult<()> {
+        asked.push(message.to_string());
+        Ok(())
+    };
+    let options = FlashOptions::default();
+    let error = check_erase(&maps, &blob_info, &options, false, &mut confirm).unwrap_err();
+    assert!(error.is::<EraseRefused>());
+    // Flashing the application along with the blobs rewrites its part
+    check_erase(&maps, &blob_info, &options, true, &mut confirm).unwrap();
+    let restore = FlashOptions {
+        restore_unwritten: true,
+        ..FlashOptions::default()
+    };
+    check_erase(&maps, &blob_info, &restore, false, &mut confirm).unwrap();
+    assert_eq!(asked.len(), 1);
+    assert!(asked[0].contains("0x08004000-0x08005fff, 0x08010100-0x0801ffff"));
+    let refuse = &mut |_: &str| -> DynResult<()> { Err("Aborted".into()) };
+    let error = check_erase(&maps, &blob_info, &options, true, refuse).unwrap_err();
+    assert!(error.is::<EraseRefused>());
+}
@@ -0,0 +1,194 @@
+// Flashing through a GDB server on another machine, e.g. probe-rs, OpenOCD or
+// pyOCD serving a probe in a hardware lab, using the vFlash packets of the
+// GDB remote serial protocol.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+type DynResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// Flash operations may take long on large sectors
+const TIMEOUT: Duration = Duration::from_secs(60);
+/// Packet size used if the server doesn't tell
+const DEFAULT_PACKET_SIZE: usize = 1024;
+
+/// Sum of the payload bytes, modulo 256
+fn checksum(payload: &[u8]) -> u8 {
+    payload.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+/// Packet with start marker and checksum
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let checksum = checksum(payload);
+    let mut packet = Vec::with_capacity(payload.len() + 4);
+    packet.push(b'$');
+    packet.extend_from_slice(payload);
+    packet.extend_from_slice(format!("#{:02x}", checksum).as_bytes());
+    packet
+}
+
+/// Escapes binary data for vFlashWrite, appending to out
+fn escape(data: &[u8], out: &mut Vec<u8>) {
+    for &b in data {
+        if matches!(b, b'#' | b'$' | b'}' | b'*') {
+            out.push(b'}');
+            out.push(b ^ 0x20);
+        } else {
+            out.push(b);
+        }
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub struct GdbRemote {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    packet_size: usize,
+}
+
+impl GdbRemote {
+    pub fn connect(addr: &str) -> DynResult<GdbRemote> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        let mut remote = GdbRemote {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            packet_size: DEFAULT_PACKET_SIZE,
+        };
+        let features = remote.request(b"qSupported:multiprocess-")?;
+        for feature in String::from_utf8_lossy(&features).split(';') {
+            if let Some(size) = feature.strip_prefix("PacketSize=") {
+                remote.packet_size = usize::from_str_radix(size, 16)?;
+            }
+        }
+        Ok(remote)
+    }
+
+    fn send(&mut self, payload: &[u8]) -> DynResult<()> {
+        let packet = frame(payload);
+        loop {
+            self.writer.write_all(&packet)?;
+            let mut ack = [0u8];
+            self.reader.read_exact(&mut ack)?;
+            match ack[0] {
+                b'+' => return Ok(()),
+                // Retransmit on a bad checksum
+                b'-' => continue,
+                b => return Err(format!("Unexpected byte 0x{:02x} from GDB server", b).into()),
+            }
+        }
+    }
+
+    fn receive(&mut self) -> DynResult<Vec<u8>> {
+        loop {
+            let mut skipped = Vec::new();
+            self.reader.read_until(b'$', &mut skipped)?;
+            let mut payload = Vec::new();
+            self.reader.read_until(b'#', &mut payload)?;
+            if payload.pop() != Some(b'#') {
+                return Err("Connection to GDB server closed".into());
+            }
+            let mut digits = [0u8; 2];
+            self.reader.read_exact(&mut digits)?;
+            let expected = std::str::from_utf8(&digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+            if expected == Some(checksum(&payload)) {
+                self.writer.write_all(b"+")?;
+                return Ok(payload);
+            }
+            // Ask the server to retransmit
+            self.writer.write_all(b"-")?;
+        }
+    }
+
+    fn request(&mut self, payload: &[u8]) -> DynResult<Vec<u8>> {
+        self.send(payload)?;
+        self.receive()
+    }
+
+    fn request_ok(&mut self, payload: &[u8]) -> DynResult<()> {
+        let reply = self.request(payload)?;
+        if reply == b"OK" {
+            Ok(())
+        } else {
+            Err(format!(
+                "GDB server replied '{}' to {}",
+                String::from_utf8_lossy(&reply),
+                String::from_utf8_lossy(&payload[..payload.len().min(20)])
+            )
+            .into())
+        }
+    }
+
+    /// Erases flash, the range must cover whole sectors
+    pub fn flash_erase(&mut self, addr: u32, len: u32) -> DynResult<()> {
+        self.request_ok(format!("vFlashErase:{:x},{:x}", addr, len).as_bytes())
+    }
+
+    /// Writes erased flash, calling progress with the number of bytes
+    /// written so far
+    pub fn flash_write(
+        &mut self,
+        addr: u32,
+        data: &[u8],
+        progress: &mut dyn FnMut(usize),
+    ) -> DynResult<()> {
+        let mut written = 0;
+        while written < data.len() {
+            let mut payload = format!("vFlashWrite:{:x}:", addr + written as u32).into_bytes();
+            // Leave room for the frame and escaped bytes
+            let limit = self.packet_size.saturating_sub(6);
+            let start = written;
+            while written < data.len() && payload.len() < limit {
+                escape(&data[written..written + 1], &mut payload);
+                written += 1;
+            }
+            if written == start {
+                return Err("GDB server packet size too small".into());
+            }
+            self.request_ok(&payload)?;
+            progress(written);
+        }
+        Ok(())
+    }
+
+    /// Commits the written data
+    pub fn flash_done(&mut self) -> DynResult<()> {
+        self.request_ok(b"vFlashDone")
+    }
+
+    /// Runs a monitor command, e.g. "reset"
+    pub fn monitor(&mut self, command: &str) -> DynResult<()> {
+        self.send(format!("qRcmd,{}", hex(command.as_bytes())).as_bytes())?;
+        // Console output comes as O packets before the result
+        loop {
+            let reply = self.receive()?;
+            if reply.first() == Some(&b'O') && reply != b"OK" {
+                continue;
+            }
+            if reply == b"OK" || reply.is_empty() {
+                return Ok(());
+            }
+            return Err(format!(
+                "GDB server replied '{}' to monitor {}",
+                String::from_utf8_lossy(&reply),
+                command
+            )
+            .into());
+        }
+    }
+}
+
+#[test]
+fn test_frame() {
+    assert_eq!(frame(b"OK"), b"$OK#9a");
+    assert_eq!(frame(b""), b"$#00");
+    let mut out = Vec::new();
+    escape(b"a#$}*", &mut out);
+    assert_eq!(out, b"a}\x03}\x04}\x5d}\x0a");
+}
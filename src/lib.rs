@@ -1,4 +1,21 @@
 mod link_script_parser;
 mod build_blob;
+mod crc32;
+mod littlefs;
+mod perfect_hash;
+pub mod blob_image;
+pub mod blob_index;
 pub mod blob_info;
+pub mod blob_table;
+pub mod compress;
+#[cfg(feature = "loader")]
+pub mod dfu;
+pub mod embed_config;
+pub mod encrypt;
+pub mod flash;
+#[cfg(feature = "loader")]
+pub mod gdb_remote;
+#[cfg(feature = "loader")]
+pub mod serial_boot;
+pub mod signing;
 pub use build_blob::prepare_blob;
@@ -0,0 +1,481 @@
+// littlefs images built from a directory, so that the firmware mounts a
+// filesystem with the files already in place. The image holds what
+// littlefs itself writes when formatting and creating the files: one
+// commit per metadata pair, with small files inline in their directory and
+// larger ones in CTZ skip-lists. The unused blocks are left erased.
+
+use crate::crc32::Crc32;
+use std::fs;
+use std::path::Path;
+
+/// Layout of the filesystem. It has to match the configuration the
+/// firmware mounts it with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Geometry {
+    pub block_size: u32,
+    pub block_count: u32,
+    /// Smallest unit written. Commits are padded to it.
+    pub prog_size: u32,
+    /// Size of the caches. Files up to this size, and at most an eighth of
+    /// a block, are stored inline in their directory.
+    pub cache_size: u32,
+}
+
+impl Geometry {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.prog_size.is_power_of_two() || self.prog_size > 256 {
+            return Err("The program size is not a power of two up to 256".to_string());
+        }
+        if self.cache_size == 0 || !self.cache_size.is_multiple_of(self.prog_size) {
+            return Err("The cache size is not a multiple of the program size".to_string());
+        }
+        if self.block_size < 128 || !self.block_size.is_multiple_of(self.cache_size) {
+            return Err(
+                "The block size is less than 128 or not a multiple of the cache size".to_string(),
+            );
+        }
+        if self.block_count < 2 {
+            return Err("There are less than 2 blocks".to_string());
+        }
+        self.size()
+            .ok_or_else(|| "The image is larger than 4 GiB".to_string())?;
+        Ok(())
+    }
+
+    /// Size of the image, the whole filesystem
+    pub fn size(&self) -> Option<u32> {
+        self.block_size.checked_mul(self.block_count)
+    }
+
+    fn inline_max(&self) -> usize {
+        self.cache_size.min(ATTR_MAX).min(self.block_size / 8) as usize
+    }
+}
+
+// Version 2.0 of the on-disk format, which all littlefs 2 releases mount
+const DISK_VERSION: u32 = 0x0002_0000;
+const NAME_MAX: u32 = 255;
+const FILE_MAX: u32 = 0x7fff_ffff;
+const ATTR_MAX: u32 = 1022;
+
+// Tag types
+const TYPE_REG: u32 = 0x001;
+const TYPE_DIR: u32 = 0x002;
+const TYPE_SUPERBLOCK: u32 = 0x0ff;
+const TYPE_DIRSTRUCT: u32 = 0x200;
+const TYPE_INLINESTRUCT: u32 = 0x201;
+const TYPE_CTZSTRUCT: u32 = 0x202;
+const TYPE_CREATE: u32 = 0x401;
+const TYPE_CRC: u32 = 0x500;
+const TYPE_SOFTTAIL: u32 = 0x600;
+const TYPE_HARDTAIL: u32 = 0x601;
+
+// Id of tags that don't belong to an entry
+const NO_ID: u32 = 0x3ff;
+
+fn tag(kind: u32, id: u32, size: usize) -> u32 {
+    kind << 20 | id << 10 | size as u32
+}
+
+// littlefs uses CRC-32 without the final inversion
+fn lfs_crc(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    !crc.value()
+}
+
+enum Content {
+    Inline(Vec<u8>),
+    Ctz(Vec<u8>),
+    Dir(usize), // Index in the directory list
+}
+
+struct Entry {
+    name: String,
+    content: Content,
+}
+
+impl Entry {
+    // Bytes the entry takes in a commit
+    fn size(&self) -> usize {
+        let data = match &self.content {
+            Content::Inline(data) => data.len(),
+            Content::Ctz(_) | Content::Dir(_) => 8,
+        };
+        3 * 4 + self.name.len() + data
+    }
+}
+
+struct Dir {
+    pairs: Vec<Vec<Entry>>, // Entries of each metadata pair, chained with hard tails
+    blocks: Vec<u32>,       // First block of each pair
+}
+
+// A commit being built. Tags are stored big endian, XORed with the
+// previous tag.
+struct Commit {
+    data: Vec<u8>,
+    ptag: u32,
+}
+
+impl Commit {
+    fn new(revision: u32) -> Commit {
+        Commit {
+            data: revision.to_le_bytes().to_vec(),
+            ptag: 0xffffffff,
+        }
+    }
+
+    fn push(&mut self, tag: u32, data: &[u8]) {
+        self.data.extend((tag ^ self.ptag).to_be_bytes());
+        self.data.extend(data);
+        self.ptag = tag;
+    }
+
+    // Ends the commit with the CRC, padded to the program size
+    fn finish(mut self, geometry: &Geometry) -> Vec<u8> {
+        let start = self.data.len() + 4;
+        let end = (start + 4).next_multiple_of(geometry.prog_size as usize);
+        self.push(tag(TYPE_CRC, NO_ID, end - start), &[]);
+        let crc = lfs_crc(&self.data);
+        self.data.extend(crc.to_le_bytes());
+        self.data.resize(end, 0xff);
+        self.data
+    }
+}
+
+// Reads path and the directories below it into dirs, in the order they
+// are threaded together by their tails. Returns the index of path.
+fn read_dir(path: &Path, geometry: &Geometry, dirs: &mut Vec<Dir>) -> Result<usize, String> {
+    let index = dirs.len();
+    dirs.push(Dir {
+        pairs: Vec::new(),
+        blocks: Vec::new(),
+    });
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(path).map_err(|e| format!("{}: {}", path.display(), e))? {
+        let entry = entry.map_err(|e| format!("{}: {}", path.display(), e))?;
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|_| format!("{}: Name can not be converted to UTF-8", path.display()))?;
+        if name.len() > NAME_MAX as usize {
+            return Err(format!(
+                "{}: Name is longer than {} bytes",
+                entry.path().display(),
+                NAME_MAX
+            ));
+        }
+        paths.push((name, entry.path()));
+    }
+    // Entries are sorted by name, like littlefs keeps them
+    paths.sort();
+    let mut entries = Vec::new();
+    for (name, path) in paths {
+        let content = if path.is_dir() {
+            Content::Dir(read_dir(&path, geometry, dirs)?)
+        } else {
+            let data = fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            if data.len() > FILE_MAX as usize {
+                return Err(format!("{}: File is too large", path.display()));
+            }
+            if data.len() <= geometry.inline_max() {
+                Content::Inline(data)
+            } else {
+                Content::Ctz(data)
+            }
+        };
+        entries.push(Entry { name, content });
+    }
+    dirs[index].pairs = split_entries(entries, geometry, index == 0)?;
+    Ok(index)
+}
+
+// Splits the entries of a directory over metadata pairs. Like when
+// littlefs compacts a directory, a pair is filled to half a block, leaving
+// room for later commits.
+fn split_entries(
+    entries: Vec<Entry>,
+    geometry: &Geometry,
+    root: bool,
+) -> Result<Vec<Vec<Entry>>, String> {
+    // Revision count, tail and CRC
+    let overhead = 4 + 3 * 4 + 8 + geometry.prog_size as usize;
+    let limit = geometry.block_size as usize / 2;
+    let mut pairs = vec![Vec::new()];
+    // The superblock entry is first in the root directory
+    let mut used = if root { 3 * 4 + 8 + 24 } else { 0 };
+    for entry in entries {
+        let size = entry.size();
+        let pair = pairs.last_mut().unwrap();
+        if overhead + used + size > limit && !pair.is_empty() {
+            pairs.push(Vec::new());
+            used = 0;
+        }
+        if overhead + used + size > geometry.block_size as usize {
+            return Err(format!(
+                "Entry {} does not fit in a block of {} bytes",
+                entry.name, geometry.block_size
+            ));
+        }
+        used += size;
+        pairs.last_mut().unwrap().push(entry);
+    }
+    Ok(pairs)
+}
+
+// Writes a file as a CTZ skip-list, returning the last block. Block n of
+// the file starts with pointers to blocks n - 1, n - 2, n - 4 and so on,
+// as many as n has trailing zeros plus one.
+fn write_ctz(
+    image: &mut [u8],
+    data: &[u8],
+    next_block: &mut u32,
+    geometry: &Geometry,
+) -> Result<u32, String> {
+    let block_size = geometry.block_size as usize;
+    let mut blocks: Vec<u32> = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        if *next_block >= geometry.block_count {
+            return Err("The files don't fit in the image".to_string());
+        }
+        let block = *next_block;
+        *next_block += 1;
+        let index = blocks.len();
+        let skips = if index == 0 {
+            0
+        } else {
+            index.trailing_zeros() as usize + 1
+        };
+        let out = &mut image[block as usize * block_size..][..block_size];
+        for i in 0..skips {
+            out[i * 4..][..4].copy_from_slice(&blocks[index - (1 << i)].to_le_bytes());
+        }
+        let len = rest.len().min(block_size - skips * 4);
+        out[skips * 4..][..len].copy_from_slice(&rest[..len]);
+        rest = &rest[len..];
+        blocks.push(block);
+    }
+    Ok(*blocks.last().unwrap())
+}
+
+fn pair_bytes(block: u32) -> [u8; 8] {
+    let mut pair = [0; 8];
+    pair[..4].copy_from_slice(&block.to_le_bytes());
+    pair[4..].copy_from_slice(&(block + 1).to_le_bytes());
+    pair
+}
+
+/// Builds an image of the files in dir and the directories below it
+pub fn build_image(dir: &Path, geometry: &Geometry) -> Result<Vec<u8>, String> {
+    geometry.validate()?;
+    let mut dirs = Vec::new();
+    read_dir(dir, geometry, &mut dirs)?;
+    // Metadata pairs come first, starting with the root at block 0 and 1
+    let mut next_block = 0;
+    for dir in &mut dirs {
+        for _ in &dir.pairs {
+            dir.blocks.push(next_block);
+            next_block += 2;
+        }
+    }
+    if next_block > geometry.block_count {
+        return Err("The directories don't fit in the image".to_string());
+    }
+    let block_size = geometry.block_size as usize;
+    let mut image = vec![0xff; geometry.size().unwrap() as usize];
+    for (dir_index, dir) in dirs.iter().enumerate() {
+        for (pair_index, entries) in dir.pairs.iter().enumerate() {
+            let mut commit = Commit::new(1);
+            let mut id = 0;
+            if dir_index == 0 && pair_index == 0 {
+                let superblock: Vec<u8> = [
+                    DISK_VERSION,
+                    geometry.block_size,
+                    geometry.block_count,
+                    NAME_MAX,
+                    FILE_MAX,
+                    ATTR_MAX,
+                ]
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect();
+                commit.push(tag(TYPE_CREATE, 0, 0), &[]);
+                commit.push(tag(TYPE_SUPERBLOCK, 0, 8), b"littlefs");
+                commit.push(tag(TYPE_INLINESTRUCT, 0, superblock.len()), &superblock);
+                id += 1;
+            }
+            for entry in entries {
+                let name = entry.name.as_bytes();
+                commit.push(tag(TYPE_CREATE, id, 0), &[]);
+                match &entry.content {
+                    Content::Inline(data) => {
+                        commit.push(tag(TYPE_REG, id, name.len()), name);
+                        commit.push(tag(TYPE_INLINESTRUCT, id, data.len()), data);
+                    }
+                    Content::Ctz(data) => {
+                        let head = write_ctz(&mut image, data, &mut next_block, geometry)?;
+                        let mut ctz = head.to_le_bytes().to_vec();
+                        ctz.extend((data.len() as u32).to_le_bytes());
+                        commit.push(tag(TYPE_REG, id, name.len()), name);
+                        commit.push(tag(TYPE_CTZSTRUCT, id, ctz.len()), &ctz);
+                    }
+                    Content::Dir(index) => {
+                        commit.push(tag(TYPE_DIR, id, name.len()), name);
+                        commit.push(
+                            tag(TYPE_DIRSTRUCT, id, 8),
+                            &pair_bytes(dirs[*index].blocks[0]),
+                        );
+                    }
+                }
+                id += 1;
+            }
+            // A hard tail continues the directory, a soft tail leads to the
+            // next directory
+            if let Some(block) = dir.blocks.get(pair_index + 1) {
+                commit.push(tag(TYPE_HARDTAIL, NO_ID, 8), &pair_bytes(*block));
+            } else if let Some(next) = dirs.get(dir_index + 1) {
+                commit.push(tag(TYPE_SOFTTAIL, NO_ID, 8), &pair_bytes(next.blocks[0]));
+            }
+            let data = commit.finish(geometry);
+            let block = dir.blocks[pair_index] as usize;
+            image[block * block_size..][..data.len()].copy_from_slice(&data);
+        }
+    }
+    Ok(image)
+}
+
+// Tags of the commit in a block, checking the CRC
+#[cfg(test)]
+fn read_commit(block: &[u8]) -> Vec<(u32, Vec<u8>)> {
+    let mut tags = Vec::new();
+    let mut ptag = 0xffffffff;
+    let mut off = 4;
+    loop {
+        let tag = u32::from_be_bytes(block[off..off + 4].try_into().unwrap()) ^ ptag;
+        assert_eq!(tag >> 31, 0);
+        let size = (tag & 0x3ff) as usize;
+        if tag >> 20 == TYPE_CRC {
+            let crc = u32::from_le_bytes(block[off + 4..off + 8].try_into().unwrap());
+            assert_eq!(crc, lfs_crc(&block[..off + 4]));
+            return tags;
+        }
+        tags.push((tag, block[off + 4..off + 4 + size].to_vec()));
+        ptag = tag;
+        off += 4 + size;
+    }
+}
+
+#[cfg(test)]
+fn read_u32(data: &[u8], index: usize) -> u32 {
+    u32::from_le_bytes(data[index * 4..][..4].try_into().unwrap())
+}
+
+// Reads the files of the directory at block and below it, by path
+#[cfg(test)]
+fn read_files(
+    image: &[u8],
+    bs: usize,
+    block: u32,
+    prefix: &str,
+) -> std::collections::BTreeMap<String, Vec<u8>> {
+    let mut files = std::collections::BTreeMap::new();
+    let mut next = Some(block as usize);
+    while let Some(block) = next.take() {
+        let mut names = std::collections::BTreeMap::new();
+        for (tag, data) in read_commit(&image[block * bs..][..bs]) {
+            let id = tag >> 10 & 0x3ff;
+            match tag >> 20 {
+                TYPE_REG | TYPE_DIR => {
+                    let name = String::from_utf8(data).unwrap();
+                    names.insert(id, format!("{}{}", prefix, name));
+                }
+                TYPE_INLINESTRUCT if names.contains_key(&id) => {
+                    files.insert(names[&id].clone(), data);
+                }
+                TYPE_CTZSTRUCT => {
+                    let size = read_u32(&data, 1) as usize;
+                    // Blocks with their data offsets, from the first one
+                    let mut layout = Vec::new();
+                    let mut total = 0;
+                    while total < size {
+                        let n = layout.len();
+                        let skips = if n == 0 {
+                            0
+                        } else {
+                            n.trailing_zeros() as usize + 1
+                        };
+                        layout.push(skips * 4);
+                        total += bs - skips * 4;
+                    }
+                    let mut blocks = vec![read_u32(&data, 0) as usize];
+                    while blocks.len() < layout.len() {
+                        blocks.push(read_u32(&image[blocks.last().unwrap() * bs..], 0) as usize);
+                    }
+                    let mut content = Vec::new();
+                    for (b, off) in blocks.iter().rev().zip(layout) {
+                        content.extend(&image[b * bs + off..(b + 1) * bs]);
+                    }
+                    content.truncate(size);
+                    files.insert(names[&id].clone(), content);
+                }
+                TYPE_DIRSTRUCT => {
+                    let prefix = format!("{}/", names[&id]);
+                    files.extend(read_files(image, bs, read_u32(&data, 0), &prefix));
+                }
+                TYPE_HARDTAIL => next = Some(read_u32(&data, 0) as usize),
+                _ => {}
+            }
+        }
+    }
+    files
+}
+
+#[test]
+fn test_build_image() {
+    let dir = std::env::temp_dir().join(format!("littlefs_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("fonts/bold")).unwrap();
+    fs::create_dir_all(dir.join("empty")).unwrap();
+    let large: Vec<u8> = (0..5000u32).map(|i| (i * 7) as u8).collect();
+    let mut expected = std::collections::BTreeMap::new();
+    expected.insert("config.txt".to_string(), b"volume=3\n".to_vec());
+    expected.insert("fonts/regular.bin".to_string(), large.clone());
+    expected.insert("fonts/bold/a.bin".to_string(), large[..300].to_vec());
+    expected.insert("fonts/bold/empty.bin".to_string(), Vec::new());
+    // Enough entries to take more than one pair
+    for i in 0..20 {
+        expected.insert(format!("log{:02}.txt", i), vec![b'x'; 30]);
+    }
+    for (path, data) in &expected {
+        fs::write(dir.join(path), data).unwrap();
+    }
+    let geometry = Geometry {
+        block_size: 512,
+        block_count: 64,
+        prog_size: 16,
+        cache_size: 64,
+    };
+    let image = build_image(&dir, &geometry).unwrap();
+    assert_eq!(image.len(), 512 * 64);
+    let root = read_commit(&image[..512]);
+    assert_eq!(root[1].1, b"littlefs");
+    assert_eq!(read_u32(&root[2].1, 1), 512);
+    assert_eq!(read_u32(&root[2].1, 2), 64);
+    assert!(root.iter().any(|(tag, _)| tag >> 20 == TYPE_HARDTAIL));
+    assert_eq!(read_files(&image, 512, 0, ""), expected);
+    // The second block of each pair is left erased
+    assert!(image[512..1024].iter().all(|&b| b == 0xff));
+    let small = Geometry {
+        block_count: 8,
+        ..geometry
+    };
+    assert!(build_image(&dir, &small).is_err());
+    let invalid = Geometry {
+        cache_size: 24,
+        ..geometry
+    };
+    assert!(build_image(&dir, &invalid).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,127 @@
+// Perfect hash of blob names for lookup by name in generated code.
+//
+// Names are first hashed into buckets. Each bucket has a displacement that
+// is used as seed for a second hash, chosen so that all names end up in
+// different slots.
+
+/// 32-bit FNV-1a hash of a name, with the seed mixed into the offset basis
+/// and a final avalanche step
+pub const fn seeded_hash(name: &str, seed: u32) -> u32 {
+    let bytes = name.as_bytes();
+    let mut hash = 0x811c9dc5u32 ^ seed.wrapping_mul(0x9e3779b9);
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u32;
+        hash = hash.wrapping_mul(0x01000193);
+        i += 1;
+    }
+    // Mix the high bits into the low ones, which otherwise only depend on
+    // the low bits of the seed
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85ebca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2ae35);
+    hash ^ (hash >> 16)
+}
+
+/// Source of seeded_hash for inclusion in generated code
+pub const SEEDED_HASH_SOURCE: &str = r#"
+const fn blob_seeded_hash(name: &str, seed: u32) -> u32 {
+    let bytes = name.as_bytes();
+    let mut hash = 0x811c9dc5u32 ^ seed.wrapping_mul(0x9e3779b9);
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u32;
+        hash = hash.wrapping_mul(0x01000193);
+        i += 1;
+    }
+    // Mix the high bits into the low ones, which otherwise only depend on
+    // the low bits of the seed
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85ebca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2ae35);
+    hash ^ (hash >> 16)
+}
+"#;
+
+const MAX_DISPLACEMENT: u32 = 1_000_000;
+
+pub struct PerfectHash {
+    /// Displacement for each bucket
+    pub displacements: Vec<u32>,
+    /// Index into the names for each slot, None for unused slots
+    pub slots: Vec<Option<usize>>,
+}
+
+impl PerfectHash {
+    /// Slot of a name, which has to be compared with the name stored there
+    pub fn slot(&self, name: &str) -> usize {
+        let bucket = seeded_hash(name, 0) as usize % self.displacements.len();
+        seeded_hash(name, self.displacements[bucket]) as usize % self.slots.len()
+    }
+}
+
+/// Builds a perfect hash for the names, which must be unique
+pub fn build(names: &[&str]) -> Result<PerfectHash, String> {
+    let bucket_count = names.len().max(1);
+    // Some spare slots make it a lot easier to find displacements
+    let slot_count = names.len() + names.len() / 4 + 1;
+    let mut buckets = vec![Vec::new(); bucket_count];
+    for (index, name) in names.iter().enumerate() {
+        buckets[seeded_hash(name, 0) as usize % bucket_count].push(index);
+    }
+    // Place the largest buckets first while there are many free slots
+    let mut order: Vec<usize> = (0..bucket_count).collect();
+    order.sort_by_key(|&b| std::cmp::Reverse(buckets[b].len()));
+    let mut displacements = vec![0; bucket_count];
+    let mut slots = vec![None; slot_count];
+    for bucket in order {
+        let entries = &buckets[bucket];
+        if entries.is_empty() {
+            break;
+        }
+        let mut found = false;
+        for displacement in 1..MAX_DISPLACEMENT {
+            let positions: Vec<usize> = entries
+                .iter()
+                .map(|&i| seeded_hash(names[i], displacement) as usize % slot_count)
+                .collect();
+            let free = positions
+                .iter()
+                .enumerate()
+                .all(|(n, &p)| slots[p].is_none() && !positions[..n].contains(&p));
+            if free {
+                for (&i, &p) in entries.iter().zip(&positions) {
+                    slots[p] = Some(i);
+                }
+                displacements[bucket] = displacement;
+                found = true;
+                break;
+            }
+        }
+        if !found {
+            return Err("Failed to find a perfect hash for the blob names".to_string());
+        }
+    }
+    Ok(PerfectHash {
+        displacements,
+        slots,
+    })
+}
+
+#[test]
+fn test_build() {
+    let names: Vec<String> = (0..200).map(|i| format!("blob_{}", i)).collect();
+    let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+    let hash = build(&names).unwrap();
+    for (index, name) in names.iter().enumerate() {
+        assert_eq!(hash.slots[hash.slot(name)], Some(index));
+    }
+    let names = ["inl", "a", "b", "c", "z", "logo"];
+    let hash = build(&names).unwrap();
+    for (index, name) in names.iter().enumerate() {
+        assert_eq!(hash.slots[hash.slot(name)], Some(index));
+    }
+    assert_eq!(build(&[]).unwrap().slots, vec![None]);
+}
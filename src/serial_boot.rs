@@ -0,0 +1,185 @@
+// Writing an image through the UART bootloader in the STM32 system memory,
+// as described in ST application note AN3155.
+
+use serialport::{DataBits, Parity, SerialPort, StopBits};
+use std::io::{Read, Write};
+use std::time::Duration;
+
+type DynResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+const ACK: u8 = 0x79;
+const NACK: u8 = 0x1f;
+const INIT: u8 = 0x7f;
+
+const CMD_GET: u8 = 0x00;
+const CMD_GO: u8 = 0x21;
+const CMD_WRITE_MEMORY: u8 = 0x31;
+const CMD_ERASE: u8 = 0x43;
+const CMD_EXTENDED_ERASE: u8 = 0x44;
+
+/// Largest amount of data in a Write Memory command
+pub const MAX_WRITE: usize = 256;
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+const ERASE_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |x, b| x ^ b)
+}
+
+/// Address followed by its checksum
+fn address_frame(addr: u32) -> Vec<u8> {
+    let mut frame = addr.to_be_bytes().to_vec();
+    frame.push(checksum(&frame));
+    frame
+}
+
+/// Length - 1, data and checksum for Write Memory. The length must be a
+/// multiple of 4.
+fn data_frame(data: &[u8]) -> Vec<u8> {
+    let mut frame = vec![(data.len() - 1) as u8];
+    frame.extend_from_slice(data);
+    frame.push(checksum(&frame));
+    frame
+}
+
+/// Page list for Erase or Extended Erase
+fn erase_frame(pages: &[u16], extended: bool) -> Vec<u8> {
+    let mut frame = Vec::new();
+    if extended {
+        frame.extend_from_slice(&(pages.len() as u16 - 1).to_be_bytes());
+        for page in pages {
+            frame.extend_from_slice(&page.to_be_bytes());
+        }
+    } else {
+        frame.push((pages.len() - 1) as u8);
+        frame.extend(pages.iter().map(|&p| p as u8));
+    }
+    frame.push(checksum(&frame));
+    frame
+}
+
+pub struct SerialBootloader {
+    port: Box<dyn SerialPort>,
+    extended_erase: bool,
+}
+
+impl SerialBootloader {
+    /// Opens the port and synchronizes with the bootloader, which must have
+    /// been started, e.g. with BOOT0 high during reset
+    pub fn open(path: &str, baud: u32) -> DynResult<SerialBootloader> {
+        let port = serialport::new(path, baud)
+            .data_bits(DataBits::Eight)
+            .parity(Parity::Even)
+            .stop_bits(StopBits::One)
+            .timeout(TIMEOUT)
+            .open()?;
+        let mut boot = SerialBootloader {
+            port,
+            extended_erase: false,
+        };
+        boot.port.write_all(&[INIT])?;
+        match boot.read_byte()? {
+            // Already synchronized if the bootloader was used before
+            ACK | NACK => {}
+            b => return Err(format!("Unexpected answer 0x{:02x} from bootloader", b).into()),
+        }
+        boot.command(CMD_GET)?;
+        let count = boot.read_byte()? as usize + 1;
+        let mut data = vec![0; count];
+        boot.port.read_exact(&mut data)?;
+        boot.wait_ack(TIMEOUT)?;
+        // The first byte is the bootloader version, the rest are commands
+        boot.extended_erase = data[1..].contains(&CMD_EXTENDED_ERASE);
+        Ok(boot)
+    }
+
+    fn read_byte(&mut self) -> DynResult<u8> {
+        let mut b = [0u8];
+        self.port.read_exact(&mut b)?;
+        Ok(b[0])
+    }
+
+    fn wait_ack(&mut self, timeout: Duration) -> DynResult<()> {
+        self.port.set_timeout(timeout)?;
+        let answer = self.read_byte();
+        self.port.set_timeout(TIMEOUT)?;
+        match answer? {
+            ACK => Ok(()),
+            NACK => Err("Bootloader refused the command".into()),
+            b => Err(format!("Unexpected answer 0x{:02x} from bootloader", b).into()),
+        }
+    }
+
+    fn command(&mut self, command: u8) -> DynResult<()> {
+        self.port.write_all(&[command, !command])?;
+        self.wait_ack(TIMEOUT)
+    }
+
+    /// Erases flash pages, numbered from the start of flash
+    pub fn erase(&mut self, pages: &[u16]) -> DynResult<()> {
+        if pages.is_empty() {
+            return Ok(());
+        }
+        if self.extended_erase {
+            // Counts from 0xFFF0 up select the special erase modes
+            if pages.len() > 0xFFF0 {
+                return Err("Too many pages for the Extended Erase command".into());
+            }
+            self.command(CMD_EXTENDED_ERASE)?;
+        } else {
+            // A count of 0xFF selects global erase
+            if pages.iter().any(|&p| p > 0xff) || pages.len() >= 0x100 {
+                return Err("Too many pages for the Erase command".into());
+            }
+            self.command(CMD_ERASE)?;
+        }
+        self.port
+            .write_all(&erase_frame(pages, self.extended_erase))?;
+        self.wait_ack(ERASE_TIMEOUT)
+    }
+
+    /// Writes data, calling progress with the number of bytes written so far
+    pub fn write(
+        &mut self,
+        addr: u32,
+        data: &[u8],
+        progress: &mut dyn FnMut(usize),
+    ) -> DynResult<()> {
+        let mut written = 0;
+        for chunk in data.chunks(MAX_WRITE) {
+            // The length has to be a multiple of 4
+            let mut chunk = chunk.to_vec();
+            chunk.resize(chunk.len().next_multiple_of(4), 0xff);
+            self.command(CMD_WRITE_MEMORY)?;
+            self.port.write_all(&address_frame(addr + written as u32))?;
+            self.wait_ack(TIMEOUT)?;
+            self.port.write_all(&data_frame(&chunk))?;
+            self.wait_ack(TIMEOUT)?;
+            written += chunk.len().min(data.len() - written);
+            progress(written);
+        }
+        Ok(())
+    }
+
+    /// Starts the application with the vector table at addr
+    pub fn go(mut self, addr: u32) -> DynResult<()> {
+        self.command(CMD_GO)?;
+        self.port.write_all(&address_frame(addr))?;
+        self.wait_ack(TIMEOUT)
+    }
+}
+
+#[test]
+fn test_frames() {
+    assert_eq!(address_frame(0x08000000), [0x08, 0, 0, 0, 0x08]);
+    assert_eq!(data_frame(&[1, 2, 3, 4]), [3, 1, 2, 3, 4, 7]);
+    assert_eq!(
+        erase_frame(&[0x10, 0x11], false),
+        [1, 0x10, 0x11, 1 ^ 0x10 ^ 0x11]
+    );
+    assert_eq!(
+        erase_frame(&[0x10, 0x11], true),
+        [0, 1, 0, 0x10, 0, 0x11, 1 ^ 0x10 ^ 0x11]
+    );
+}
@@ -0,0 +1,81 @@
+// Detached ed25519 signatures of the blob info files. The signature is of
+// the file as written, and is kept next to it in a file with ".sig" added
+// to the name, as 128 hex digits.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use std::path::{Path, PathBuf};
+
+/// Environment variable with the signing key, overriding the key file in
+/// the blob configuration
+pub const SIGNING_KEY_ENV: &str = "BLOB_INFO_SIGNING_KEY";
+
+/// Name of the signature file of path
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".sig");
+    PathBuf::from(name)
+}
+
+fn from_hex<const N: usize>(text: &str, what: &str) -> Result<[u8; N], String> {
+    let text = text.trim();
+    if text.len() != N * 2 {
+        return Err(format!("A {} has {} hex digits", what, N * 2));
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = text
+            .get(i * 2..i * 2 + 2)
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| format!("Invalid hex digits in {}", what))?;
+    }
+    Ok(bytes)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses a signing key, given as the 32 byte secret in hex
+pub fn signing_key(text: &str) -> Result<SigningKey, String> {
+    Ok(SigningKey::from_bytes(&from_hex(text, "signing key")?))
+}
+
+/// Parses a public key, given as 32 bytes in hex
+pub fn verifying_key(text: &str) -> Result<VerifyingKey, String> {
+    VerifyingKey::from_bytes(&from_hex(text, "public key")?)
+        .map_err(|e| format!("Invalid public key: {}", e))
+}
+
+/// Public key of a signing key, in hex
+pub fn public_key_hex(key: &SigningKey) -> String {
+    to_hex(&key.verifying_key().to_bytes())
+}
+
+/// Signs data, returning the contents of the signature file
+pub fn sign(key: &SigningKey, data: &[u8]) -> String {
+    to_hex(&key.sign(data).to_bytes()) + "\n"
+}
+
+/// Checks the contents of a signature file against data
+pub fn verify(key: &VerifyingKey, data: &[u8], signature: &str) -> Result<(), String> {
+    let signature = Signature::from_bytes(&from_hex(signature, "signature")?);
+    key.verify_strict(data, &signature)
+        .map_err(|_| "Signature doesn't match".to_string())
+}
+
+#[test]
+fn test_sign() {
+    let key = signing_key(&"9d".repeat(32)).unwrap();
+    let public = verifying_key(&public_key_hex(&key)).unwrap();
+    let data = b"[info.font]\nsize = 4096\n";
+    let signature = sign(&key, data);
+    assert_eq!(signature.len(), 129);
+    assert!(verify(&public, data, &signature).is_ok());
+    assert!(verify(&public, b"[info.font]\nsize = 4097\n", &signature).is_err());
+    assert!(verify(&public, data, &signature[..64]).is_err());
+    assert!(signing_key("9d9d").is_err());
+    assert_eq!(
+        signature_path(Path::new("target/BlobInfo.toml")),
+        Path::new("target/BlobInfo.toml.sig")
+    );
+}